@@ -1,9 +1,16 @@
 use futures::stream::{self, StreamExt};
-use rss::Channel;
-use scraper::{Html, Selector};
-use std::fs::File;
-use std::io::BufReader;
-use url::Url;
+use log::{debug, info};
+use reqwest::{Client, Response};
+use std::collections::HashSet;
+
+#[cfg(feature = "async")]
+use log::warn;
+#[cfg(feature = "async")]
+use std::collections::HashMap;
+#[cfg(feature = "async")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "async")]
+use tokio::sync::Semaphore;
 
 use crate::common::*;
 
@@ -13,121 +20,422 @@ const MAX_CONN: usize = 59;  // 59
 /// feeds). For each feed in the list, get the URL and call `process_feed`. Take a look at the
 /// examples in the `rss` crate.
 pub async fn process_feed_file(file_name: &str) -> RssIndexResult<ArticleIndex> {
+    process_feed_file_with_config(file_name, IndexConfig::default()).await
+}
 
+/// Same as `process_feed_file`, but with the settings taken from `config`.
+pub async fn process_feed_file_with_config(
+    file_name: &str,
+    config: IndexConfig,
+) -> RssIndexResult<ArticleIndex> {
+    let client = &build_async_client(&config)?;
+    let urls = &SeenUrls::new(HashSet::new(), &config);
+    let config = &config;
     let mut index = ArticleIndex::new();
 
-    let file = File::open(file_name)?;
-    println!("Processing feed file: {}", file_name);
+    let file = open_feed_file(file_name)?;
+    info!("Processing feed file: {}", file_name);
 
-    let channel = Channel::read_from(BufReader::new(file))?;
+    let feeds = read_feed(file)?;
+    let mut feed_urls = HashSet::new();
     let mut tasks = Vec::new();
 
-    for feed in channel.into_items() {
-        let url = feed.link().ok_or(RssIndexError::UrlError)?.to_string();
-        let title = feed.title().ok_or(RssIndexError::UrlError)?.to_string();
+    for feed in feeds {
+        let (url, title) = feed.link_and_title()?;
+
+        if !feed_urls.insert(canonical_url(url, config)) {
+            debug!("Skipping already seen feed: {} [{}]", title, url);
+            continue;
+        }
 
         let title = title.to_string();
         let url = url.to_string();
 
         tasks.push(async move {
-            println!("Processing feed: {} [{}]", title, url);
-            process_feed(&url).await
+            info!("Processing feed: {} [{}]", title, url);
+            process_feed(&url, client, urls, config).await
         });
     }
 
     let all_results_stream = stream::iter(tasks);
     let all_results = all_results_stream.buffer_unordered(MAX_CONN)
-    .filter_map(|res| async move {
-        if let Ok(result) = res {
-            Some(result)
-        } else {
-            None
-        }
-    })
+    .filter_map(|res| async move { res.ok() })
     .collect::<Vec<_>>().await;
 
     for (site, title, url, article_words) in all_results.into_iter().flatten() {
-        index.add(site, title, url, article_words);
+        index.add_article(site, title, url, article_words);
+    }
+    index.set_query_config(config);
+
+    RssIndexResult::Ok(index)
+}
+
+
+/// Same as `process_feed_file_with_config`, for callers already running in a Tokio runtime:
+/// every feed and article is fetched by a task of its own, spawned on that runtime. At most
+/// `config.max_threads_feeds` feeds, and `config.max_threads_sites` articles per site (see
+/// `IndexConfig::site_key`), are fetched at once. Articles that fail are logged and left out.
+#[cfg(feature = "async")]
+pub async fn index_feed_file_async(
+    path: &str,
+    config: IndexConfig,
+) -> RssIndexResult<ArticleIndex> {
+    let client = build_async_client(&config)?;
+    let urls = Arc::new(SeenUrls::new(HashSet::new(), &config));
+    let config = Arc::new(config);
+    // no permits at all would leave every task waiting forever
+    let feed_permits = Arc::new(Semaphore::new(config.max_threads_feeds.max(1) as usize));
+    let site_permits = Arc::new(SitePermits::default());
+
+    let file = open_feed_file(path)?;
+    info!("Processing feed file: {}", path);
+
+    let mut feed_urls = HashSet::new();
+    let mut feed_tasks = Vec::new();
+    for feed in read_feed(file)? {
+        let (url, title) = feed.link_and_title()?;
+        if !feed_urls.insert(canonical_url(url, &config)) {
+            debug!("Skipping already seen feed: {} [{}]", title, url);
+            continue;
+        }
+        let (url, title) = (url.to_string(), title.to_string());
+        let (client, urls, config) = (client.clone(), urls.clone(), config.clone());
+        let (feed_permits, site_permits) = (feed_permits.clone(), site_permits.clone());
+
+        feed_tasks.push(tokio::spawn(async move {
+            let articles = {
+                let _permit = feed_permits.acquire().await;
+                info!("Processing feed: {} [{}]", title, url);
+                // errors aren't Send, so they can't be returned from a task
+                feed_articles(&url, &client, &urls, &config)
+                    .await
+                    .map_err(|e| e.to_string())
+            };
+            let articles = match articles {
+                Ok(articles) => articles,
+                Err(e) => {
+                    warn!("Failed to process feed {} [{}]: {}", title, url, e);
+                    return Vec::new();
+                }
+            };
+
+            let mut article_tasks = Vec::new();
+            for entry in articles {
+                let (client, config) = (client.clone(), config.clone());
+                let site_key = config.site_key_of(&entry.site);
+                let permits = site_permits.get(&site_key, config.max_threads_sites.max(1) as usize);
+                let feed_url = url.clone();
+                article_tasks.push(tokio::spawn(async move {
+                    let _permit = permits.acquire().await;
+                    let article_url = entry.article.url.clone();
+                    index_article(entry, feed_url, &client, &config)
+                        .await
+                        .map_err(|e| warn!("Failed to process article [{}]: {}", article_url, e))
+                        .ok()
+                }));
+            }
+            article_tasks
+        }));
+    }
+
+    let mut index = ArticleIndex::new();
+    for feed_task in feed_tasks {
+        for article_task in feed_task.await? {
+            if let Some((site, title, url, words)) = article_task.await? {
+                index.add_article(site, title, url, words);
+            }
+        }
     }
+    index.set_query_config(&config);
 
     RssIndexResult::Ok(index)
 }
 
+/// One semaphore per site, each created with the number of permits given the first time it is
+/// asked for.
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct SitePermits(Mutex<HashMap<String, Arc<Semaphore>>>);
 
+#[cfg(feature = "async")]
+impl SitePermits {
+    fn get(&self, site: &str, permits: usize) -> Arc<Semaphore> {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(site.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(permits)))
+            .clone()
+    }
+}
 
 /// Read a feed file from a URL, build an rss channel from it, and iterate through the `Item`s
 /// (articles). Pull out three pieces of information: the URL, the hostname, and the title (see
 /// `Item::link`, `Url::parse`, and `Url::host_str` here). Process each url/title with
 /// `process_article`, and then add it to the input `ArticleIndex` along with the hostname. If an
 /// `Item` is missing a url/hostname/title, skip it (do not panic).
-async fn process_feed(url: &str) -> RssIndexResult<Vec<(String, String, String, Bag<String>)>> {
+async fn process_feed(
+    url: &str,
+    client: &Client,
+    urls: &SeenUrls,
+    config: &IndexConfig,
+) -> RssIndexResult<Vec<(String, String, String, ArticleWords)>> {
     
     let mut tasks = Vec::new();
 
-    let contents = reqwest::get(url).await?.bytes().await?;
-    let channel = Channel::read_from(&contents[..])?;
-    let items = channel.into_items();
+    for entry in feed_articles(url, client, urls, config).await? {
+        tasks.push(index_article(entry, url.to_string(), client, config));
+    }
+
+    let all_results_stream = stream::iter(tasks);
+    let all_results = all_results_stream.buffer_unordered(MAX_CONN)
+    .filter_map(|res| async move { res.ok() })
+    .collect::<Vec<_>>().await;
+
+    RssIndexResult::Ok(all_results)
+}
+
+/// An article listed in a feed, along with its site and its summary in the feed.
+struct FeedArticle {
+    site: String,
+    article: Article,
+    summary: Option<String>,
+}
+
+/// Fetch the feed at `url` and return the articles of it that should be indexed: articles too old
+/// for `config.since` are skipped, and so are those already in `urls` (which they are added to),
+/// those over the per-feed limit, and all of them in a dry run.
+async fn feed_articles(
+    url: &str,
+    client: &Client,
+    urls: &SeenUrls,
+    config: &IndexConfig,
+) -> RssIndexResult<Vec<FeedArticle>> {
+    let response = client
+        .get(url)
+        .headers(config.headers_for(url))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(fetch_error)?;
+    check_feed_type(
+        url,
+        response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok()),
+    )?;
+    let contents = read_body(response, url, config).await?;
+    let items = parse_feed_at(&contents, url)?;
+    let feed_url = url;
 
+    let mut articles = Vec::new();
+    let mut count = 0;
     for item in items {
-        let (url, site, title) = match (
-            item.link(),
-            Url::parse(item.link().unwrap())?.host_str(),
-            item.title(),
-        ) {
-            (Some(u), Some(s), Some(t)) => (u, s.to_string(), t),
+        let (url, site, title) = match (item.link(), item.site(), item.title()) {
+            (Some(u), Some(s), Some(t)) => (u, s, t),
             _ => continue,
         };
 
-        let url = url.to_string();
-        let title = title.to_string();
-        let site = site.to_string();
+        if is_self_link(url, feed_url, config) {
+            debug!("Skipping link back to the feed: {} [{}]", title, url);
+            continue;
+        }
 
-        tasks.push(async move {
-            println!("Processing article: {} [{}]", title, url);
-            let words = process_article(&url).await?;
-            RssIndexResult::Ok((site, title, url, words))
+        if !config.wants_item(&item) {
+            debug!("Skipping old article: {} [{}]", title, url);
+            continue;
+        }
+
+        if config.feed_is_full(count) {
+            debug!("Skipping remaining articles: per-feed limit reached");
+            break;
+        }
+
+        if !urls.insert(dedup_key(&item, url, feed_url, config)) {
+            debug!("Skipping already seen article: {} [{}]", title, url);
+            continue;
+        }
+        count += 1;
+
+        if config.dry_run {
+            info!("Would process article: {} [{}]", title, url);
+            continue;
+        }
+
+        let article = Article::with_published(url.to_string(), title.to_string(), item.published);
+        articles.push(FeedArticle {
+            site,
+            article,
+            summary: item.summary.clone(),
         });
     }
 
-    let all_results_stream = stream::iter(tasks);
-    let all_results = all_results_stream.buffer_unordered(MAX_CONN)
-    .filter_map(|res| async move {
-        if let Ok(result) = res {
-            Some(result)
-        } else {
-            None
-        }
-    })
-    .collect::<Vec<_>>().await;
+    RssIndexResult::Ok(articles)
+}
 
-    RssIndexResult::Ok(all_results)
+/// The client used for every request of a crawl, set up from `config` like the blocking one of
+/// `build_client`.
+fn build_async_client(config: &IndexConfig) -> RssIndexResult<Client> {
+    let client = Client::builder()
+        .timeout(config.timeout)
+        .user_agent(&config.user_agent)
+        .gzip(true)
+        .brotli(true)
+        .redirect(redirect_policy(config))
+        .build()?;
+    RssIndexResult::Ok(client)
 }
 
-/// Delimiters for splitting a string
-const DELIMS: &str = " \t\r\n!@#$%^&*()_-+=~`{[}]|\\\"':;<,>.?/";
+/// Fetch and split up an article listed in the feed at `feed_url` (or only its summary, as
+/// `config.index_source` says) into the site, title, URL and words that
+/// `ArticleIndex::add_article` takes.
+async fn index_article(
+    entry: FeedArticle,
+    feed_url: String,
+    client: &Client,
+    config: &IndexConfig,
+) -> RssIndexResult<(String, String, String, ArticleWords)> {
+    let FeedArticle {
+        site,
+        article,
+        summary,
+    } = entry;
+    info!("Processing article: {} [{}]", article.title, article.url);
+    let mut words = match config.index_source {
+        IndexSource::FullBody => {
+            let mut words = process_article(&article.url, client, config).await?;
+            add_article_details(&article, config, &mut words);
+            words
+        }
+        IndexSource::FeedSummary => process_summary(&article, summary.as_deref(), config)?,
+    };
+    words.feed = Some(feed_url);
+    RssIndexResult::Ok((site, article.title, article.url, words))
+}
 
-/// Use `reqwest` to fetch the article URL, use `scraper` to parse the document, select the "body"
-/// tag, and get the text, split each string on `DELIMS` (see `std::string::split` and
-/// `std::string::contains`), convert each piece to lowercase, and return a vector of words
-/// appearing in the article (possibly with duplicates).
-async fn process_article(url: &str) -> RssIndexResult<Bag<String>> {
+/// Use `reqwest` to fetch the article URL, get the text of the document, split it into words with
+/// `document_words`, and return the words appearing in the article, along with the URL it was
+/// fetched from if it was redirected.
+async fn process_article(
+    url: &str,
+    client: &Client,
+    config: &IndexConfig,
+) -> RssIndexResult<ArticleWords> {
     
-    let mut words = Bag::new();
-    let contents = reqwest::get(url).await?.text().await?;
-    let parsed = Html::parse_document(&contents);
-    let body_selector = Selector::parse("body").map_err(|_| RssIndexError::ScraperError)?;
-    let body = parsed
-        .select(&body_selector)
-        .next()
-        .ok_or(RssIndexError::ScraperError)?;
-
-    for text in body.text() {
-        for tok in text.split(|c| DELIMS.contains(c)) {
-            if !tok.is_empty() {
-                words.add(tok.to_string().to_lowercase());
-            }
-        }
+    let response = client
+        .get(url)
+        .headers(config.headers_for(url))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(fetch_error)?;
+    let final_url = response.url().to_string();
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let bytes = read_body(response, url, config).await?;
+    let document = Document {
+        text: decode_html(&bytes, content_type.as_deref()),
+        url: final_url,
+        content_type,
+    };
+    let mut words = document_words(&document, config)?;
+    if document.url != url {
+        words.url = Some(document.url);
     }
 
     RssIndexResult::Ok(words)
 }
+
+/// Read the body of `response`, which came from a request for `url`, chunk by chunk, failing with
+/// `RssIndexError::BodyTooLarge` once it grows larger than `config.max_body_bytes`.
+async fn read_body(
+    mut response: Response,
+    url: &str,
+    config: &IndexConfig,
+) -> RssIndexResult<Vec<u8>> {
+    let limit = config.max_body_bytes.unwrap_or(usize::MAX);
+    let too_large = || RssIndexError::BodyTooLarge {
+        url: url.to_string(),
+        limit,
+    };
+    // a server may send more than it declares, so this only saves reading a body known to be
+    // too large
+    if response.content_length().unwrap_or(0) > limit as u64 {
+        return Result::Err(Box::new(too_large()));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(fetch_error)? {
+        if body.len() + chunk.len() > limit {
+            return Result::Err(Box::new(too_large()));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    RssIndexResult::Ok(body)
+}
+
+#[cfg(all(test, feature = "async"))]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[tokio::test]
+    async fn a_mock_feed_is_indexed_on_the_runtime() {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/a1", "apple"), ("/a2", "apricot")]);
+        let feed_b = server.feed("/b.xml", &[("/b1", "banana")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_a, feed_b]));
+
+        let index = index_feed_file_async(&file, IndexConfig::default()).await.unwrap();
+
+        let expected = vec![server.url("/a1"), server.url("/a2"), server.url("/b1")];
+        assert_eq!(urls(&index), expected);
+        assert_eq!(index.search("banana")[0].0.url, server.url("/b1"));
+    }
+
+    #[tokio::test]
+    async fn feeds_and_articles_listed_twice_are_fetched_once() {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/shared", "shared words"), ("/a1", "apple")]);
+        let feed_b = server.feed("/b.xml", &[("/shared", "shared words")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_a.clone(), feed_b, feed_a]));
+
+        let index = index_feed_file_async(&file, IndexConfig::default()).await.unwrap();
+
+        assert_eq!(urls(&index), vec![server.url("/a1"), server.url("/shared")]);
+        assert_eq!(server.hits("/a.xml"), 1);
+        assert_eq!(server.hits("/shared"), 1);
+    }
+
+    #[tokio::test]
+    async fn zero_thread_limits_still_index_everything() {
+        let server = MockServer::start();
+        let feed = server.feed("/feed.xml", &[("/one", "first"), ("/two", "second")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed]));
+        let config = IndexConfig::builder().max_threads_feeds(0).max_threads_sites(0).build();
+
+        let index = index_feed_file_async(&file, config).await.unwrap();
+
+        assert_eq!(urls(&index), vec![server.url("/one"), server.url("/two")]);
+    }
+
+    #[tokio::test]
+    async fn the_buffered_pipeline_skips_articles_already_seen() {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/shared", "shared words")]);
+        let feed_b = server.feed("/b.xml", &[("/shared", "shared words"), ("/b1", "banana")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_a, feed_b]));
+
+        let index = process_feed_file_with_config(&file, IndexConfig::default()).await.unwrap();
+
+        assert_eq!(urls(&index), vec![server.url("/b1"), server.url("/shared")]);
+        assert_eq!(server.hits("/shared"), 1);
+    }
+}
@@ -1,18 +1,95 @@
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::collections::hash_map::{DefaultHasher, Entry};
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::error::Error;
 use std::fmt;
+use std::fs::File;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
+use dashmap::DashSet;
+use encoding_rs::{Encoding, UTF_8};
+use flate2::read::GzDecoder;
+use log::{debug, info, warn};
+use lru::LruCache;
+use publicsuffix::Psl;
+use pulldown_cmark::{Event as MdEvent, Parser as MdParser};
+use quick_xml::events::{BytesStart, Event};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::{redirect, StatusCode};
+use rss::Channel;
+use rust_stemmers::{Algorithm, Stemmer};
 use scraper::{Html, Selector};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use unicode_segmentation::UnicodeSegmentation;
+
+#[cfg(feature = "robots")]
+use crate::robots::RobotsRules;
 
 pub type RssIndexResult<T> = Result<T, Box<dyn Error>>;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum RssIndexError {
     ArgsError,
     UrlError,
     ScraperError,
+    IoError(io::Error),
+    SerdeError(serde_json::Error),
+    Timeout {
+        url: String,
+        source: reqwest::Error,
+    },
+    HttpStatus {
+        url: String,
+        status: u16,
+        source: reqwest::Error,
+    },
+    FeedListError(String),
+    OtherError(String),
+    MissingLink {
+        feed_title: String,
+    },
+    MissingTitle {
+        url: String,
+    },
+    UrlParseError {
+        url: String,
+        source: url::ParseError,
+    },
+    RequestError {
+        url: String,
+        source: reqwest::Error,
+    },
+    TooManyRedirects {
+        url: String,
+    },
+    NotAFeed {
+        url: String,
+        content_type: String,
+    },
+    RobotsDisallowed {
+        url: String,
+    },
+    NotAnArticle {
+        url: String,
+    },
+    BodyTooLarge {
+        url: String,
+        limit: usize,
+    },
 }
 
 impl fmt::Display for RssIndexError {
@@ -21,189 +98,4388 @@ impl fmt::Display for RssIndexError {
             RssIndexError::ArgsError => write!(f, "ArgsError"),
             RssIndexError::UrlError => write!(f, "UrlError"),
             RssIndexError::ScraperError => write!(f, "ScraperError"),
+            RssIndexError::IoError(e) => write!(f, "IoError: {}", e),
+            RssIndexError::SerdeError(e) => write!(f, "SerdeError: {}", e),
+            RssIndexError::Timeout { url, .. } => write!(f, "Timeout: {} timed out", url),
+            RssIndexError::HttpStatus { url, status, .. } => {
+                write!(f, "HttpStatus: {} answered {}", url, status)
+            }
+            RssIndexError::FeedListError(url) => write!(
+                f,
+                "FeedListError: {} is not an RSS, Atom or OPML document",
+                url
+            ),
+            RssIndexError::OtherError(msg) => write!(f, "OtherError: {}", msg),
+            RssIndexError::MissingLink { feed_title } => {
+                write!(f, "MissingLink: feed \"{}\" has no link", feed_title)
+            }
+            RssIndexError::MissingTitle { url } => write!(f, "MissingTitle: {} has no title", url),
+            RssIndexError::UrlParseError { url, source } => {
+                write!(f, "UrlParseError: {}: {}", url, source)
+            }
+            RssIndexError::RequestError { url, source } => {
+                write!(f, "RequestError: {}: {}", url, source)
+            }
+            RssIndexError::TooManyRedirects { url } => {
+                write!(f, "TooManyRedirects: {} redirects too often", url)
+            }
+            RssIndexError::NotAFeed { url, content_type } => {
+                write!(f, "NotAFeed: {} is {}, not a feed", url, content_type)
+            }
+            RssIndexError::RobotsDisallowed { url } => {
+                write!(f, "RobotsDisallowed: robots.txt disallows {}", url)
+            }
+            RssIndexError::NotAnArticle { url } => {
+                write!(f, "NotAnArticle: {} is a feed, not an article", url)
+            }
+            RssIndexError::BodyTooLarge { url, limit } => {
+                write!(f, "BodyTooLarge: {} is larger than {} bytes", url, limit)
+            }
         }
     }
 }
 
 impl Error for RssIndexError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        None
+        match self {
+            RssIndexError::IoError(e) => Some(e),
+            RssIndexError::SerdeError(e) => Some(e),
+            RssIndexError::UrlParseError { source, .. } => Some(source),
+            RssIndexError::RequestError { source, .. } => Some(source),
+            RssIndexError::Timeout { source, .. } => Some(source),
+            RssIndexError::HttpStatus { source, .. } => Some(source),
+            _ => None,
+        }
     }
 }
 
-/// Bag of words
-#[derive(Default)]
-pub struct Bag<T>
-where
-    T: Ord,
-{
-    pub counts: BTreeMap<T, u32>,
+impl From<Box<dyn Error>> for RssIndexError {
+    /// Recover an `RssIndexError` (or an `io::Error`) that was boxed up by `?`, and keep only the
+    /// message of any other error, so that the result can be sent between threads.
+    fn from(e: Box<dyn Error>) -> Self {
+        let e = match e.downcast::<RssIndexError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        match e.downcast::<io::Error>() {
+            Ok(e) => RssIndexError::IoError(*e),
+            Err(e) => RssIndexError::OtherError(e.to_string()),
+        }
+    }
 }
 
-/// The URL and title of an article.
-#[derive(PartialEq, Eq, Hash, Clone)]
-pub struct Article {
-    pub url: String,
-    pub title: String,
+/// Feeds and articles that were skipped because of an error, with the URL of each.
+pub type ItemErrors = Vec<(String, RssIndexError)>;
+
+/// Progress of a crawl, as reported to a progress callback.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// A feed is about to be fetched.
+    FeedStarted { url: String },
+    /// An article was fetched and added to the index.
+    ArticleIndexed { url: String },
+    /// A feed or article was skipped: already seen, too old, over the per-feed limit, or failed.
+    ItemSkipped { url: String },
+    /// A feed was fetched, and `count` of its articles were queued for indexing.
+    FeedFinished { url: String, count: usize },
 }
 
-/// The site (hostname) and title of an article.
-#[derive(PartialEq, Eq, Hash, Clone)]
-pub struct ArticleKey {
-    pub site: String,
-    pub title: String,
+/// A progress callback. It is called from worker threads, so it must be `Send + Sync`.
+pub type ProgressFn = Arc<dyn Fn(ProgressEvent) + Send + Sync>;
+
+/// A flag for stopping a crawl early. Clones share the flag, so one can be kept to cancel the crawl
+/// that was given another.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Ask the crawl to stop. Feeds and articles not yet started are skipped; those already being
+    /// fetched still finish.
+    pub fn cancel(&self) {
+        self.0.store(true, atomic::Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(atomic::Ordering::SeqCst)
+    }
 }
 
-/// Map from an ArticleKey to a vector of URLs and bag of words appearing in this article.
-pub struct ArticleIndex {
-    pub index: HashMap<ArticleKey, (Vec<String>, Bag<String>)>,
+/// Counts of what happened during a crawl.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CrawlStats {
+    /// Feeds fetched and parsed, including feeds skipped as unchanged.
+    pub feeds_processed: usize,
+    /// Articles fetched and added to the index (or written out, when streaming).
+    pub articles_indexed: usize,
+    /// Feeds and articles skipped because their URL was already seen.
+    pub duplicates_skipped: usize,
+    /// Feeds and articles that failed.
+    pub errors: usize,
 }
 
-/// Map from words to Maps from Articles to counts of how frequently the word shows up in the
-/// article. Articles not containing this word should not be in the inner HashMap.
-pub struct RssIndex {
-    pub index: HashMap<String, HashMap<Article, u32>>,
+/// The parts of a feed item (an RSS `<item>` or an Atom `<entry>`) used for indexing.
+#[derive(Clone, Debug, Default)]
+pub struct FeedItem {
+    pub link: Option<String>,
+    pub title: Option<String>,
+    /// The RSS `<guid>` or Atom `<id>` of the item, which identifies it better than its link.
+    pub guid: Option<String>,
+    /// The RSS `<description>` or Atom `<summary>` of the item, or else its content
+    /// (`<content:encoded>` or `<content>`), usually as HTML.
+    pub summary: Option<String>,
+    /// When the item was published, if the feed says so in a format we understand.
+    pub published: Option<DateTime<Utc>>,
 }
 
-impl<T> Bag<T>
-where
-    T: Ord,
-{
-    pub fn new() -> Self {
-        Bag {
-            counts: BTreeMap::new(),
+impl FeedItem {
+    pub fn link(&self) -> Option<&str> {
+        self.link.as_deref()
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    pub fn guid(&self) -> Option<&str> {
+        self.guid.as_deref()
+    }
+
+    /// The site of the article the item links to: the host of its link, which may well differ
+    /// from that of the feed. None if the link is missing, can't be parsed, or has no host.
+    pub fn site(&self) -> Option<String> {
+        let link = url::Url::parse(self.link()?).ok()?;
+        link.host_str().map(String::from)
+    }
+
+    /// The link and title of a feed listed in a feed file, or an error saying which one is
+    /// missing.
+    pub fn link_and_title(&self) -> Result<(&str, &str), RssIndexError> {
+        let link = self.link().ok_or_else(|| RssIndexError::MissingLink {
+            feed_title: self.title().unwrap_or_default().to_string(),
+        })?;
+        let title = self.title().ok_or_else(|| RssIndexError::MissingTitle {
+            url: link.to_string(),
+        })?;
+        Result::Ok((link, title))
+    }
+}
+
+impl From<rss::Item> for FeedItem {
+    fn from(item: rss::Item) -> Self {
+        FeedItem {
+            link: item.link().map(String::from),
+            title: item.title().map(String::from),
+            guid: item.guid().map(|guid| guid.value().to_string()),
+            summary: item
+                .description()
+                .or_else(|| item.content())
+                .map(String::from),
+            published: item
+                .pub_date()
+                .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+                .map(|date| date.with_timezone(&Utc)),
         }
     }
+}
 
-    /// Add one element.
-    pub fn add(&mut self, t: T) {
-        *self.counts.entry(t).or_insert(0) += 1;
+impl From<&atom_syndication::Entry> for FeedItem {
+    /// Of several links, the `rel="alternate"` one points at the article itself.
+    fn from(entry: &atom_syndication::Entry) -> Self {
+        let link = entry
+            .links()
+            .iter()
+            .find(|link| link.rel() == "alternate")
+            .or_else(|| entry.links().first());
+        FeedItem {
+            link: link.map(|link| link.href().to_string()),
+            title: Some(entry.title().to_string()),
+            guid: Some(entry.id().to_string()).filter(|id| !id.is_empty()),
+            summary: entry
+                .summary()
+                .or_else(|| entry.content().and_then(|content| content.value()))
+                .map(String::from),
+            published: Some(
+                entry
+                    .published()
+                    .unwrap_or_else(|| entry.updated())
+                    .with_timezone(&Utc),
+            ),
+        }
     }
+}
 
-    /// Take intersection with another Bag. The count of any element appearing in both Bags should
-    /// be the minimum of the counts in each Bag. Elements appearing in just one bag should have
-    /// count zero.
-    pub fn intersect_with(&mut self, other: &Self) {
-        let mut my_iter = self.counts.iter_mut();
-        let mut other_iter = other.counts.iter();
+/// Parse an RSS channel, or an Atom feed if `contents` isn't RSS, and return its items.
+pub fn parse_feed(contents: &[u8]) -> RssIndexResult<Vec<FeedItem>> {
+    match Channel::read_from(contents) {
+        Ok(channel) => Result::Ok(
+            channel
+                .into_items()
+                .into_iter()
+                .map(FeedItem::from)
+                .collect(),
+        ),
+        Err(rss_error) => match atom_syndication::Feed::read_from(contents) {
+            Ok(feed) => Result::Ok(feed.entries().iter().map(FeedItem::from).collect()),
+            Err(_) => Result::Err(Box::new(rss_error)),
+        },
+    }
+}
 
-        let mut my_cur = my_iter.next();
-        let mut other_cur = other_iter.next();
+/// Same as `parse_feed`, for a feed fetched from `feed_url`: relative links (like `/posts/123`) are
+/// resolved against `feed_url`, absolute ones are kept as they are.
+pub fn parse_feed_at(contents: &[u8], feed_url: &str) -> RssIndexResult<Vec<FeedItem>> {
+    let base = parse_url(feed_url)?;
+    let mut items = parse_feed(contents)?;
+    for item in &mut items {
+        let link = match &item.link {
+            Some(link) => link.trim(),
+            None => continue,
+        };
+        if let Err(url::ParseError::RelativeUrlWithoutBase) = url::Url::parse(link) {
+            if let Ok(resolved) = base.join(link) {
+                item.link = Some(resolved.to_string());
+            }
+        }
+    }
+    Result::Ok(items)
+}
 
-        while let (Some(my_kv), Some(ot_kv)) = (&mut my_cur, &other_cur) {
-            match my_kv.0.cmp(&ot_kv.0) {
-                Ordering::Less => {
-                    *my_kv.1 = 0;
-                    my_cur = my_iter.next();
+/// The first bytes of every gzip file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Open a feed file (or an OPML file) for reading. A gzipped file, recognized by its `.gz`
+/// extension or else by the magic bytes it starts with, is decompressed as it is read.
+pub fn open_feed_file<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Read + Send>> {
+    let path = path.as_ref();
+    let mut file = File::open(path)?;
+    let mut magic = Vec::with_capacity(GZIP_MAGIC.len());
+    (&mut file)
+        .take(GZIP_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    if path.extension().is_some_and(|ext| ext == "gz") || magic == GZIP_MAGIC {
+        Result::Ok(Box::new(GzDecoder::new(file)))
+    } else {
+        Result::Ok(Box::new(file))
+    }
+}
+
+/// Read a whole feed from `reader` and parse it with `parse_feed`.
+pub fn read_feed<R: Read>(mut reader: R) -> RssIndexResult<Vec<FeedItem>> {
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents)?;
+    parse_feed(&contents)
+}
+
+/// The local names of the root elements of RSS (2.0 and 1.0) and Atom feeds.
+const FEED_ROOTS: &[&[u8]] = &[b"rss", b"feed", b"RDF"];
+
+/// Whether `text` is an RSS or Atom feed rather than a web page, judging by its root element.
+pub fn looks_like_feed(text: &str) -> bool {
+    let mut reader = quick_xml::Reader::from_str(text);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf) {
+            Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) => {
+                return FEED_ROOTS.contains(&e.local_name());
+            }
+            Ok(Event::Eof) | Err(_) => return false,
+            Ok(_) => buf.clear(),
+        }
+    }
+}
+
+/// Whether the item of the feed at `feed_url` linking to `url` links back to the feed itself.
+pub fn is_self_link(url: &str, feed_url: &str, config: &IndexConfig) -> bool {
+    canonical_url(url, config) == canonical_url(feed_url, config)
+}
+
+/// Read the items of a feed from `reader` one by one, as they are parsed, instead of parsing the
+/// whole feed first like `read_feed` does. Meant for huge feed files; items are read the same way
+/// as `parse_feed` reads them, but nothing else of the feed is checked beyond its root element.
+/// After an error, the iterator ends.
+pub fn stream_feed<R: Read>(reader: R) -> FeedItems<BufReader<R>> {
+    let mut reader = quick_xml::Reader::from_reader(BufReader::new(reader));
+    reader.trim_text(true);
+    FeedItems {
+        reader,
+        buf: Vec::new(),
+        depth: 0,
+        has_root: false,
+        done: false,
+    }
+}
+
+/// Iterator over the items of a feed, returned by `stream_feed`.
+pub struct FeedItems<B: BufRead> {
+    reader: quick_xml::Reader<B>,
+    buf: Vec<u8>,
+    /// How many elements are open.
+    depth: usize,
+    /// Whether the root element has been read.
+    has_root: bool,
+    done: bool,
+}
+
+/// The element of a feed item whose text is being read.
+#[derive(Clone, Copy, PartialEq)]
+enum ItemField {
+    Link,
+    Title,
+    Guid,
+    Summary,
+    Content,
+    PubDate,
+    Published,
+    Updated,
+}
+
+/// A feed item being read by `FeedItems`.
+#[derive(Default)]
+struct PartialItem {
+    is_entry: bool,
+    depth: usize,
+    item: FeedItem,
+    alternate_link: Option<String>,
+    published: Option<DateTime<Utc>>,
+    updated: Option<DateTime<Utc>>,
+    content: Option<String>,
+    field: Option<ItemField>,
+    text: String,
+}
+
+impl PartialItem {
+    /// Note an Atom `<link>` element: of several links, the `rel="alternate"` one (the default)
+    /// points at the article itself.
+    fn add_atom_link<B: BufRead>(
+        &mut self,
+        e: &BytesStart,
+        reader: &quick_xml::Reader<B>,
+    ) -> RssIndexResult<()> {
+        let mut href = None;
+        let mut rel = None;
+        for attr in e.attributes() {
+            let attr = attr?;
+            match attr.key {
+                b"href" => href = Some(attr.unescape_and_decode_value(reader)?),
+                b"rel" => rel = Some(attr.unescape_and_decode_value(reader)?),
+                _ => {}
+            }
+        }
+        if let Some(href) = href {
+            if rel.as_deref().unwrap_or("alternate") == "alternate" {
+                self.alternate_link.get_or_insert(href);
+            } else if self.item.link.is_none() {
+                self.item.link = Some(href);
+            }
+        }
+        Result::Ok(())
+    }
+
+    /// Store the text read for the current field.
+    fn end_field(&mut self) {
+        let text = std::mem::take(&mut self.text).trim().to_string();
+        let rfc3339 = |text: &str| {
+            DateTime::parse_from_rfc3339(text)
+                .ok()
+                .map(|date| date.with_timezone(&Utc))
+        };
+        match self.field.take() {
+            Some(ItemField::Link) => self.item.link = Some(text),
+            Some(ItemField::Title) => self.item.title = Some(text),
+            Some(ItemField::Guid) => self.item.guid = Some(text).filter(|guid| !guid.is_empty()),
+            Some(ItemField::Summary) => self.item.summary = Some(text),
+            Some(ItemField::Content) => self.content = Some(text),
+            Some(ItemField::PubDate) => {
+                self.published = DateTime::parse_from_rfc2822(&text)
+                    .ok()
+                    .map(|date| date.with_timezone(&Utc))
+            }
+            Some(ItemField::Published) => self.published = rfc3339(&text),
+            Some(ItemField::Updated) => self.updated = rfc3339(&text),
+            None => {}
+        }
+    }
+
+    fn finish(self) -> FeedItem {
+        let mut item = self.item;
+        item.summary = item.summary.or(self.content);
+        if self.is_entry {
+            item.link = self.alternate_link.or(item.link);
+            item.title = Some(item.title.unwrap_or_default());
+            item.published = self.published.or(self.updated);
+        } else {
+            item.published = self.published;
+        }
+        item
+    }
+}
+
+impl<B: BufRead> FeedItems<B> {
+    /// Read up to the end of the next item, if there is one.
+    fn read_item(&mut self) -> RssIndexResult<Option<FeedItem>> {
+        let mut current: Option<PartialItem> = None;
+        loop {
+            self.buf.clear();
+            match self.reader.read_event(&mut self.buf)? {
+                Event::Start(ref e) => {
+                    self.depth += 1;
+                    let depth = self.depth;
+                    let name = e.local_name();
+                    if !self.has_root {
+                        if !FEED_ROOTS.contains(&name) {
+                            let name = String::from_utf8_lossy(name);
+                            let msg = format!("<{}> is not the root of an RSS or Atom feed", name);
+                            return Result::Err(Box::new(RssIndexError::OtherError(msg)));
+                        }
+                        self.has_root = true;
+                    }
+                    match &mut current {
+                        None if name == b"item" || name == b"entry" => {
+                            current = Some(PartialItem {
+                                is_entry: name == b"entry",
+                                depth,
+                                ..PartialItem::default()
+                            });
+                        }
+                        Some(partial) if depth == partial.depth + 1 => {
+                            partial.field = match name {
+                                b"link" if partial.is_entry => {
+                                    partial.add_atom_link(e, &self.reader)?;
+                                    None
+                                }
+                                b"link" => Some(ItemField::Link),
+                                b"title" => Some(ItemField::Title),
+                                b"guid" if !partial.is_entry => Some(ItemField::Guid),
+                                b"id" if partial.is_entry => Some(ItemField::Guid),
+                                b"description" if !partial.is_entry => Some(ItemField::Summary),
+                                b"encoded" if !partial.is_entry => Some(ItemField::Content),
+                                b"summary" if partial.is_entry => Some(ItemField::Summary),
+                                b"content" if partial.is_entry => Some(ItemField::Content),
+                                b"pubDate" => Some(ItemField::PubDate),
+                                b"published" => Some(ItemField::Published),
+                                b"updated" => Some(ItemField::Updated),
+                                _ => None,
+                            };
+                        }
+                        _ => {}
+                    }
                 }
-                Ordering::Greater => {
-                    other_cur = other_iter.next();
+                Event::Empty(ref e) => {
+                    if let Some(partial) = &mut current {
+                        if partial.is_entry
+                            && self.depth == partial.depth
+                            && e.local_name() == b"link"
+                        {
+                            partial.add_atom_link(e, &self.reader)?;
+                        }
+                    }
                 }
-                Ordering::Equal => {
-                    *my_kv.1 = (*my_kv.1).min(*ot_kv.1);
-                    my_cur = my_iter.next();
-                    other_cur = other_iter.next();
+                Event::Text(ref e) => {
+                    if let Some(partial) = current.as_mut().filter(|p| p.field.is_some()) {
+                        partial.text += &e.unescape_and_decode(&self.reader)?;
+                    }
                 }
+                Event::CData(ref e) => {
+                    if let Some(partial) = current.as_mut().filter(|p| p.field.is_some()) {
+                        partial.text += &self.reader.decode(e);
+                    }
+                }
+                Event::End(_) => {
+                    let depth = self.depth;
+                    self.depth = depth.saturating_sub(1);
+                    if let Some(partial) = &mut current {
+                        if depth == partial.depth {
+                            return Result::Ok(current.map(PartialItem::finish));
+                        }
+                        if depth == partial.depth + 1 {
+                            partial.end_field();
+                        }
+                    }
+                }
+                Event::Eof if !self.has_root => {
+                    let msg = "no RSS or Atom feed found".to_string();
+                    return Result::Err(Box::new(RssIndexError::OtherError(msg)));
+                }
+                Event::Eof => return Result::Ok(None),
+                _ => {}
             }
         }
     }
 }
 
-impl Article {
-    pub fn new(url: String, title: String) -> Self {
-        Article { url, title }
+impl<B: BufRead> Iterator for FeedItems<B> {
+    type Item = RssIndexResult<FeedItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_item().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
     }
+}
 
-    pub fn cmp_title(&self, other: &Article) -> std::cmp::Ordering {
-        self.title.cmp(&other.title)
+/// Read the feeds listed in an OPML document: every `<outline>` with an `xmlUrl` attribute, at any
+/// depth, so folders of feeds are flattened. The title of a feed is its `title` attribute, or its
+/// `text` attribute if it has none.
+pub fn read_opml<R: Read>(reader: R) -> RssIndexResult<Vec<FeedItem>> {
+    let mut reader = quick_xml::Reader::from_reader(BufReader::new(reader));
+    let mut feeds = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(ref e) | Event::Empty(ref e) if e.name() == b"outline" => {
+                let mut feed = FeedItem::default();
+                let mut text = None;
+                for attr in e.attributes() {
+                    let attr = attr?;
+                    match attr.key {
+                        b"xmlUrl" => feed.link = Some(attr.unescape_and_decode_value(&reader)?),
+                        b"title" => feed.title = Some(attr.unescape_and_decode_value(&reader)?),
+                        b"text" => text = Some(attr.unescape_and_decode_value(&reader)?),
+                        _ => {}
+                    }
+                }
+                if feed.link.is_some() {
+                    feed.title = feed.title.or(text);
+                    feeds.push(feed);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
     }
+    Result::Ok(feeds)
 }
 
-impl ArticleKey {
-    pub fn new(site: String, title: String) -> Self {
-        ArticleKey { site, title }
+/// Read a list of feeds that is either an OPML document or itself an RSS/Atom feed, depending on
+/// the document's root element.
+pub fn parse_feed_list(contents: &[u8]) -> RssIndexResult<Vec<FeedItem>> {
+    let mut reader = quick_xml::Reader::from_reader(contents);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event(&mut buf)? {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                return if e.name() == b"opml" {
+                    read_opml(contents)
+                } else {
+                    parse_feed(contents)
+                };
+            }
+            Event::Eof => return parse_feed(contents),
+            _ => {}
+        }
+        buf.clear();
     }
 }
 
-impl ArticleIndex {
+/// Write a set of seen URLs as JSON to the file at `path`, replacing it if it exists, so that a
+/// later run can skip articles indexed before.
+pub fn save_seen_urls(seen: &HashSet<String>, path: &Path) -> RssIndexResult<()> {
+    let file = File::create(path).map_err(RssIndexError::IoError)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, seen).map_err(RssIndexError::SerdeError)?;
+    writer.flush().map_err(RssIndexError::IoError)?;
+    Result::Ok(())
+}
+
+/// Read a set of seen URLs written by `save_seen_urls` from the file at `path`. A missing file
+/// yields an empty set, as on the first run of an incremental crawl.
+pub fn load_seen_urls(path: &Path) -> RssIndexResult<HashSet<String>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Result::Ok(HashSet::new()),
+        Err(e) => return Result::Err(Box::new(RssIndexError::IoError(e))),
+    };
+    let seen = serde_json::from_reader(BufReader::new(file)).map_err(RssIndexError::SerdeError)?;
+    Result::Ok(seen)
+}
+
+/// Validators a server sent along with a feed, to ask on a later crawl whether it has changed.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Map from feed URLs to the validators of their last responses, used for conditional requests
+/// by `fetch_if_modified`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeedCache {
+    pub entries: HashMap<String, CacheEntry>,
+}
+
+impl FeedCache {
     pub fn new() -> Self {
-        ArticleIndex {
-            index: HashMap::new(),
-        }
+        FeedCache::default()
     }
 
-    /// Add a site, title, URL, and a bag of words to the ArticleIndex.
-    pub fn add(&mut self, site: String, title: String, url: String, words: Bag<String>) {
-        let key = ArticleKey::new(site, title);
-        self.index
-            .entry(key)
-            .and_modify(|e| {
-                e.0.push(url.clone());
-                e.1.intersect_with(&words)
-            })
-            .or_insert((vec![url], words));
+    /// Write the cache as JSON to the file at `path`, replacing it if it exists.
+    pub fn save(&self, path: &Path) -> RssIndexResult<()> {
+        let file = File::create(path).map_err(RssIndexError::IoError)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self).map_err(RssIndexError::SerdeError)?;
+        writer.flush().map_err(RssIndexError::IoError)?;
+        Result::Ok(())
+    }
+
+    /// Read a cache written by `save` from the file at `path`. A missing file yields an empty
+    /// cache.
+    pub fn load(path: &Path) -> RssIndexResult<FeedCache> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Result::Ok(FeedCache::new()),
+            Err(e) => return Result::Err(Box::new(RssIndexError::IoError(e))),
+        };
+        let cache =
+            serde_json::from_reader(BufReader::new(file)).map_err(RssIndexError::SerdeError)?;
+        Result::Ok(cache)
     }
 }
 
-impl RssIndex {
-    pub fn new() -> Self {
-        RssIndex {
-            index: HashMap::new(),
+/// Bits per URL of a `BloomFilter`, and number of bits set per URL. With these, about 1% of the
+/// URLs not in the filter are reported as maybe in it once it holds as many URLs as it was sized
+/// for.
+const BLOOM_BITS_PER_URL: usize = 10;
+const BLOOM_HASHES: u64 = 7;
+
+/// A Bloom filter over strings: it answers "definitely not added" or "maybe added", without
+/// locking. Bits are only ever set, so a string once added is never reported as missing.
+#[derive(Debug)]
+struct BloomFilter {
+    bits: Vec<AtomicU64>,
+}
+
+impl BloomFilter {
+    /// A filter sized for `capacity` strings.
+    fn new(capacity: usize) -> Self {
+        let words = (capacity.max(1) * BLOOM_BITS_PER_URL).div_ceil(64);
+        BloomFilter {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
         }
     }
 
-    /// Add a article, word, and count to the RssIndex. The word might not be in the index.
-    pub fn add(&mut self, article: Article, word: String, freq: u32) {
-        *self
-            .index
-            .entry(word)
-            .or_insert_with(HashMap::new)
-            .entry(article)
-            .or_insert(0) += freq;
+    /// The bits of `s`, by double hashing: bit `i` is `h1 + i * h2`.
+    fn bit_indexes(&self, s: &str) -> impl Iterator<Item = usize> {
+        let hash = |seed: u64| {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            s.hash(&mut hasher);
+            hasher.finish()
+        };
+        let (h1, h2) = (hash(0), hash(1) | 1);
+        let len = self.bits.len() as u64 * 64;
+        (0..BLOOM_HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    fn insert(&self, s: &str) {
+        for bit in self.bit_indexes(s) {
+            self.bits[bit / 64].fetch_or(1 << (bit % 64), atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn maybe_contains(&self, s: &str) -> bool {
+        self.bit_indexes(s)
+            .all(|bit| self.bits[bit / 64].load(atomic::Ordering::Relaxed) & (1 << (bit % 64)) != 0)
     }
 }
 
-/// Turn an ArticleIndex into an RssIndex.
-///
-/// If an article has multiple URLs in the ArticleIndex, sort the URLs and file the words under the
-/// alphabetically-earliest URL with RssIndex::add here. If an entry in ArticleIndex doesn't have a
-/// URL for some reason, just skip it. This function should not panic.
-pub fn build_index(article_index: &mut ArticleIndex, rss_index: &mut RssIndex) {
-    println!("Building index...");
-    for (article_key, article_entry) in &mut article_index.index {
-        article_entry.0.sort();
-        if let Some(url) = article_entry.0.pop() {
-            for (word, count) in &article_entry.1.counts {
-                let article = Article::new(url.clone(), article_key.title.clone());
-                rss_index.add(article, word.to_string(), *count)
+/// The set of URLs seen during a crawl, shared by its threads. If `IndexConfig::bloom_capacity` is
+/// set, a Bloom filter in front of the set answers most lookups of new URLs without looking in the
+/// set.
+#[derive(Debug)]
+pub struct SeenUrls {
+    bloom: Option<BloomFilter>,
+    exact: DashSet<String>,
+    /// How many lookups went to `exact`, i.e. weren't answered by the Bloom filter alone.
+    exact_lookups: AtomicUsize,
+}
+
+impl SeenUrls {
+    /// A set holding `urls`, with a Bloom filter if the config asks for one.
+    pub fn new(urls: HashSet<String>, config: &IndexConfig) -> Self {
+        let bloom = config.bloom_capacity.map(|capacity| {
+            let bloom = BloomFilter::new(capacity.max(urls.len()));
+            for url in &urls {
+                bloom.insert(url);
             }
+            bloom
+        });
+        SeenUrls {
+            bloom,
+            exact: urls.into_iter().collect(),
+            exact_lookups: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether `url` may be in the set, as far as the Bloom filter knows. Without a filter, it
+    /// always may.
+    fn maybe_contains(&self, url: &str) -> bool {
+        self.bloom
+            .as_ref()
+            .is_none_or(|bloom| bloom.maybe_contains(url))
+    }
+
+    /// Whether `url` is in the set itself, counted in `exact_lookups`.
+    fn exact_contains(&self, url: &str) -> bool {
+        self.exact_lookups.fetch_add(1, atomic::Ordering::Relaxed);
+        self.exact.contains(url)
+    }
+
+    pub fn contains(&self, url: &str) -> bool {
+        self.maybe_contains(url) && self.exact_contains(url)
+    }
+
+    /// Add `url`, returning whether it was new. A URL the Bloom filter has surely not seen is
+    /// added right away; any other is first looked up under the read lock of its shard of the set,
+    /// so that a duplicate never takes the write lock. Checking and adding happen under the write
+    /// lock, so of several threads adding the same URL, exactly one sees it as new.
+    pub fn insert(&self, url: String) -> bool {
+        if self.maybe_contains(&url) && self.exact_contains(&url) {
+            return false;
+        }
+        if let Some(bloom) = &self.bloom {
+            // set the bits before adding to the set, so that `contains` never misses the URL
+            bloom.insert(&url);
         }
+        self.exact.insert(url)
+    }
+
+    /// How many lookups couldn't be answered by the Bloom filter alone and went to the set. With
+    /// no filter, every lookup does.
+    pub fn exact_lookups(&self) -> usize {
+        self.exact_lookups.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Remove `url`. Its bits stay set in the Bloom filter, which only makes later lookups of it
+    /// look in the set.
+    pub fn remove(&self, url: &str) -> bool {
+        self.exact.remove(url).is_some()
+    }
+
+    /// Take all URLs out of the set, leaving it empty. URLs added meanwhile may be lost, so this is
+    /// only meant for once a crawl is over.
+    pub fn take(&self) -> HashSet<String> {
+        let urls = self.exact.iter().map(|url| url.key().clone()).collect();
+        self.exact.clear();
+        urls
     }
 }
 
-/// Delimiters for splitting a string
-const DELIMS: &str = " \t\r\n!@#$%^&*()_-+=~`{[}]|\\\"':;<,>.?/";
+/// When the next request to each host may be sent, shared by all threads of a crawl to keep
+/// `IndexConfig::per_host_delay` between requests to the same host.
+#[derive(Debug, Default)]
+pub struct HostThrottle {
+    next_request: Mutex<HashMap<String, Instant>>,
+}
 
-/// Use reqwest to fetch the article URL, use scraper to parse the document, select the "body" tag,
-/// and get the text, split each string on DELIMS (see `std::string::split` and
-/// `std::string::contains`), convert each piece to lowercase, and return a vector of words
-/// appearing in the article (possibly with duplicates).
-pub fn process_article(article: &Article) -> RssIndexResult<Bag<String>> {
-    let mut words = Bag::new();
-    let content = reqwest::blocking::get(&article.url)?.text()?;
-    let parsed = Html::parse_document(&content);
-    let body_selector = Selector::parse("body").map_err(|_| RssIndexError::ScraperError)?;
-    let body = parsed
-        .select(&body_selector)
-        .next()
-        .ok_or(RssIndexError::ScraperError)?;
+impl HostThrottle {
+    pub fn new() -> Self {
+        HostThrottle::default()
+    }
 
-    for text in body.text() {
-        for tok in text.split(|c| DELIMS.contains(c)) {
-            if !tok.is_empty() {
-                words.add(tok.to_string().to_lowercase());
-            }
+    /// Sleep until a request to the host of `url` may be sent. Each caller reserves its slot
+    /// before sleeping, so concurrent requests to one host are spaced out rather than all sent
+    /// once the first delay is over. Does nothing if `config.per_host_delay` is unset or `url` has
+    /// no host.
+    pub fn wait(&self, url: &str, config: &IndexConfig) {
+        let delay = match config.per_host_delay {
+            Some(delay) => delay,
+            None => return,
+        };
+        let host = match url::Url::parse(url) {
+            Ok(url) => match url.host_str() {
+                Some(host) => host.to_string(),
+                None => return,
+            },
+            Err(_) => return,
+        };
+        let now = Instant::now();
+        let slot = {
+            let mut next_request = self.next_request.lock().unwrap();
+            let slot = next_request.get(&host).map_or(now, |&next| next.max(now));
+            next_request.insert(host, slot + delay);
+            slot
+        };
+        thread::sleep(slot - now);
+    }
+}
+
+/// Default thread limits of the multi-threaded version.
+const MAX_THREADS_FEEDS: u32 = 5;
+const MAX_THREADS_SITES: u32 = 10;
+const MAX_THREADS_TOTAL: u32 = 18;
+
+/// Default thread pool sizes of the pooled version.
+const SIZE_FEEDS_POOL: usize = 3;
+const SIZE_SITES_POOL: usize = 20;
+
+/// Default number of article jobs waiting for the sites pool before feed jobs have to wait.
+const SIZE_SITES_QUEUE: usize = 200;
+
+/// Default time allowed for a whole request, including reading the body.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default number of retries of a failed request, and delay before the first retry.
+const MAX_RETRIES: u32 = 3;
+const BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Default number of redirects followed before a request fails.
+const MAX_REDIRECTS: usize = 10;
+
+/// Default User-Agent header of outbound requests.
+const USER_AGENT: &str = concat!("RSS-Indexer/", env!("CARGO_PKG_VERSION"));
+
+/// Default length (in characters) below which words are dropped.
+const MIN_WORD_LEN: usize = 2;
+
+/// Default query parameters dropped from URLs before deduplication.
+const TRACKING_PARAMS: &[&str] = &["utm_*", "fbclid", "gclid", "mc_cid", "mc_eid"];
+
+/// Default number of articles an article worker collects before adding them to the index.
+const FLUSH_INTERVAL: usize = 32;
+
+/// Default number of times a word of an article's title is counted.
+const TITLE_WEIGHT: u32 = 3;
+
+/// Default stop words: common English words that carry little meaning on their own.
+const DEFAULT_STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+    "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+    "they", "this", "to", "was", "will", "with",
+];
+
+/// A function splitting text into words, for `Tokenizer::Custom`.
+pub type SplitFn = Arc<dyn Fn(&str) -> Vec<String> + Send + Sync>;
+
+/// How text is split into words, before they are lowercased and filtered.
+#[derive(Clone, Default)]
+pub enum Tokenizer {
+    /// Split at whitespace only, so punctuation stays attached to the words next to it.
+    Whitespace,
+    /// Split at Unicode word boundaries (see `UnicodeSegmentation::split_word_bounds`).
+    #[default]
+    UnicodeWords,
+    /// Split with the given function, e.g. a segmenter for languages written without spaces.
+    Custom(SplitFn),
+}
+
+impl Tokenizer {
+    /// The pieces of `text`, in order. Pieces that are only whitespace are left for the caller
+    /// to skip.
+    pub fn split<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        match self {
+            Tokenizer::Whitespace => Box::new(text.split_whitespace().map(Cow::Borrowed)),
+            Tokenizer::UnicodeWords => Box::new(text.split_word_bounds().map(Cow::Borrowed)),
+            Tokenizer::Custom(split) => Box::new(split(text).into_iter().map(Cow::Owned)),
         }
     }
-    Result::Ok(words)
+}
+
+impl fmt::Debug for Tokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tokenizer::Whitespace => write!(f, "Whitespace"),
+            Tokenizer::UnicodeWords => write!(f, "UnicodeWords"),
+            Tokenizer::Custom(_) => write!(f, "Custom(..)"),
+        }
+    }
+}
+
+/// Where an article listed more than once counts as a duplicate, see `IndexConfig::dedup_scope`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupScope {
+    /// An article is indexed once per crawl, through the first feed that lists it. The other
+    /// feeds listing it are still recorded in `ArticleIndex::source_feeds`.
+    #[default]
+    Global,
+    /// An article is indexed once per feed listing it, so it is fetched once for each of them.
+    PerFeed,
+}
+
+/// What the words of an article are taken from, see `IndexConfig::index_source`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexSource {
+    /// The article itself, fetched from its link.
+    #[default]
+    FullBody,
+    /// The summary of the article in its feed item (see `FeedItem::summary`). Nothing but the
+    /// feeds is fetched, which makes for much faster crawls.
+    FeedSummary,
+}
+
+/// What the per-site limits of a crawl (like `IndexConfig::max_threads_sites`) tell sites apart
+/// by, see `IndexConfig::site_key_of`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SiteKey {
+    /// The host name, so `a.example.com` and `b.example.com` each have limits of their own.
+    #[default]
+    Host,
+    /// The registrable domain of the host: its public suffix plus one label, like `example.com`
+    /// for `a.example.com`, or `example.co.uk` for `news.example.co.uk`.
+    Domain,
+}
+
+/// Settings for building an index.
+#[derive(Clone, Debug)]
+pub struct IndexConfig {
+    /// Maximum number of feed threads (multi).
+    pub max_threads_feeds: u32,
+    /// Maximum number of article threads per hostname (multi).
+    pub max_threads_sites: u32,
+    /// Whether the per-site limits count articles by host or by registrable domain.
+    pub site_key: SiteKey,
+    /// The public suffix list that `SiteKey::Domain` finds registrable domains with, e.g. parsed
+    /// from https://publicsuffix.org/list/public_suffix_list.dat. By default it is empty, which
+    /// makes every top-level domain (but no more) a public suffix.
+    pub public_suffixes: Arc<publicsuffix::List>,
+    /// Maximum number of threads overall (multi). Feed threads count towards this limit too, so
+    /// it is raised to at least `max_threads_feeds + 1` to leave room for article threads.
+    pub max_threads_total: u32,
+    /// Number of workers in the feeds pool (pool).
+    pub feeds_pool_size: usize,
+    /// Number of workers in the articles pool (pool).
+    pub sites_pool_size: usize,
+    /// Number of article jobs that may wait for a worker of the articles pool (pool). Feed jobs
+    /// block once that many are waiting, which bounds the memory held by queued articles.
+    pub sites_queue_size: usize,
+    /// Whether articles are fetched and indexed in full, or only their summaries in the feed.
+    pub index_source: IndexSource,
+    /// How the text of articles (and queries, see `query_words`) is split into words.
+    pub tokenizer: Tokenizer,
+    /// Lowercase words that are dropped instead of counted.
+    pub stop_words: HashSet<String>,
+    /// Words with fewer characters than this are dropped instead of counted.
+    pub min_word_len: usize,
+    /// How many times each word of an article's title is counted, on top of its count in the body,
+    /// so that headline terms rank higher. 0 leaves titles out.
+    pub title_weight: u32,
+    /// Whether numbers (like "2024" or "3.14", but not "rust2021") are counted as words.
+    pub keep_numbers: bool,
+    /// Whether punctuation (like "—" or "•") is counted as words.
+    pub keep_punct: bool,
+    /// Time allowed for fetching a feed or an article.
+    pub timeout: Duration,
+    /// How often a fetch is retried after a connection error, timeout, or 5xx response.
+    pub max_retries: u32,
+    /// Delay before the first retry; it doubles with every further retry.
+    pub base_delay: Duration,
+    /// User-Agent header sent with every request.
+    pub user_agent: String,
+    /// How many redirects a request may follow before it fails with
+    /// `RssIndexError::TooManyRedirects`. A redirect back to a URL already visited fails at once.
+    pub max_redirects: usize,
+    /// If set, a response body (of a feed or an article) larger than this many bytes fails with
+    /// `RssIndexError::BodyTooLarge`, and no more than that is ever read of it.
+    pub max_body_bytes: Option<usize>,
+    /// If set, feed items published before this time are skipped.
+    pub since: Option<DateTime<Utc>>,
+    /// Whether items without a (parseable) publication date are indexed when `since` is set.
+    pub include_undated: bool,
+    /// If set, at most this many new articles are indexed per feed; already seen articles don't
+    /// count.
+    pub max_articles_per_feed: Option<usize>,
+    /// If set, at most this many distinct words (not occurrences) of each article are kept: the
+    /// most frequent ones, title words included. The whole article is still read, so this bounds
+    /// the memory an article takes up in the index, not the memory needed to process it.
+    pub max_words_per_article: Option<usize>,
+    /// Whether to record where in an article each word appears, for `search_phrase`. This keeps
+    /// one number per word of every article, so it costs about as much memory as the text itself.
+    pub record_positions: bool,
+    /// Whether words are reduced to their stem (with the Porter stemmer for English), so that
+    /// e.g. "runs" and "running" are both counted as "run". Queries of an index are stemmed too,
+    /// see `ArticleIndex::set_query_config`.
+    pub stem: bool,
+    /// If set, requests for articles on the same host are sent at least this far apart.
+    pub per_host_delay: Option<Duration>,
+    /// Extra headers (e.g. `Authorization`) sent when fetching the feed with the given URL.
+    pub feed_headers: HashMap<String, Vec<(HeaderName, HeaderValue)>>,
+    /// Whether the `feed_headers` of a feed are also sent when fetching articles on the same host
+    /// as the feed. They are never sent to other hosts.
+    pub share_feed_headers: bool,
+    /// How many articles each article worker (pool) collects before adding them to the shared
+    /// index, so that the shared index is locked once per batch rather than once per article.
+    pub flush_interval: usize,
+    /// If set, the set of seen URLs gets a Bloom filter sized for this many URLs, which answers
+    /// most lookups of new URLs without locking. Worth it for crawls of millions of URLs.
+    pub bloom_capacity: Option<usize>,
+    /// Query parameters that are dropped from URLs before they are compared for deduplication (see
+    /// `canonical_url`). A name ending in `*` matches every parameter starting with the rest.
+    pub tracking_params: Vec<String>,
+    /// Whether articles are told apart by the guid of their feed item rather than by their link,
+    /// when the item has one (see `dedup_key`), so that e.g. links differing only in their query
+    /// string are the same article if their guid is.
+    pub dedup_by_guid: bool,
+    /// Whether an article listed by several feeds is indexed once, or once per feed.
+    pub dedup_scope: DedupScope,
+    /// If set, feeds are still fetched and their articles enumerated (and counted as seen), but
+    /// articles are only logged instead of being fetched and indexed.
+    pub dry_run: bool,
+    /// Whether articles disallowed by the robots.txt of their host for `user_agent` are skipped
+    /// (by `HttpFetcher`).
+    #[cfg(feature = "robots")]
+    pub respect_robots: bool,
+}
+
+impl IndexConfig {
+    /// Whether a feed item is recent enough to be indexed.
+    pub fn wants_item(&self, item: &FeedItem) -> bool {
+        match (self.since, item.published) {
+            (None, _) => true,
+            (Some(since), Some(published)) => published >= since,
+            (Some(_), None) => self.include_undated,
+        }
+    }
+
+    /// The extra headers to send with a request for `url`: the `feed_headers` of `url` if it is a
+    /// feed, or else, with `share_feed_headers`, those of the feeds on the same host.
+    pub fn headers_for(&self, url: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(feed_headers) = self.feed_headers.get(url) {
+            headers.extend(feed_headers.iter().cloned());
+            return headers;
+        }
+        if !self.share_feed_headers {
+            return headers;
+        }
+        let host = |url: &str| url::Url::parse(url).ok()?.host_str().map(String::from);
+        let article_host = match host(url) {
+            Some(article_host) => article_host,
+            None => return headers,
+        };
+        for (feed_url, feed_headers) in &self.feed_headers {
+            if host(feed_url).as_ref() == Some(&article_host) {
+                headers.extend(feed_headers.iter().cloned());
+            }
+        }
+        headers
+    }
+
+    /// The key that per-site limits count articles on `host` under, according to `site_key`. An IP
+    /// address, or a host that is a public suffix itself, is always its own key.
+    pub fn site_key_of(&self, host: &str) -> String {
+        if self.site_key == SiteKey::Host
+            || host.trim_matches(&['[', ']'][..]).parse::<IpAddr>().is_ok()
+        {
+            return host.to_string();
+        }
+        match self.public_suffixes.domain(host.as_bytes()) {
+            Some(domain) => String::from_utf8_lossy(domain.as_bytes()).into_owned(),
+            None => host.to_string(),
+        }
+    }
+
+    /// Whether a feed already had `count` articles indexed and may not get any more.
+    pub fn feed_is_full(&self, count: usize) -> bool {
+        self.max_articles_per_feed.is_some_and(|max| count >= max)
+    }
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        IndexConfig {
+            max_threads_feeds: MAX_THREADS_FEEDS,
+            max_threads_sites: MAX_THREADS_SITES,
+            site_key: SiteKey::default(),
+            public_suffixes: Arc::new(publicsuffix::List::new()),
+            max_threads_total: MAX_THREADS_TOTAL,
+            feeds_pool_size: SIZE_FEEDS_POOL,
+            sites_pool_size: SIZE_SITES_POOL,
+            sites_queue_size: SIZE_SITES_QUEUE,
+            index_source: IndexSource::default(),
+            tokenizer: Tokenizer::default(),
+            stop_words: DEFAULT_STOP_WORDS.iter().map(|w| w.to_string()).collect(),
+            min_word_len: MIN_WORD_LEN,
+            title_weight: TITLE_WEIGHT,
+            keep_numbers: false,
+            keep_punct: false,
+            timeout: TIMEOUT,
+            max_retries: MAX_RETRIES,
+            base_delay: BASE_DELAY,
+            user_agent: USER_AGENT.to_string(),
+            max_redirects: MAX_REDIRECTS,
+            max_body_bytes: None,
+            since: None,
+            include_undated: true,
+            max_articles_per_feed: None,
+            max_words_per_article: None,
+            record_positions: false,
+            stem: false,
+            per_host_delay: None,
+            flush_interval: FLUSH_INTERVAL,
+            bloom_capacity: None,
+            feed_headers: HashMap::new(),
+            share_feed_headers: false,
+            tracking_params: TRACKING_PARAMS.iter().map(|p| p.to_string()).collect(),
+            dedup_by_guid: true,
+            dedup_scope: DedupScope::default(),
+            dry_run: false,
+            #[cfg(feature = "robots")]
+            respect_robots: false,
+        }
+    }
+}
+
+/// Builds an `IndexConfig` with chainable setters, starting from the defaults, e.g.
+/// `IndexConfig::builder().timeout(Duration::from_secs(5)).max_retries(0).build()`.
+#[derive(Clone, Debug, Default)]
+pub struct IndexConfigBuilder {
+    config: IndexConfig,
+}
+
+/// Setters of `IndexConfigBuilder` that set a field of `IndexConfig` to the value given.
+macro_rules! setters {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        $(
+            #[doc = concat!("Set `IndexConfig::", stringify!($field), "`.")]
+            pub fn $field(mut self, $field: $ty) -> Self {
+                self.config.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+/// Setters of `IndexConfigBuilder` that set an optional field of `IndexConfig` to `Some` value.
+macro_rules! optional_setters {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        $(
+            #[doc = concat!("Set `IndexConfig::", stringify!($field), "`.")]
+            pub fn $field(mut self, $field: $ty) -> Self {
+                self.config.$field = Some($field);
+                self
+            }
+        )*
+    };
+}
+
+impl IndexConfig {
+    pub fn builder() -> IndexConfigBuilder {
+        IndexConfigBuilder::default()
+    }
+}
+
+impl IndexConfigBuilder {
+    setters! {
+        max_threads_feeds: u32,
+        max_threads_sites: u32,
+        site_key: SiteKey,
+        max_threads_total: u32,
+        feeds_pool_size: usize,
+        sites_pool_size: usize,
+        sites_queue_size: usize,
+        flush_interval: usize,
+        min_word_len: usize,
+        title_weight: u32,
+        keep_numbers: bool,
+        keep_punct: bool,
+        timeout: Duration,
+        max_retries: u32,
+        base_delay: Duration,
+        max_redirects: usize,
+        include_undated: bool,
+        record_positions: bool,
+        stem: bool,
+        share_feed_headers: bool,
+        dedup_by_guid: bool,
+        dedup_scope: DedupScope,
+        index_source: IndexSource,
+        dry_run: bool,
+    }
+
+    optional_setters! {
+        since: DateTime<Utc>,
+        max_articles_per_feed: usize,
+        max_words_per_article: usize,
+        per_host_delay: Duration,
+        bloom_capacity: usize,
+        max_body_bytes: usize,
+    }
+
+    /// Set `IndexConfig::stop_words`, replacing the default ones. Words are lowercased.
+    pub fn stop_words<I, S>(mut self, stop_words: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.config.stop_words = stop_words
+            .into_iter()
+            .map(|word| word.as_ref().to_lowercase())
+            .collect();
+        self
+    }
+
+    /// Set `IndexConfig::public_suffixes`.
+    pub fn public_suffixes(mut self, public_suffixes: publicsuffix::List) -> Self {
+        self.config.public_suffixes = Arc::new(public_suffixes);
+        self
+    }
+
+    /// Set `IndexConfig::tokenizer`.
+    pub fn tokenizer(mut self, tokenizer: Tokenizer) -> Self {
+        self.config.tokenizer = tokenizer;
+        self
+    }
+
+    /// Set `IndexConfig::user_agent`.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.config.user_agent = user_agent.into();
+        self
+    }
+
+    /// Set `IndexConfig::tracking_params`, replacing the default ones.
+    pub fn tracking_params<I, S>(mut self, tracking_params: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.tracking_params = tracking_params.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set `IndexConfig::respect_robots`.
+    #[cfg(feature = "robots")]
+    pub fn respect_robots(mut self, respect_robots: bool) -> Self {
+        self.config.respect_robots = respect_robots;
+        self
+    }
+
+    /// Add a header to the `IndexConfig::feed_headers` of the feed at `feed_url`.
+    pub fn feed_header(mut self, feed_url: &str, name: HeaderName, value: HeaderValue) -> Self {
+        self.config
+            .feed_headers
+            .entry(feed_url.to_string())
+            .or_default()
+            .push((name, value));
+        self
+    }
+
+    pub fn build(self) -> IndexConfig {
+        self.config
+    }
+}
+
+/// Bag of words
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bag<T>
+where
+    T: Ord,
+{
+    pub counts: BTreeMap<T, u32>,
+}
+
+/// The URL and title of an article.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub struct Article {
+    pub url: String,
+    pub title: String,
+    /// When the article was published, if its feed says so in a format we understand.
+    #[serde(default)]
+    pub published: Option<DateTime<Utc>>,
+}
+
+/// The site (hostname) and title of an article.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
+pub struct ArticleKey {
+    pub site: String,
+    pub title: String,
+}
+
+/// An article of an index with its words, in the canonical form returned by
+/// `ArticleIndex::snapshot`.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize)]
+pub struct ArticleSummary {
+    /// All URLs of the article, sorted.
+    pub urls: Vec<String>,
+    pub site: String,
+    pub title: String,
+    /// The words of the article with their counts, by decreasing count and then alphabetically.
+    pub words: Vec<(String, u32)>,
+}
+
+/// How an index differs from an earlier one, by article URL. Each list is sorted.
+#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize)]
+pub struct IndexDiff {
+    /// URLs only in the newer index.
+    pub added: Vec<String>,
+    /// URLs only in the earlier index.
+    pub removed: Vec<String>,
+    /// URLs in both indexes whose articles have different words or counts.
+    pub modified: Vec<String>,
+}
+
+/// Map from an ArticleKey to a vector of URLs and bag of words appearing in this article.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArticleIndex {
+    #[serde(with = "map_entries")]
+    pub index: HashMap<ArticleKey, (Vec<String>, Bag<String>)>,
+    /// Positions of the words of each article, for articles indexed with
+    /// `IndexConfig::record_positions`.
+    #[serde(default, with = "map_entries")]
+    pub positions: HashMap<ArticleKey, Positions>,
+    /// Publication dates of the articles whose feeds gave one.
+    #[serde(default, with = "map_entries")]
+    pub published: HashMap<ArticleKey, DateTime<Utc>>,
+    /// URLs of the feeds listing each article, for articles whose feed is known.
+    #[serde(default, with = "map_entries")]
+    pub source_feeds: HashMap<ArticleKey, HashSet<String>>,
+    /// For each site, the articles containing each word, so that `search_in_site` only looks at
+    /// the articles of one site. It is derived from `index`, so it is rebuilt when an index is
+    /// read back rather than stored.
+    #[serde(skip)]
+    site_words: HashMap<String, HashMap<String, HashSet<ArticleKey>>>,
+    /// Number of articles of each site. Derived from `index`, like `site_words`.
+    #[serde(skip)]
+    site_articles: HashMap<String, usize>,
+    /// Number of articles containing each word, i.e. its document frequency, for `word_count` and
+    /// `search_by`. Derived from `index`, like `site_words`.
+    #[serde(skip)]
+    word_articles: HashMap<String, usize>,
+    /// The settings query words are normalized with, see `set_query_config`. None stands for
+    /// `IndexConfig::default()`.
+    #[serde(skip)]
+    query_config: Option<Arc<IndexConfig>>,
+}
+
+/// Two indexes are equal if they hold the same articles; how they normalize queries doesn't count.
+impl PartialEq for ArticleIndex {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+            && self.positions == other.positions
+            && self.published == other.published
+            && self.source_feeds == other.source_feeds
+    }
+}
+
+/// Map from each word of an article to its positions (counting only indexed words), in
+/// increasing order.
+pub type Positions = HashMap<String, Vec<u32>>;
+
+/// The words of an article: how often each appears, and where, if positions are recorded.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ArticleWords {
+    pub counts: Bag<String>,
+    pub positions: Option<Positions>,
+    /// The URL the article was fetched from, if redirects led away from the URL in the feed.
+    pub url: Option<String>,
+    /// When the article was published, as taken from its `Article`.
+    pub published: Option<DateTime<Utc>>,
+    /// The URL of the feed the article was listed in.
+    pub feed: Option<String>,
+}
+
+impl ArticleWords {
+    /// Keep only the `max_words` most frequent words (see `Bag::keep_most_frequent`), along with
+    /// their positions.
+    pub fn keep_most_frequent(&mut self, max_words: usize) {
+        self.counts.keep_most_frequent(max_words);
+        if let Some(positions) = &mut self.positions {
+            let counts = &self.counts.counts;
+            positions.retain(|word, _| counts.contains_key(word));
+        }
+    }
+}
+
+/// Serialize a HashMap as a list of (key, value) pairs, since JSON only allows string keys.
+mod map_entries {
+    use super::*;
+
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let entries = Vec::<(K, V)>::deserialize(deserializer)?;
+        Ok(entries.into_iter().collect())
+    }
+}
+
+/// How the terms of a multi-word query are combined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryMode {
+    /// Articles must contain every term.
+    All,
+    /// Articles must contain at least one term.
+    Any,
+}
+
+/// What a scoring function given to `ArticleIndex::search_by` knows about the searched term and
+/// the article being scored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScoringContext {
+    /// How often the term appears in the article.
+    pub term_frequency: u32,
+    /// How many articles contain the term.
+    pub document_frequency: usize,
+    /// How many articles the index holds.
+    pub total_documents: usize,
+    /// How many words, counting repeats, the article has.
+    pub article_length: u32,
+}
+
+/// Map from words to Maps from Articles to counts of how frequently the word shows up in the
+/// article. Articles not containing this word should not be in the inner HashMap.
+pub struct RssIndex {
+    pub index: HashMap<String, HashMap<Article, u32>>,
+}
+
+impl<T> Bag<T>
+where
+    T: Ord,
+{
+    pub fn new() -> Self {
+        Bag {
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Add one element.
+    pub fn add(&mut self, t: T) {
+        *self.counts.entry(t).or_insert(0) += 1;
+    }
+
+    /// Keep only the `n` elements with the highest counts, and of those with equal counts, the
+    /// smallest ones.
+    pub fn keep_most_frequent(&mut self, n: usize) {
+        if self.counts.len() <= n {
+            return;
+        }
+        let mut entries = std::mem::take(&mut self.counts)
+            .into_iter()
+            .collect::<Vec<_>>();
+        // a stable sort keeps elements of equal counts in ascending order
+        entries.sort_by(|(_, count1), (_, count2)| count2.cmp(count1));
+        entries.truncate(n);
+        self.counts = entries.into_iter().collect();
+    }
+
+    /// Add the counts of another Bag to this one.
+    pub fn merge_with(&mut self, other: Self) {
+        for (t, count) in other.counts {
+            *self.counts.entry(t).or_insert(0) += count;
+        }
+    }
+
+    /// Take intersection with another Bag. The count of any element appearing in both Bags should
+    /// be the minimum of the counts in each Bag. Elements appearing in just one bag should have
+    /// count zero.
+    pub fn intersect_with(&mut self, other: &Self) {
+        let mut my_iter = self.counts.iter_mut();
+        let mut other_iter = other.counts.iter();
+
+        let mut my_cur = my_iter.next();
+        let mut other_cur = other_iter.next();
+
+        while let (Some(my_kv), Some(ot_kv)) = (&mut my_cur, &other_cur) {
+            match my_kv.0.cmp(ot_kv.0) {
+                Ordering::Less => {
+                    *my_kv.1 = 0;
+                    my_cur = my_iter.next();
+                }
+                Ordering::Greater => {
+                    other_cur = other_iter.next();
+                }
+                Ordering::Equal => {
+                    *my_kv.1 = (*my_kv.1).min(*ot_kv.1);
+                    my_cur = my_iter.next();
+                    other_cur = other_iter.next();
+                }
+            }
+        }
+        // whatever is left of this bag comes after the last element of the other one
+        for (_, count) in my_cur.into_iter().chain(my_iter) {
+            *count = 0;
+        }
+    }
+}
+
+impl Article {
+    pub fn new(url: String, title: String) -> Self {
+        Article {
+            url,
+            title,
+            published: None,
+        }
+    }
+
+    /// Same as `new`, with the publication date of the article.
+    pub fn with_published(url: String, title: String, published: Option<DateTime<Utc>>) -> Self {
+        Article {
+            url,
+            title,
+            published,
+        }
+    }
+
+    pub fn cmp_title(&self, other: &Article) -> std::cmp::Ordering {
+        self.title.cmp(&other.title)
+    }
+}
+
+impl ArticleKey {
+    pub fn new(site: String, title: String) -> Self {
+        ArticleKey { site, title }
+    }
+}
+
+/// Take one off the tally of `key`, dropping it once it reaches zero.
+fn untally(tallies: &mut HashMap<String, usize>, key: &str) {
+    if let Some(count) = tallies.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            tallies.remove(key);
+        }
+    }
+}
+
+impl ArticleIndex {
+    pub fn new() -> Self {
+        ArticleIndex {
+            index: HashMap::new(),
+            positions: HashMap::new(),
+            published: HashMap::new(),
+            source_feeds: HashMap::new(),
+            site_words: HashMap::new(),
+            site_articles: HashMap::new(),
+            word_articles: HashMap::new(),
+            query_config: None,
+        }
+    }
+
+    /// Normalize the words of queries with `config` (see `query_words`), which should be the
+    /// settings the index was built with, so that e.g. a query for "Running" finds the stem "run"
+    /// in an index built with `IndexConfig::stem`. A crawl sets the settings of the index it adds
+    /// to; an index read back with `load` normalizes like `IndexConfig::default()` until this is
+    /// called.
+    pub fn set_query_config(&mut self, config: &IndexConfig) {
+        self.query_config = Some(Arc::new(config.clone()));
+    }
+
+    /// The settings query words are normalized with.
+    fn query_config(&self) -> &IndexConfig {
+        static DEFAULT: OnceLock<IndexConfig> = OnceLock::new();
+        match &self.query_config {
+            Some(config) => config,
+            None => DEFAULT.get_or_init(IndexConfig::default),
+        }
+    }
+
+    /// Add a site, title, URL, and a bag of words to the ArticleIndex.
+    pub fn add(&mut self, site: String, title: String, url: String, words: Bag<String>) {
+        let key = ArticleKey::new(site, title);
+        let new_article = !self.index.contains_key(&key);
+        self.index
+            .entry(key.clone())
+            .and_modify(|e| {
+                e.0.push(url.clone());
+                e.1.intersect_with(&words)
+            })
+            .or_insert((vec![url], words));
+        self.update_site_words(&key, new_article);
+    }
+
+    /// Bring `site_words` and the tallies up to date with the words of the article `key`, which
+    /// may have gained words (through `merge`) or lost some (through the intersection in `add`),
+    /// or may just have been added (`new_article`).
+    fn update_site_words(&mut self, key: &ArticleKey, new_article: bool) {
+        let words = match self.index.get(key) {
+            Some((_, words)) => words,
+            None => return,
+        };
+        if new_article {
+            *self.site_articles.entry(key.site.clone()).or_default() += 1;
+        }
+        let site_words = self.site_words.entry(key.site.clone()).or_default();
+        for (word, &count) in &words.counts {
+            if count > 0 {
+                let keys = site_words.entry(word.clone()).or_default();
+                if keys.insert(key.clone()) {
+                    *self.word_articles.entry(word.clone()).or_default() += 1;
+                }
+            } else if let Some(keys) = site_words.get_mut(word) {
+                if keys.remove(key) {
+                    untally(&mut self.word_articles, word);
+                }
+                if keys.is_empty() {
+                    site_words.remove(word);
+                }
+            }
+        }
+    }
+
+    /// Remove the article `key` from `site_words` and the tallies, before it is removed from the
+    /// index.
+    fn forget_site_words(&mut self, key: &ArticleKey) {
+        let words = match self.index.get(key) {
+            Some((_, words)) => words,
+            None => return,
+        };
+        untally(&mut self.site_articles, &key.site);
+        let site_words = match self.site_words.get_mut(&key.site) {
+            Some(site_words) => site_words,
+            None => return,
+        };
+        for word in words.counts.keys() {
+            if let Some(keys) = site_words.get_mut(word) {
+                if keys.remove(key) {
+                    untally(&mut self.word_articles, word);
+                }
+                if keys.is_empty() {
+                    site_words.remove(word);
+                }
+            }
+        }
+        if site_words.is_empty() {
+            self.site_words.remove(&key.site);
+        }
+    }
+
+    /// Build `site_words` and the tallies of an index that was read back.
+    fn with_site_words(mut self) -> Self {
+        let keys = self.index.keys().cloned().collect::<Vec<_>>();
+        for key in &keys {
+            self.update_site_words(key, true);
+        }
+        self
+    }
+
+    /// Same as `add`, but also keeps the positions of the words, if they were recorded, the
+    /// publication date, if known, and the feed the article came from, if known. An article added
+    /// again under another URL keeps the positions and date of its first URL, and gets the feed of
+    /// the new URL added to its feeds. If the article was redirected, it is stored under the URL
+    /// it was fetched from instead of `url`.
+    pub fn add_article(&mut self, site: String, title: String, url: String, words: ArticleWords) {
+        let url = words.url.unwrap_or(url);
+        let key = ArticleKey::new(site.clone(), title.clone());
+        if let Some(feed) = words.feed {
+            self.source_feeds
+                .entry(key.clone())
+                .or_default()
+                .insert(feed);
+        }
+        if let Some(published) = words.published {
+            self.published.entry(key.clone()).or_insert(published);
+        }
+        if let Some(positions) = words.positions {
+            self.positions.entry(key).or_insert(positions);
+        }
+        self.add(site, title, url, words.counts);
+    }
+
+    /// Add an article whose plain `text` is already at hand, without fetching anything. The text
+    /// is split up into words as `process_article` splits up a fetched article, and the title
+    /// words are added, following `config` in both.
+    pub fn add_document(
+        &mut self,
+        site: String,
+        title: String,
+        url: String,
+        text: &str,
+        config: &IndexConfig,
+    ) -> RssIndexResult<()> {
+        let article = Article::new(url.clone(), title.clone());
+        let mut words = text_words(text, config)?;
+        add_article_details(&article, config, &mut words);
+        self.add_article(site, title, url, words);
+        Result::Ok(())
+    }
+
+    /// Fold the articles of `other` into this index, summing the counts of words appearing in
+    /// both. An article of `other` sharing a URL with an article already in this index is treated
+    /// as the same article, even if its site or title differ.
+    pub fn merge(&mut self, other: ArticleIndex) {
+        let mut keys_by_url = HashMap::new();
+        for (article_key, (urls, _)) in &self.index {
+            for url in urls {
+                keys_by_url.insert(url.clone(), article_key.clone());
+            }
+        }
+
+        for (article_key, (urls, words)) in other.index {
+            let article_key = urls
+                .iter()
+                .find_map(|url| keys_by_url.get(url).cloned())
+                .unwrap_or(article_key);
+            let new_article = !self.index.contains_key(&article_key);
+            let entry = self
+                .index
+                .entry(article_key.clone())
+                .or_insert_with(|| (Vec::new(), Bag::new()));
+            for url in urls {
+                if !entry.0.contains(&url) {
+                    keys_by_url.insert(url.clone(), article_key.clone());
+                    entry.0.push(url);
+                }
+            }
+            entry.1.merge_with(words);
+            self.update_site_words(&article_key, new_article);
+        }
+        for (article_key, positions) in other.positions {
+            self.positions.entry(article_key).or_insert(positions);
+        }
+        for (article_key, published) in other.published {
+            self.published.entry(article_key).or_insert(published);
+        }
+        for (article_key, feeds) in other.source_feeds {
+            self.source_feeds
+                .entry(article_key)
+                .or_default()
+                .extend(feeds);
+        }
+    }
+
+    /// Move all articles of `other` into this index, as if each had been added with `add_article`
+    /// (unlike `merge`, which sums the counts of articles in both).
+    pub fn absorb(&mut self, other: ArticleIndex) {
+        for (key, (urls, words)) in other.index {
+            self.add_entry(key, urls, words);
+        }
+        for (key, positions) in other.positions {
+            self.positions.entry(key).or_insert(positions);
+        }
+        for (key, published) in other.published {
+            self.published.entry(key).or_insert(published);
+        }
+        for (key, feeds) in other.source_feeds {
+            self.source_feeds.entry(key).or_default().extend(feeds);
+        }
+    }
+
+    /// Record that the feed at `feed_url` lists the articles at the given URLs as well, for each
+    /// `(url, feed_url)` of `sources`. This is for articles skipped as already seen when they
+    /// turn up in another feed. URLs not in the index (e.g. of articles that failed, or that were
+    /// redirected elsewhere) are ignored.
+    pub fn add_source_feeds<I>(&mut self, sources: I)
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut keys_by_url = HashMap::new();
+        for (article_key, (urls, _)) in &self.index {
+            for url in urls {
+                keys_by_url.insert(url.as_str(), article_key);
+            }
+        }
+        for (url, feed_url) in sources {
+            if let Some(&article_key) = keys_by_url.get(url.as_str()) {
+                self.source_feeds
+                    .entry(article_key.clone())
+                    .or_default()
+                    .insert(feed_url);
+            }
+        }
+    }
+
+    /// All articles listed in the feed at `feed_url`, sorted by title. Articles are reported under
+    /// their alphabetically-earliest URL.
+    pub fn articles_from_feed(&self, feed_url: &str) -> Vec<Article> {
+        let mut articles = self
+            .source_feeds
+            .iter()
+            .filter(|(_, feeds)| feeds.contains(feed_url))
+            .filter_map(|(article_key, _)| {
+                let url = self.index.get(article_key)?.0.iter().min()?;
+                let title = article_key.title.clone();
+                let published = self.published.get(article_key).copied();
+                Some(Article::with_published(url.clone(), title, published))
+            })
+            .collect::<Vec<_>>();
+        articles.sort_by(|art1, art2| art1.cmp_title(art2));
+        articles
+    }
+
+    /// Add an article that may already have several URLs, as if each URL had been added with
+    /// `add`.
+    fn add_entry(&mut self, key: ArticleKey, urls: Vec<String>, words: Bag<String>) {
+        let new_article = match self.index.entry(key.clone()) {
+            Entry::Occupied(mut e) => {
+                let e = e.get_mut();
+                e.0.extend(urls);
+                e.1.intersect_with(&words);
+                false
+            }
+            Entry::Vacant(e) => {
+                e.insert((urls, words));
+                true
+            }
+        };
+        self.update_site_words(&key, new_article);
+    }
+
+    /// Write the index as JSON.
+    pub fn to_json_writer<W: Write>(&self, writer: W) -> RssIndexResult<()> {
+        serde_json::to_writer(writer, self)?;
+        Result::Ok(())
+    }
+
+    /// Read an index written by `to_json_writer`.
+    pub fn from_json_reader<R: Read>(reader: R) -> RssIndexResult<ArticleIndex> {
+        let index: ArticleIndex = serde_json::from_reader(reader)?;
+        Result::Ok(index.with_site_words())
+    }
+
+    /// Write the index as CSV, with a header and one `site,title,url,word,frequency` row per word
+    /// of each article. Articles are listed under their alphabetically-earliest URL, sorted by site
+    /// and title, and their words alphabetically.
+    pub fn export_csv<W: Write>(&self, writer: W) -> RssIndexResult<()> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(["site", "title", "url", "word", "frequency"])?;
+
+        let mut articles = self
+            .index
+            .iter()
+            .filter_map(|(key, (urls, words))| Some((key, urls.iter().min()?, words)))
+            .collect::<Vec<_>>();
+        articles.sort_by(|(key1, _, _), (key2, _, _)| {
+            key1.site.cmp(&key2.site).then(key1.title.cmp(&key2.title))
+        });
+        for (key, url, words) in articles {
+            for (word, count) in words.counts.iter().filter(|(_, &count)| count > 0) {
+                let count = count.to_string();
+                writer.write_record([&key.site, &key.title, url, word, &count])?;
+            }
+        }
+        writer.flush()?;
+        Result::Ok(())
+    }
+
+    /// Write a human-readable report of the index: each site with its articles, and each article
+    /// with its alphabetically-earliest URL and its `top_n_words` most frequent words. Sites,
+    /// articles and ties between words are sorted alphabetically, so the report of an index is
+    /// the same on every run.
+    pub fn print_report<W: Write>(&self, writer: W, top_n_words: usize) -> RssIndexResult<()> {
+        let mut writer = BufWriter::new(writer);
+
+        let mut sites = BTreeMap::new();
+        for (key, (urls, words)) in &self.index {
+            if let Some(url) = urls.iter().min() {
+                sites
+                    .entry(&key.site)
+                    .or_insert_with(Vec::new)
+                    .push((&key.title, url, words));
+            }
+        }
+        for (site, mut articles) in sites {
+            articles.sort_by_key(|(title, _, _)| *title);
+            let plural = if articles.len() == 1 { "" } else { "s" };
+            writeln!(writer, "{} ({} article{})", site, articles.len(), plural)?;
+            for (title, url, words) in articles {
+                let mut top = words
+                    .counts
+                    .iter()
+                    .filter(|(_, &count)| count > 0)
+                    .collect::<Vec<_>>();
+                top.sort_by(|(word1, count1), (word2, count2)| {
+                    count2.cmp(count1).then(word1.cmp(word2))
+                });
+                let top = top
+                    .iter()
+                    .take(top_n_words)
+                    .map(|(word, count)| format!("{} ({})", word, count))
+                    .collect::<Vec<_>>();
+                writeln!(writer, "    {}", title)?;
+                writeln!(writer, "        {}", url)?;
+                if !top.is_empty() {
+                    writeln!(writer, "        {}", top.join(", "))?;
+                }
+            }
+        }
+        writer.flush()?;
+        Result::Ok(())
+    }
+
+    /// Write the index as JSON to the file at `path`, replacing it if it exists. The index is
+    /// written to `<path>.tmp` first and then moved to `path`, so that a save that is cut short
+    /// never leaves a half-written index at `path`.
+    pub fn save(&self, path: &Path) -> RssIndexResult<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let file = File::create(&tmp_path).map_err(RssIndexError::IoError)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self).map_err(RssIndexError::SerdeError)?;
+        let file = writer
+            .into_inner()
+            .map_err(|e| RssIndexError::IoError(e.into_error()))?;
+        file.sync_all().map_err(RssIndexError::IoError)?;
+        std::fs::rename(&tmp_path, path).map_err(RssIndexError::IoError)?;
+        Result::Ok(())
+    }
+
+    /// Read an index written by `save` from the file at `path`.
+    pub fn load(path: &Path) -> RssIndexResult<ArticleIndex> {
+        let file = File::open(path).map_err(RssIndexError::IoError)?;
+        let index: ArticleIndex =
+            serde_json::from_reader(BufReader::new(file)).map_err(RssIndexError::SerdeError)?;
+        Result::Ok(index.with_site_words())
+    }
+
+    /// Find all articles containing `word`, ordered by decreasing count of the word in the article
+    /// and then alphabetically by title. The query is normalized with `query_word` to match how
+    /// words are stored (see `set_query_config`). Articles are reported under their
+    /// alphabetically-earliest URL.
+    pub fn search(&self, word: &str) -> Vec<(Article, u32)> {
+        let word = query_word(word, self.query_config());
+        let mut matches = Vec::new();
+        for (article_key, (urls, words)) in &self.index {
+            if let (Some(&count), Some(url)) = (words.counts.get(&word), urls.iter().min()) {
+                if count > 0 {
+                    let article = Article::new(url.clone(), article_key.title.clone());
+                    matches.push((article, count));
+                }
+            }
+        }
+        matches.sort_by(|(art1, ct1), (art2, ct2)| ct2.cmp(ct1).then(art1.cmp_title(art2)));
+        matches
+    }
+
+    /// Same as `search`, but only among the articles of `site`. An unknown site has no articles.
+    pub fn search_in_site(&self, site: &str, term: &str) -> Vec<(Article, u32)> {
+        let term = query_word(term, self.query_config());
+        let keys = match self.site_words.get(site).and_then(|words| words.get(&term)) {
+            Some(keys) => keys,
+            None => return Vec::new(),
+        };
+        let mut matches = keys
+            .iter()
+            .filter_map(|article_key| {
+                let (urls, words) = self.index.get(article_key)?;
+                let count = *words.counts.get(&term)?;
+                let url = urls.iter().min()?;
+                let article = Article::new(url.clone(), article_key.title.clone());
+                Some((article, count))
+            })
+            .collect::<Vec<_>>();
+        matches.sort_by(|(art1, ct1), (art2, ct2)| ct2.cmp(ct1).then(art1.cmp_title(art2)));
+        matches
+    }
+
+    /// Find all articles containing the words of `phrase` next to each other and in order, sorted
+    /// by title. Only articles indexed with `IndexConfig::record_positions` can match, and only the
+    /// text of an article is searched, not its title (see `add_title_words`). The phrase is split
+    /// and normalized with `query_words`, the same way the text of articles is. Stop words aren't
+    /// indexed, so a phrase containing one doesn't match either.
+    pub fn search_phrase(&self, phrase: &str) -> Vec<Article> {
+        let phrase = query_words(phrase, self.query_config());
+        if phrase.is_empty() {
+            return Vec::new();
+        }
+
+        let mut matches = Vec::new();
+        for (article_key, positions) in &self.positions {
+            let url = match self
+                .index
+                .get(article_key)
+                .and_then(|(urls, _)| urls.iter().min())
+            {
+                Some(url) => url,
+                None => continue,
+            };
+            let starts = match positions.get(&phrase[0]) {
+                Some(starts) => starts,
+                None => continue,
+            };
+            let found = starts.iter().any(|&start| {
+                phrase[1..].iter().zip(start + 1..).all(|(word, pos)| {
+                    positions
+                        .get(word)
+                        .is_some_and(|ps| ps.binary_search(&pos).is_ok())
+                })
+            });
+            if found {
+                matches.push(Article::new(url.clone(), article_key.title.clone()));
+            }
+        }
+        matches.sort_by(|art1, art2| art1.cmp_title(art2));
+        matches
+    }
+
+    /// Same as `search`, but articles are scored by TF-IDF instead of the raw count. The term
+    /// frequency is the count of `word` divided by the number of words in the article, and the
+    /// inverse document frequency is `ln((1 + n) / (1 + df)) + 1`, where `n` is the number of
+    /// articles and `df` the number of articles containing `word`. A rare word thus scores higher
+    /// than a common one, and a long article doesn't win just by being long. Ties are broken by
+    /// title.
+    pub fn search_ranked(&self, word: &str) -> Vec<(Article, f64)> {
+        self.search_by(word, |_, cx| {
+            let tf = f64::from(cx.term_frequency) / f64::from(cx.article_length);
+            let n = cx.total_documents as f64;
+            let idf = ((1.0 + n) / (1.0 + cx.document_frequency as f64)).ln() + 1.0;
+            tf * idf
+        })
+    }
+
+    /// Same as `search`, but articles are scored by `score` instead of the raw count, and ordered
+    /// by decreasing score and then by title. A score that isn't a number ties with every other.
+    pub fn search_by<F>(&self, term: &str, score: F) -> Vec<(Article, f64)>
+    where
+        F: Fn(&Article, &ScoringContext) -> f64,
+    {
+        let term = query_word(term, self.query_config());
+        let mut matches = Vec::new();
+        for (article_key, (urls, words)) in &self.index {
+            if let (Some(&count), Some(url)) = (words.counts.get(&term), urls.iter().min()) {
+                if count > 0 {
+                    let length = words.counts.values().sum::<u32>();
+                    let article = Article::new(url.clone(), article_key.title.clone());
+                    matches.push((article, count, length));
+                }
+            }
+        }
+
+        let document_frequency = self.word_articles.get(&term).copied().unwrap_or(0);
+        let mut matches = matches
+            .into_iter()
+            .map(|(article, count, length)| {
+                let cx = ScoringContext {
+                    term_frequency: count,
+                    document_frequency,
+                    total_documents: self.index.len(),
+                    article_length: length,
+                };
+                let score = score(&article, &cx);
+                (article, score)
+            })
+            .collect::<Vec<_>>();
+        matches.sort_by(|(art1, sc1), (art2, sc2)| {
+            sc2.partial_cmp(sc1)
+                .unwrap_or(Ordering::Equal)
+                .then(art1.cmp_title(art2))
+        });
+        matches
+    }
+
+    /// Find all articles matching `terms` according to `mode`. Articles are scored by the summed
+    /// counts of the terms they contain and ordered like `search`. Terms are normalized as in
+    /// `search`, and duplicates are only counted once; no terms match nothing.
+    pub fn query(&self, terms: &[&str], mode: QueryMode) -> Vec<(Article, u32)> {
+        let config = self.query_config();
+        let mut terms = terms
+            .iter()
+            .map(|t| query_word(t, config))
+            .collect::<Vec<_>>();
+        terms.sort();
+        terms.dedup();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let mut matches = Vec::new();
+        for (article_key, (urls, words)) in &self.index {
+            let url = match urls.iter().min() {
+                Some(url) => url,
+                None => continue,
+            };
+            let counts = terms
+                .iter()
+                .map(|t| words.counts.get(t).copied().unwrap_or(0))
+                .collect::<Vec<_>>();
+            let matched = match mode {
+                QueryMode::All => counts.iter().all(|&c| c > 0),
+                QueryMode::Any => counts.iter().any(|&c| c > 0),
+            };
+            if matched {
+                let article = Article::new(url.clone(), article_key.title.clone());
+                matches.push((article, counts.iter().sum::<u32>()));
+            }
+        }
+        matches.sort_by(|(art1, ct1), (art2, ct2)| ct2.cmp(ct1).then(art1.cmp_title(art2)));
+        matches
+    }
+
+    /// Remove `url` from the index, returning whether it was there. An article is removed along
+    /// with its words once its last URL is gone; an article still reachable through another URL
+    /// keeps its words, since those are shared by all of its URLs.
+    pub fn remove(&mut self, url: &str) -> bool {
+        let key = match self
+            .index
+            .iter()
+            .find(|(_, (urls, _))| urls.iter().any(|u| u == url))
+        {
+            Some((key, _)) => key.clone(),
+            None => return false,
+        };
+        let urls = &mut self.index.get_mut(&key).unwrap().0;
+        urls.retain(|u| u != url);
+        if urls.is_empty() {
+            self.forget_site_words(&key);
+            self.index.remove(&key);
+            self.positions.remove(&key);
+            self.published.remove(&key);
+            self.source_feeds.remove(&key);
+        }
+        true
+    }
+
+    /// All articles published at or after `t`, from the newest to the oldest. Articles without a
+    /// publication date are left out. Articles are reported under their alphabetically-earliest
+    /// URL.
+    pub fn articles_since(&self, t: DateTime<Utc>) -> Vec<Article> {
+        let mut articles = self
+            .published
+            .iter()
+            .filter(|(_, &published)| published >= t)
+            .filter_map(|(article_key, &published)| {
+                let url = self.index.get(article_key)?.0.iter().min()?;
+                let title = article_key.title.clone();
+                Some(Article::with_published(url.clone(), title, Some(published)))
+            })
+            .collect::<Vec<_>>();
+        articles.sort_by(|art1, art2| {
+            art2.published
+                .cmp(&art1.published)
+                .then(art1.cmp_title(art2))
+        });
+        articles
+    }
+
+    /// Number of articles in the index.
+    pub fn article_count(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Number of distinct words appearing in at least one article.
+    pub fn word_count(&self) -> usize {
+        self.word_articles.len()
+    }
+
+    /// The articles of the index, each along with its site, in no particular order. An article
+    /// listed under several URLs is returned once, with the smallest one, as `search` does. The
+    /// articles are built as they are returned, so they are handed out by value.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Article)> + '_ {
+        self.index
+            .iter()
+            .filter_map(move |(article_key, (urls, _))| {
+                let url = urls.iter().min()?.clone();
+                let title = article_key.title.clone();
+                let published = self.published.get(article_key).copied();
+                let article = Article::with_published(url, title, published);
+                Some((article_key.site.as_str(), article))
+            })
+    }
+
+    /// How often each word appears in the article at `url` (any of its URLs), or None if no
+    /// article has that URL.
+    pub fn words(&self, url: &str) -> Option<&BTreeMap<String, u32>> {
+        self.index
+            .values()
+            .find(|(urls, _)| urls.iter().any(|u| u == url))
+            .map(|(_, words)| &words.counts)
+    }
+
+    /// Number of articles from `site`.
+    pub fn articles_for_site(&self, site: &str) -> usize {
+        self.site_articles.get(site).copied().unwrap_or(0)
+    }
+
+    /// Compare this index to an earlier one, `previous`, by URL: which articles were added,
+    /// removed, or changed their words since.
+    pub fn diff(&self, previous: &ArticleIndex) -> IndexDiff {
+        fn words_by_url(index: &ArticleIndex) -> HashMap<&str, &Bag<String>> {
+            let mut words_by_url = HashMap::new();
+            for (urls, words) in index.index.values() {
+                for url in urls {
+                    words_by_url.insert(url.as_str(), words);
+                }
+            }
+            words_by_url
+        }
+        // intersecting bags leaves words with count zero behind, which don't count as a change
+        fn same_words(words1: &Bag<String>, words2: &Bag<String>) -> bool {
+            let nonzero1 = words1.counts.iter().filter(|(_, &count)| count > 0);
+            let nonzero2 = words2.counts.iter().filter(|(_, &count)| count > 0);
+            nonzero1.eq(nonzero2)
+        }
+
+        let current = words_by_url(self);
+        let previous = words_by_url(previous);
+        let mut diff = IndexDiff::default();
+        for (&url, words) in &current {
+            match previous.get(url) {
+                None => diff.added.push(url.to_string()),
+                Some(old_words) if !same_words(words, old_words) => {
+                    diff.modified.push(url.to_string())
+                }
+                Some(_) => {}
+            }
+        }
+        for &url in previous.keys() {
+            if !current.contains_key(url) {
+                diff.removed.push(url.to_string());
+            }
+        }
+        diff.added.sort();
+        diff.removed.sort();
+        diff.modified.sort();
+        diff
+    }
+
+    /// All articles of the index, sorted by URL, with their words sorted by decreasing count and
+    /// then alphabetically. Unlike iterating over `index`, this order doesn't depend on how (or
+    /// how concurrently) the index was built, so two snapshots can be compared directly.
+    pub fn snapshot(&self) -> Vec<ArticleSummary> {
+        let mut articles = self
+            .index
+            .iter()
+            .map(|(article_key, (urls, words))| {
+                let mut urls = urls.clone();
+                urls.sort();
+                // intersecting bags leaves words with count zero behind
+                let mut words = words
+                    .counts
+                    .iter()
+                    .filter(|(_, &count)| count > 0)
+                    .map(|(word, &count)| (word.clone(), count))
+                    .collect::<Vec<_>>();
+                words.sort_by(|(w1, c1), (w2, c2)| c2.cmp(c1).then(w1.cmp(w2)));
+                ArticleSummary {
+                    urls,
+                    site: article_key.site.clone(),
+                    title: article_key.title.clone(),
+                    words,
+                }
+            })
+            .collect::<Vec<_>>();
+        articles.sort_by(|a1, a2| {
+            a1.urls
+                .cmp(&a2.urls)
+                .then_with(|| a1.site.cmp(&a2.site))
+                .then_with(|| a1.title.cmp(&a2.title))
+        });
+        articles
+    }
+
+    /// The `n` most frequent words, with their counts summed over all articles, ordered by
+    /// decreasing count and then alphabetically. Returns all words if there are fewer than `n`.
+    /// For an index built with `IndexConfig::stem`, these are stems rather than words.
+    pub fn top_words(&self, n: usize) -> Vec<(String, usize)> {
+        let mut totals: HashMap<&str, usize> = HashMap::new();
+        for (_, words) in self.index.values() {
+            // intersecting bags leaves words with count zero behind
+            for (word, &count) in words.counts.iter().filter(|(_, &count)| count > 0) {
+                *totals.entry(word).or_default() += count as usize;
+            }
+        }
+        let mut totals = totals
+            .into_iter()
+            .map(|(word, count)| (word.to_string(), count))
+            .collect::<Vec<_>>();
+        totals.sort_by(|(w1, c1), (w2, c2)| c2.cmp(c1).then(w1.cmp(w2)));
+        totals.truncate(n);
+        totals
+    }
+}
+
+/// Number of shards of a `ShardedArticleIndex`.
+const INDEX_SHARDS: usize = 16;
+
+/// An ArticleIndex split into shards by site and title, each behind its own lock, so that threads
+/// adding different articles rarely wait for each other.
+pub struct ShardedArticleIndex {
+    shards: Vec<Mutex<ArticleIndex>>,
+}
+
+impl Default for ShardedArticleIndex {
+    fn default() -> Self {
+        ShardedArticleIndex::new()
+    }
+}
+
+impl ShardedArticleIndex {
+    pub fn new() -> Self {
+        ShardedArticleIndex {
+            shards: (0..INDEX_SHARDS)
+                .map(|_| Mutex::new(ArticleIndex::new()))
+                .collect(),
+        }
+    }
+
+    /// The shard holding articles with this site and title.
+    fn shard(&self, site: &str, title: &str) -> &Mutex<ArticleIndex> {
+        let mut hasher = DefaultHasher::new();
+        site.hash(&mut hasher);
+        title.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Same as `ArticleIndex::add`, but only locks the shard the article belongs to.
+    pub fn add(&self, site: String, title: String, url: String, words: Bag<String>) {
+        let shard = self.shard(&site, &title);
+        shard.lock().unwrap().add(site, title, url, words);
+    }
+
+    /// Same as `ArticleIndex::add_article`, but only locks the shard the article belongs to.
+    pub fn add_article(&self, site: String, title: String, url: String, words: ArticleWords) {
+        let shard = self.shard(&site, &title);
+        shard.lock().unwrap().add_article(site, title, url, words);
+    }
+
+    /// Move all articles added so far into `index`, as if each had been added with
+    /// `ArticleIndex::add`, leaving the shards empty.
+    pub fn drain_into(&self, index: &mut ArticleIndex) {
+        for shard in &self.shards {
+            let shard = std::mem::replace(&mut *shard.lock().unwrap(), ArticleIndex::new());
+            index.absorb(shard);
+        }
+    }
+}
+
+impl RssIndex {
+    pub fn new() -> Self {
+        RssIndex {
+            index: HashMap::new(),
+        }
+    }
+
+    /// Add a article, word, and count to the RssIndex. The word might not be in the index.
+    pub fn add(&mut self, article: Article, word: String, freq: u32) {
+        *self
+            .index
+            .entry(word)
+            .or_default()
+            .entry(article)
+            .or_insert(0) += freq;
+    }
+}
+
+/// Turn an ArticleIndex into an RssIndex.
+///
+/// If an article has multiple URLs in the ArticleIndex, sort the URLs and file the words under the
+/// alphabetically-earliest URL with RssIndex::add here. If an entry in ArticleIndex doesn't have a
+/// URL for some reason, just skip it. This function should not panic.
+pub fn build_index(article_index: &mut ArticleIndex, rss_index: &mut RssIndex) {
+    info!("Building index...");
+    for (article_key, article_entry) in &mut article_index.index {
+        article_entry.0.sort();
+        if let Some(url) = article_entry.0.pop() {
+            for (word, count) in &article_entry.1.counts {
+                let article = Article::new(url.clone(), article_key.title.clone());
+                rss_index.add(article, word.to_string(), *count)
+            }
+        }
+    }
+}
+
+/// Split `text` with `config.tokenizer`, convert each piece to lowercase, and add every piece
+/// that isn't one of the configured stop words or shorter than `config.min_word_len` to `words`.
+/// Pieces that are only numbers or only punctuation are skipped too, unless `config.keep_numbers`
+/// or `config.keep_punct` is set.
+pub fn add_words(text: &str, config: &IndexConfig, words: &mut Bag<String>) {
+    for word in tokenize(text, config) {
+        words.add(word);
+    }
+}
+
+/// The words `add_words` would add, in the order they appear in `text`.
+pub fn tokenize<'a>(text: &'a str, config: &'a IndexConfig) -> impl Iterator<Item = String> + 'a {
+    let stemmer = if config.stem {
+        Some(Stemmer::create(Algorithm::English))
+    } else {
+        None
+    };
+    config
+        .tokenizer
+        .split(text)
+        .filter(move |tok| is_token(tok, config) && tok.chars().count() >= config.min_word_len)
+        .map(|tok| tok.to_lowercase())
+        .filter(move |word| !config.stop_words.contains(word))
+        .map(move |word| match &stemmer {
+            Some(stemmer) => stemmer.stem(&word).into_owned(),
+            None => word,
+        })
+}
+
+/// Whether a piece of text split off by the tokenizer is worth indexing: whitespace never is,
+/// numbers (pieces with digits but no letters, like "2024" or "3.14") and punctuation (pieces
+/// with neither) only if the config says so.
+fn is_token(tok: &str, config: &IndexConfig) -> bool {
+    if tok.chars().all(char::is_whitespace) {
+        false
+    } else if !tok.chars().any(char::is_alphanumeric) {
+        config.keep_punct
+    } else if !tok.chars().any(char::is_alphabetic) {
+        config.keep_numbers
+    } else {
+        true
+    }
+}
+
+/// Add the words of an article's `title` to `words`, each counted `config.title_weight` times.
+/// Title words get no positions: the title isn't part of the running text of the article, so
+/// `ArticleIndex::search_phrase` only matches phrases in the text itself.
+pub fn add_title_words(title: &str, config: &IndexConfig, words: &mut Bag<String>) {
+    // words that only appear in the title would otherwise be left in with a count of 0
+    if config.title_weight == 0 {
+        return;
+    }
+    for word in tokenize(title, config) {
+        *words.counts.entry(word).or_insert(0) += config.title_weight;
+    }
+}
+
+/// Normalize a query word the same way words of articles are: lowercased, and stemmed if
+/// `config.stem` is set. `ArticleIndex::search` and friends do this with the settings of the index.
+pub fn query_word(word: &str, config: &IndexConfig) -> String {
+    let word = word.to_lowercase();
+    if config.stem {
+        Stemmer::create(Algorithm::English).stem(&word).into_owned()
+    } else {
+        word
+    }
+}
+
+/// Split a query into words with `config.tokenizer`, the same way the text of articles is, and
+/// normalize each of them with `query_word`, e.g. for `ArticleIndex::search_phrase`. Pieces that
+/// would never be indexed, like punctuation (see `is_token`), are left out; stop words are kept.
+pub fn query_words(query: &str, config: &IndexConfig) -> Vec<String> {
+    config
+        .tokenizer
+        .split(query)
+        .filter(|tok| is_token(tok, config))
+        .map(|tok| query_word(&tok, config))
+        .collect()
+}
+
+/// Elements whose text isn't shown on the page.
+const HIDDEN_ELEMENTS: &[&str] = &["script", "style", "noscript", "template"];
+
+/// Use scraper to parse the document, select the "body" tag, and add the words of its visible text
+/// to `words` with `add_words`. Tags and attributes are not text, and the contents of scripts and
+/// style sheets are skipped.
+pub fn add_html_words(
+    content: &str,
+    config: &IndexConfig,
+    words: &mut Bag<String>,
+) -> RssIndexResult<()> {
+    for_each_visible_text(content, |text| add_words(text, config, words))
+}
+
+/// Same as `add_html_words`, but returns the words, along with their positions if
+/// `config.record_positions` is set.
+pub fn html_words(content: &str, config: &IndexConfig) -> RssIndexResult<ArticleWords> {
+    words_of(config, |f| for_each_visible_text(content, f))
+}
+
+/// Same as `html_words`, but for a markdown document: only the text is indexed, not the
+/// formatting (heading markers, emphasis, link targets, ...).
+pub fn markdown_words(content: &str, config: &IndexConfig) -> RssIndexResult<ArticleWords> {
+    words_of(config, |f| {
+        for event in MdParser::new(content) {
+            if let MdEvent::Text(text) | MdEvent::Code(text) = event {
+                f(&text);
+            }
+        }
+        Result::Ok(())
+    })
+}
+
+/// Same as `html_words`, but for a plain text document, all of which is indexed.
+pub fn text_words(content: &str, config: &IndexConfig) -> RssIndexResult<ArticleWords> {
+    words_of(config, |f| {
+        f(content);
+        Result::Ok(())
+    })
+}
+
+/// The words of the document whose text `for_each_text` passes, piece by piece, to the function
+/// it is given, along with their positions if `config.record_positions` is set.
+fn words_of<F>(config: &IndexConfig, for_each_text: F) -> RssIndexResult<ArticleWords>
+where
+    F: FnOnce(&mut dyn FnMut(&str)) -> RssIndexResult<()>,
+{
+    let mut words = ArticleWords::default();
+    if !config.record_positions {
+        for_each_text(&mut |text| add_words(text, config, &mut words.counts))?;
+        return Result::Ok(words);
+    }
+
+    let mut positions = Positions::new();
+    let mut pos = 0;
+    for_each_text(&mut |text| {
+        for word in tokenize(text, config) {
+            positions.entry(word.clone()).or_default().push(pos);
+            words.counts.add(word);
+            pos += 1;
+        }
+    })?;
+    words.positions = Some(positions);
+    Result::Ok(words)
+}
+
+/// The kinds of documents articles can link to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DocumentFormat {
+    Html,
+    Markdown,
+    PlainText,
+}
+
+impl DocumentFormat {
+    /// Guess the format of the document at `url` from its Content-Type, if known. A markdown
+    /// file is often served as text/plain, so an .md extension wins over it; anything that isn't
+    /// recognizably markdown or plain text is taken to be HTML.
+    pub fn detect(url: &str, content_type: Option<&str>) -> Self {
+        let mime = content_type
+            .and_then(|ct| ct.split(';').next())
+            .map(|mime| mime.trim().to_ascii_lowercase());
+        let path = url::Url::parse(url)
+            .map(|url| url.path().to_ascii_lowercase())
+            .unwrap_or_default();
+        match mime.as_deref() {
+            Some("text/markdown") | Some("text/x-markdown") => DocumentFormat::Markdown,
+            _ if path.ends_with(".md") || path.ends_with(".markdown") => DocumentFormat::Markdown,
+            Some("text/plain") => DocumentFormat::PlainText,
+            None if path.ends_with(".txt") => DocumentFormat::PlainText,
+            _ => DocumentFormat::Html,
+        }
+    }
+}
+
+/// The words of `document`, split with `html_words`, `markdown_words` or `text_words` according
+/// to its format.
+pub fn document_words(document: &Document, config: &IndexConfig) -> RssIndexResult<ArticleWords> {
+    match document.format() {
+        DocumentFormat::Html => html_words(&document.text, config),
+        DocumentFormat::Markdown => markdown_words(&document.text, config),
+        DocumentFormat::PlainText => text_words(&document.text, config),
+    }
+}
+
+/// Call `f` with each piece of visible text in the body of the document, in document order.
+fn for_each_visible_text<F: FnMut(&str)>(content: &str, mut f: F) -> RssIndexResult<()> {
+    let parsed = Html::parse_document(content);
+    let body_selector = Selector::parse("body").map_err(|_| RssIndexError::ScraperError)?;
+    let body = parsed
+        .select(&body_selector)
+        .next()
+        .ok_or(RssIndexError::ScraperError)?;
+
+    for node in body.descendants() {
+        if let Some(text) = node.value().as_text() {
+            let hidden = node.ancestors().any(|ancestor| {
+                ancestor
+                    .value()
+                    .as_element()
+                    .is_some_and(|e| HIDDEN_ELEMENTS.contains(&e.name()))
+            });
+            if !hidden {
+                f(text);
+            }
+        }
+    }
+    Result::Ok(())
+}
+
+/// Build the HTTP client shared by all fetches of one run. It asks for gzip or brotli compressed
+/// responses and transparently decompresses them, so feeds are always parsed from plain bytes.
+pub fn build_client(config: &IndexConfig) -> RssIndexResult<Client> {
+    let client = Client::builder()
+        .timeout(config.timeout)
+        .user_agent(&config.user_agent)
+        .gzip(true)
+        .brotli(true)
+        .redirect(redirect_policy(config))
+        .build()?;
+    Result::Ok(client)
+}
+
+/// Follow at most `config.max_redirects` redirects, and never one back to a URL already visited.
+pub fn redirect_policy(config: &IndexConfig) -> redirect::Policy {
+    let max_redirects = config.max_redirects;
+    redirect::Policy::custom(move |attempt| {
+        // the URLs visited so far include the one first requested
+        if attempt.previous().len() > max_redirects {
+            attempt.error("too many redirects")
+        } else if attempt.previous().contains(attempt.url()) {
+            attempt.error("redirect loop")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Turn a reqwest timeout into `RssIndexError::Timeout`, an error status into
+/// `RssIndexError::HttpStatus`, a redirect refused by `redirect_policy` into
+/// `RssIndexError::TooManyRedirects`, and anything else into `RssIndexError::RequestError`. All of
+/// them keep the URL of the request, and all but `TooManyRedirects` keep `e` as their source.
+pub fn fetch_error(e: reqwest::Error) -> Box<dyn Error> {
+    let url = e.url().map(|url| url.to_string()).unwrap_or_default();
+    if e.is_timeout() {
+        Box::new(RssIndexError::Timeout { url, source: e })
+    } else if let Some(status) = e.status() {
+        let status = status.as_u16();
+        Box::new(RssIndexError::HttpStatus {
+            url,
+            status,
+            source: e,
+        })
+    } else if e.is_redirect() {
+        Box::new(RssIndexError::TooManyRedirects { url })
+    } else {
+        Box::new(RssIndexError::RequestError { url, source: e })
+    }
+}
+
+/// Parse `url`, keeping it in the error if it is malformed.
+pub fn parse_url(url: &str) -> Result<url::Url, RssIndexError> {
+    url::Url::parse(url).map_err(|source| RssIndexError::UrlParseError {
+        url: url.to_string(),
+        source,
+    })
+}
+
+/// The form of `url` used to tell whether two URLs are the same feed or article: the host is
+/// lowercased, a trailing slash is removed from the path, and the query parameters listed in
+/// `config.tracking_params` are dropped. A URL that can't be parsed is returned unchanged.
+pub fn canonical_url(url: &str, config: &IndexConfig) -> String {
+    let mut parsed = match url::Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return url.to_string(),
+    };
+    // parsing already lowercases the host of http(s) URLs
+    let path = parsed.path();
+    if path.len() > 1 && path.ends_with('/') {
+        let path = path.trim_end_matches('/').to_string();
+        parsed.set_path(&path);
+    }
+    if parsed.query().is_some() {
+        let is_tracking = |name: &str| {
+            config
+                .tracking_params
+                .iter()
+                .any(|param| match param.strip_suffix('*') {
+                    Some(prefix) => name.starts_with(prefix),
+                    None => name == param,
+                })
+        };
+        let query = parsed
+            .query_pairs()
+            .filter(|(name, _)| !is_tracking(name))
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect::<Vec<_>>();
+        if query.is_empty() {
+            parsed.set_query(None);
+        } else {
+            parsed.query_pairs_mut().clear().extend_pairs(query);
+        }
+    }
+    parsed.to_string()
+}
+
+/// The key under which the article of a feed `item` linking to `url`, listed in the feed at
+/// `feed_url`, is remembered as seen: with `config.dedup_by_guid`, the canonical form (see
+/// `canonical_url`) of the item's guid if it has one, and otherwise that of `url`. A guid that is
+/// a permalink thus matches the same link of an item without a guid. A guid that isn't an
+/// absolute URL (like "42") is only unique within its feed, so it is prefixed with the canonical
+/// form of `feed_url` and never matches an item of another feed. With `DedupScope::PerFeed`,
+/// the key is prefixed with the canonical form of `feed_url`, so that only items of the same
+/// feed can match.
+pub fn dedup_key(item: &FeedItem, url: &str, feed_url: &str, config: &IndexConfig) -> String {
+    let key = match item.guid() {
+        Some(guid) if config.dedup_by_guid => match url::Url::parse(guid) {
+            Ok(_) => canonical_url(guid, config),
+            Err(_) => format!("{} {}", canonical_url(feed_url, config), guid),
+        },
+        _ => canonical_url(url, config),
+    };
+    match config.dedup_scope {
+        DedupScope::Global => key,
+        // a parsed URL never contains a space, so no two feeds' keys can be confused
+        DedupScope::PerFeed => format!("{} {}", canonical_url(feed_url, config), key),
+    }
+}
+
+/// Whether a failed request is worth retrying: connection errors, timeouts, and 5xx responses
+/// are, anything else (like a 4xx response) isn't.
+fn is_transient(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout() || e.status().is_some_and(|s| s.is_server_error())
+}
+
+/// Send a GET request for `url` with `headers` and those configured for `url` (see
+/// `IndexConfig::headers_for`), retrying transient failures up to `config.max_retries` times with
+/// exponential backoff. Once retries are exhausted, the last error is returned. Any response that
+/// isn't a success is an error, so that error pages are never indexed. Authorization headers are
+/// dropped when a redirect leads to another host.
+fn get(
+    client: &Client,
+    url: &str,
+    config: &IndexConfig,
+    headers: &HeaderMap,
+) -> RssIndexResult<Response> {
+    let mut headers = headers.clone();
+    headers.extend(config.headers_for(url));
+    let mut delay = config.base_delay;
+    let mut retries = 0;
+    loop {
+        let result = client
+            .get(url)
+            .headers(headers.clone())
+            .send()
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(response) => return Result::Ok(response),
+            Err(e) if retries < config.max_retries && is_transient(&e) => {
+                warn!("Retrying {} in {:?}: {}", url, delay, e);
+                thread::sleep(delay);
+                delay *= 2;
+                retries += 1;
+            }
+            Err(e) => return Result::Err(fetch_error(e)),
+        }
+    }
+}
+
+/// Read the body of `response`, which came from a request for `url`. A body larger than
+/// `config.max_body_bytes` fails with `RssIndexError::BodyTooLarge` as soon as that is known,
+/// either from its Content-Length or once one byte more than the limit has been read.
+fn read_body(response: Response, url: &str, config: &IndexConfig) -> RssIndexResult<Vec<u8>> {
+    let limit = match config.max_body_bytes {
+        Some(limit) => limit,
+        None => return Result::Ok(response.bytes().map_err(fetch_error)?.to_vec()),
+    };
+    let too_large = || RssIndexError::BodyTooLarge {
+        url: url.to_string(),
+        limit,
+    };
+    // a server may send more than it declares, so this only saves reading a body known to be
+    // too large
+    if response.content_length().unwrap_or(0) > limit as u64 {
+        return Result::Err(Box::new(too_large()));
+    }
+    let mut body = Vec::new();
+    response
+        .take(limit as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(RssIndexError::IoError)?;
+    if body.len() > limit {
+        return Result::Err(Box::new(too_large()));
+    }
+    Result::Ok(body)
+}
+
+/// Fetch the body of `url` as raw bytes.
+pub fn fetch_bytes(client: &Client, url: &str, config: &IndexConfig) -> RssIndexResult<Vec<u8>> {
+    let response = get(client, url, config, &HeaderMap::new())?;
+    read_body(response, url, config)
+}
+
+/// Check that a response for the feed at `url` with the given Content-Type header is a feed
+/// rather than, say, the HTML page of a captive portal. The type has to mention `xml`, `rss` or
+/// `atom`; a response without a Content-Type is given the benefit of the doubt.
+pub fn check_feed_type(url: &str, content_type: Option<&str>) -> RssIndexResult<()> {
+    let content_type = match content_type {
+        Some(content_type) => content_type,
+        None => return Result::Ok(()),
+    };
+    let lower = content_type.to_ascii_lowercase();
+    if lower.contains("xml") || lower.contains("rss") || lower.contains("atom") {
+        Result::Ok(())
+    } else {
+        Result::Err(Box::new(RssIndexError::NotAFeed {
+            url: url.to_string(),
+            content_type: content_type.to_string(),
+        }))
+    }
+}
+
+/// The Content-Type header of `response`, if any.
+fn content_type(response: &Response) -> Option<&str> {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+}
+
+/// Same as `fetch_bytes`, but fails with `RssIndexError::NotAFeed` if the response isn't a feed
+/// according to `check_feed_type`.
+pub fn fetch_feed(client: &Client, url: &str, config: &IndexConfig) -> RssIndexResult<Vec<u8>> {
+    let response = get(client, url, config, &HeaderMap::new())?;
+    check_feed_type(url, content_type(&response))?;
+    read_body(response, url, config)
+}
+
+/// A document fetched as text.
+#[derive(Clone, Debug)]
+pub struct Document {
+    /// The URL the document was fetched from, after following redirects.
+    pub url: String,
+    pub text: String,
+    /// The Content-Type it was served with, if known.
+    pub content_type: Option<String>,
+}
+
+impl Document {
+    /// The format of the document, see `DocumentFormat::detect`.
+    pub fn format(&self) -> DocumentFormat {
+        DocumentFormat::detect(&self.url, self.content_type.as_deref())
+    }
+}
+
+/// Whether `document` is a feed rather than an article: either it was served as one (with an RSS
+/// or Atom Content-Type), or it parses as one (see `looks_like_feed`).
+pub fn is_feed_document(document: &Document) -> bool {
+    let served_as_feed = document
+        .content_type
+        .as_deref()
+        .is_some_and(|content_type| {
+            let lower = content_type.to_ascii_lowercase();
+            lower.contains("rss") || lower.contains("atom")
+        });
+    served_as_feed || looks_like_feed(&document.text)
+}
+
+/// Fetch the body of `url` as text, decoded with `decode_html`.
+pub fn fetch_text(client: &Client, url: &str, config: &IndexConfig) -> RssIndexResult<String> {
+    Result::Ok(fetch_document(client, url, config)?.text)
+}
+
+/// Same as `fetch_text`, but also returns the URL the text was fetched from after following
+/// redirects, and the Content-Type it was served with.
+pub fn fetch_document(
+    client: &Client,
+    url: &str,
+    config: &IndexConfig,
+) -> RssIndexResult<Document> {
+    let response = get(client, url, config, &HeaderMap::new())?;
+    let final_url = response.url().to_string();
+    let content_type = content_type(&response).map(String::from);
+    let bytes = read_body(response, url, config)?;
+    Result::Ok(Document {
+        url: final_url,
+        text: decode_html(&bytes, content_type.as_deref()),
+        content_type,
+    })
+}
+
+/// How far into a document to look for a `<meta>` tag declaring its charset.
+const META_CHARSET_WINDOW: usize = 1024;
+
+/// Decode an HTML document to UTF-8. The charset is taken from `content_type` (the value of a
+/// Content-Type header), or else from a `<meta charset>` or `<meta http-equiv="Content-Type">` tag
+/// near the start of the document, and defaults to UTF-8. A byte order mark overrides both.
+/// Malformed sequences are replaced rather than failing the whole document.
+pub fn decode_html(bytes: &[u8], content_type: Option<&str>) -> String {
+    let encoding = content_type
+        .and_then(charset_param)
+        .or_else(|| meta_charset(bytes))
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .unwrap_or(UTF_8);
+    let (text, _, _) = encoding.decode(bytes);
+    text.into_owned()
+}
+
+/// The value of the `charset=` parameter in `text`, if any, without quotes.
+fn charset_param(text: &str) -> Option<String> {
+    let text = text.to_ascii_lowercase();
+    let start = text.find("charset=")? + "charset=".len();
+    let value = text[start..]
+        .trim_start_matches(&['"', '\''][..])
+        .split(|c: char| !(c.is_ascii_alphanumeric() || "-_.:".contains(c)))
+        .next()?;
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// The charset declared by a `<meta>` tag near the start of an HTML document, if any.
+fn meta_charset(bytes: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(META_CHARSET_WINDOW)]);
+    let head = head.to_ascii_lowercase();
+    head.match_indices("<meta").find_map(|(start, _)| {
+        let tag = &head[start..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        charset_param(tag)
+    })
+}
+
+/// Same as `fetch_feed`, but sends the validators cached for `url` (`If-None-Match` and
+/// `If-Modified-Since`), and returns None if the server answers that the feed hasn't changed.
+/// The validators of a changed feed replace those in the cache.
+pub fn fetch_if_modified(
+    client: &Client,
+    url: &str,
+    config: &IndexConfig,
+    cache: &Mutex<FeedCache>,
+) -> RssIndexResult<Option<Vec<u8>>> {
+    let mut headers = HeaderMap::new();
+    if let Some(entry) = cache.lock().unwrap().entries.get(url) {
+        if let Some(etag) = entry
+            .etag
+            .as_deref()
+            .and_then(|v| HeaderValue::from_str(v).ok())
+        {
+            headers.insert(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(date) = entry
+            .last_modified
+            .as_deref()
+            .and_then(|v| HeaderValue::from_str(v).ok())
+        {
+            headers.insert(header::IF_MODIFIED_SINCE, date);
+        }
+    }
+
+    let response = get(client, url, config, &headers)?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Result::Ok(None);
+    }
+    check_feed_type(url, content_type(&response))?;
+    let validator = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from)
+    };
+    let entry = CacheEntry {
+        etag: validator(header::ETAG),
+        last_modified: validator(header::LAST_MODIFIED),
+    };
+    let contents = read_body(response, url, config)?;
+    cache.lock().unwrap().entries.insert(url.to_string(), entry);
+    Result::Ok(Some(contents))
+}
+
+/// Something that fetches the contents of feeds and articles. The crawl only ever goes through a
+/// `Fetcher`, so it can be replaced, e.g. by canned responses in tests, or by a fetcher that adds
+/// authentication or caching.
+pub trait Fetcher {
+    /// Fetch the raw contents of `url`.
+    fn fetch(&self, url: &str) -> RssIndexResult<Vec<u8>>;
+
+    /// Fetch the raw contents of the feed at `url`, failing with `RssIndexError::NotAFeed` if it
+    /// turns out not to be one. By default, the contents are fetched with `fetch`, and left for
+    /// the feed parser to reject.
+    fn fetch_feed(&self, url: &str) -> RssIndexResult<Vec<u8>> {
+        self.fetch(url)
+    }
+
+    /// Fetch `url` as text, along with the URL it was actually fetched from and its
+    /// Content-Type. By default, the contents are decoded with `decode_html` from whatever
+    /// charset they declare, `url` is kept as is, and the Content-Type is unknown.
+    fn fetch_document(&self, url: &str) -> RssIndexResult<Document> {
+        let contents = self.fetch(url)?;
+        Result::Ok(Document {
+            url: url.to_string(),
+            text: decode_html(&contents, None),
+            content_type: None,
+        })
+    }
+
+    /// Fetch `url` unless `cache` says it hasn't changed since it was last fetched, in which case
+    /// None is returned. By default, the cache is ignored and `url` is always fetched.
+    fn fetch_if_modified(
+        &self,
+        url: &str,
+        _cache: &Mutex<FeedCache>,
+    ) -> RssIndexResult<Option<Vec<u8>>> {
+        self.fetch(url).map(Some)
+    }
+}
+
+/// A fetcher shared by the worker threads of a crawl.
+pub type SharedFetcher = Arc<dyn Fetcher + Send + Sync>;
+
+/// A `Fetcher` over HTTP, with a client built by `build_client` and retries as configured. All
+/// requests of a crawl go through one client, so connections (and TLS sessions) to a host are
+/// kept alive and reused; clones share the client too. With `IndexConfig::respect_robots`,
+/// articles are checked against the robots.txt of their host first, which clones share as well.
+#[derive(Clone)]
+pub struct HttpFetcher {
+    client: Client,
+    config: IndexConfig,
+    #[cfg(feature = "robots")]
+    robots: Option<Arc<RobotsRules>>,
+}
+
+impl HttpFetcher {
+    pub fn new(config: &IndexConfig) -> RssIndexResult<Self> {
+        Result::Ok(HttpFetcher {
+            client: build_client(config)?,
+            config: config.clone(),
+            #[cfg(feature = "robots")]
+            robots: if config.respect_robots {
+                Some(Arc::new(RobotsRules::new()))
+            } else {
+                None
+            },
+        })
+    }
+}
+
+impl Fetcher for HttpFetcher {
+    fn fetch(&self, url: &str) -> RssIndexResult<Vec<u8>> {
+        fetch_bytes(&self.client, url, &self.config)
+    }
+
+    /// Checks the Content-Type of the response, see `check_feed_type`.
+    fn fetch_feed(&self, url: &str) -> RssIndexResult<Vec<u8>> {
+        fetch_feed(&self.client, url, &self.config)
+    }
+
+    /// Decodes the contents using the charset of the Content-Type header, and returns the URL
+    /// redirects led to.
+    fn fetch_document(&self, url: &str) -> RssIndexResult<Document> {
+        #[cfg(feature = "robots")]
+        if let Some(robots) = &self.robots {
+            robots.check(&self.client, url, &self.config)?;
+        }
+        fetch_document(&self.client, url, &self.config)
+    }
+
+    /// Sends a conditional request, see `fetch_if_modified`.
+    fn fetch_if_modified(
+        &self,
+        url: &str,
+        cache: &Mutex<FeedCache>,
+    ) -> RssIndexResult<Option<Vec<u8>>> {
+        fetch_if_modified(&self.client, url, &self.config, cache)
+    }
+}
+
+/// A `Fetcher` that remembers the last few documents fetched through `fetch_document`, so that an
+/// article listed in several feeds (or crawls) is downloaded just once. Documents are keyed by
+/// their `canonical_url`. Feeds are always fetched afresh.
+pub struct CachedFetcher {
+    inner: SharedFetcher,
+    documents: Mutex<LruCache<String, Document>>,
+    config: IndexConfig,
+}
+
+impl CachedFetcher {
+    /// Cache up to `capacity` documents fetched with `inner`. URLs are canonicalized according
+    /// to `config`.
+    pub fn new(inner: SharedFetcher, capacity: usize, config: &IndexConfig) -> Self {
+        CachedFetcher {
+            inner,
+            documents: Mutex::new(LruCache::new(capacity)),
+            config: config.clone(),
+        }
+    }
+}
+
+impl Fetcher for CachedFetcher {
+    fn fetch(&self, url: &str) -> RssIndexResult<Vec<u8>> {
+        self.inner.fetch(url)
+    }
+
+    fn fetch_feed(&self, url: &str) -> RssIndexResult<Vec<u8>> {
+        self.inner.fetch_feed(url)
+    }
+
+    fn fetch_document(&self, url: &str) -> RssIndexResult<Document> {
+        let key = canonical_url(url, &self.config);
+        if let Some(document) = self.documents.lock().unwrap().get(&key) {
+            debug!("Using cached document [{}]", url);
+            return Result::Ok(document.clone());
+        }
+        // don't hold the lock while fetching; two threads may fetch the same URL, which is fine
+        let document = self.inner.fetch_document(url)?;
+        self.documents.lock().unwrap().put(key, document.clone());
+        Result::Ok(document)
+    }
+
+    fn fetch_if_modified(
+        &self,
+        url: &str,
+        cache: &Mutex<FeedCache>,
+    ) -> RssIndexResult<Option<Vec<u8>>> {
+        self.inner.fetch_if_modified(url, cache)
+    }
+}
+
+/// Use the fetcher to get the article URL, get the text of the document, split it into words
+/// with `document_words` (so HTML, markdown and plain text are each read as such), and return the
+/// bag of words appearing in the article, plus the words of the title added by
+/// `add_title_words`, cut down to `config.max_words_per_article` if set. If the article was
+/// redirected, the words also carry the URL it was fetched from. An article that turns out to be
+/// a feed fails with `RssIndexError::NotAnArticle`.
+pub fn process_article(
+    article: &Article,
+    fetcher: &dyn Fetcher,
+    config: &IndexConfig,
+) -> RssIndexResult<ArticleWords> {
+    let document = fetcher.fetch_document(&article.url)?;
+    if is_feed_document(&document) {
+        return Result::Err(Box::new(RssIndexError::NotAnArticle {
+            url: article.url.clone(),
+        }));
+    }
+    let mut words = document_words(&document, config)?;
+    add_article_details(article, config, &mut words);
+    if document.url != article.url {
+        words.url = Some(document.url);
+    }
+    Result::Ok(words)
+}
+
+/// Same as `process_article`, but the words are taken from the `summary` of the article in its
+/// feed item (read as HTML) instead of the article itself, which isn't fetched. An article
+/// without a summary only gets the words of its title.
+pub fn process_summary(
+    article: &Article,
+    summary: Option<&str>,
+    config: &IndexConfig,
+) -> RssIndexResult<ArticleWords> {
+    let mut words = html_words(summary.unwrap_or_default(), config)?;
+    add_article_details(article, config, &mut words);
+    Result::Ok(words)
+}
+
+/// Add the words of the title of `article` (see `add_title_words`) and its publication date to
+/// the words of its text, and cut them down to `config.max_words_per_article` if set.
+pub fn add_article_details(article: &Article, config: &IndexConfig, words: &mut ArticleWords) {
+    add_title_words(&article.title, config, &mut words.counts);
+    if let Some(max_words) = config.max_words_per_article {
+        words.keep_most_frequent(max_words);
+    }
+    words.published = article.published;
+}
+
+/// The words of `article`, listed in a feed item with the given `summary`, taken from what
+/// `config.index_source` says: either the article is fetched with `fetcher` (once `throttle`
+/// allows) and processed with `process_article`, or its summary is processed with
+/// `process_summary`.
+pub fn article_words(
+    article: &Article,
+    summary: Option<&str>,
+    fetcher: &dyn Fetcher,
+    throttle: &HostThrottle,
+    config: &IndexConfig,
+) -> RssIndexResult<ArticleWords> {
+    match config.index_source {
+        IndexSource::FullBody => {
+            throttle.wait(&article.url, config);
+            process_article(article, fetcher, config)
+        }
+        IndexSource::FeedSummary => process_summary(article, summary, config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    /// A bag of the given words, each counted once per time it is listed.
+    fn bag(words: &[&str]) -> Bag<String> {
+        let mut bag = Bag::new();
+        for word in words {
+            bag.add(word.to_string());
+        }
+        bag
+    }
+
+    fn titles<T>(matches: &[(Article, T)]) -> Vec<&str> {
+        matches
+            .iter()
+            .map(|(article, _)| article.title.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn intersecting_zeroes_the_words_after_the_last_word_of_the_other_bag() {
+        let mut words = bag(&["apple", "apple", "kiwi", "zebra", "zebra"]);
+        words.intersect_with(&bag(&["apple", "kiwi", "kiwi"]));
+        let expected = vec![("apple", 1), ("kiwi", 1), ("zebra", 0)];
+        let counts = words
+            .counts
+            .iter()
+            .map(|(word, &count)| (word.as_str(), count))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, expected);
+
+        let mut index = ArticleIndex::new();
+        let site = || "site.test".to_string();
+        index.add(site(), "A".into(), "u/1".into(), bag(&["apple", "zebra"]));
+        index.add(site(), "A".into(), "u/2".into(), bag(&["apple"]));
+        assert!(index.search("zebra").is_empty());
+        assert_eq!(index.search("apple").len(), 1);
+    }
+
+    #[test]
+    fn search_orders_by_count_and_then_by_title() {
+        let mut index = ArticleIndex::new();
+        let site = || "site.test".to_string();
+        index.add(site(), "B".into(), "u/b".into(), bag(&["rust", "rust"]));
+        index.add(site(), "A".into(), "u/a".into(), bag(&["rust", "rust"]));
+        index.add(site(), "C".into(), "u/c".into(), bag(&["rust", "go"]));
+        index.add(site(), "D".into(), "u/d".into(), bag(&["go"]));
+
+        let matches = index.search("Rust");
+        assert_eq!(titles(&matches), vec!["A", "B", "C"]);
+        assert_eq!(matches[0], (Article::new("u/a".into(), "A".into()), 2));
+        assert_eq!(matches[2].1, 1);
+        assert!(index.search("python").is_empty());
+    }
+
+    #[test]
+    fn search_reports_an_article_under_its_earliest_url() {
+        let mut index = ArticleIndex::new();
+        let site = || "site.test".to_string();
+        index.add(site(), "A".into(), "u/2".into(), bag(&["rust"]));
+        index.add(site(), "A".into(), "u/1".into(), bag(&["rust"]));
+        assert_eq!(index.search("rust")[0].0.url, "u/1");
+    }
+
+    /// An index of three articles of one site: "Both" with rust twice and async once, "Rust"
+    /// with rust once, and "Async" with async three times.
+    fn rust_async_index() -> ArticleIndex {
+        let mut index = ArticleIndex::new();
+        let site = || "site.test".to_string();
+        let both = bag(&["rust", "rust", "async"]);
+        index.add(site(), "Both".into(), "u/both".into(), both);
+        index.add(site(), "Rust".into(), "u/rust".into(), bag(&["rust"]));
+        index.add(site(), "Async".into(), "u/async".into(), bag(&["async"; 3]));
+        index
+    }
+
+    #[test]
+    fn query_all_needs_every_term_and_sums_their_counts() {
+        let index = rust_async_index();
+        let matches = index.query(&["rust", "ASYNC"], QueryMode::All);
+        assert_eq!(
+            matches,
+            vec![(Article::new("u/both".into(), "Both".into()), 3)]
+        );
+    }
+
+    #[test]
+    fn query_any_needs_one_term() {
+        let index = rust_async_index();
+        let matches = index.query(&["rust", "async"], QueryMode::Any);
+        let scores = matches
+            .iter()
+            .map(|(a, c)| (a.title.as_str(), *c))
+            .collect::<Vec<_>>();
+        assert_eq!(scores, vec![("Async", 3), ("Both", 3), ("Rust", 1)]);
+    }
+
+    #[test]
+    fn query_counts_duplicate_terms_once_and_matches_nothing_without_terms() {
+        let index = rust_async_index();
+        let once = index.query(&["rust"], QueryMode::Any);
+        assert_eq!(index.query(&["rust", "Rust", "rust"], QueryMode::Any), once);
+        assert!(index.query(&[], QueryMode::All).is_empty());
+        assert!(index.query(&[], QueryMode::Any).is_empty());
+    }
+
+    /// An index with an article of each kind: with positions, with a publication date and feed,
+    /// and under two URLs.
+    fn populated_index() -> ArticleIndex {
+        let mut index = rust_async_index();
+        let mut positions = Positions::new();
+        positions.insert("rust".to_string(), vec![0, 2]);
+        positions.insert("async".to_string(), vec![1]);
+        let words = ArticleWords {
+            counts: bag(&["rust", "async", "rust"]),
+            positions: Some(positions),
+            published: Some(Utc::now()),
+            feed: Some("http://site.test/feed".to_string()),
+            ..ArticleWords::default()
+        };
+        let site = || "other.test".to_string();
+        index.add_article(site(), "Full".into(), "u/full".into(), words);
+        index.add(site(), "Twice".into(), "u/twice/1".into(), bag(&["go"]));
+        index.add(site(), "Twice".into(), "u/twice/2".into(), bag(&["go"]));
+        index
+    }
+
+    #[test]
+    fn json_round_trip_gives_an_equal_index() {
+        let index = populated_index();
+        let mut json = Vec::new();
+        index.to_json_writer(&mut json).unwrap();
+        let read_back = ArticleIndex::from_json_reader(json.as_slice()).unwrap();
+        assert_eq!(read_back, index);
+        assert_eq!(read_back.search_in_site("other.test", "go").len(), 1);
+    }
+
+    #[test]
+    fn save_then_load_gives_an_equal_index() {
+        let dir = TempDir::new();
+        let path = dir.path("index.json");
+        let index = populated_index();
+        index.save(&path).unwrap();
+        assert_eq!(ArticleIndex::load(&path).unwrap(), index);
+        // saving again replaces the file
+        ArticleIndex::new().save(&path).unwrap();
+        assert_eq!(ArticleIndex::load(&path).unwrap(), ArticleIndex::new());
+    }
+
+    #[test]
+    fn loading_a_missing_or_corrupt_file_fails() {
+        let dir = TempDir::new();
+        let e = ArticleIndex::load(&dir.path("missing.json")).unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(RssIndexError::IoError(_))));
+        let corrupt = dir.file("corrupt.json", "{\"index\": [");
+        let e = ArticleIndex::load(Path::new(&corrupt)).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(RssIndexError::SerdeError(_))
+        ));
+    }
+
+    #[test]
+    fn merge_sums_the_counts_of_shared_articles() {
+        let site = || "site.test".to_string();
+        let mut index = ArticleIndex::new();
+        index.add(site(), "A".into(), "u/a".into(), bag(&["rust", "go"]));
+        index.add(site(), "B".into(), "u/b".into(), bag(&["go"]));
+        let mut other = ArticleIndex::new();
+        // the same article as "A", found under another title
+        other.add(
+            site(),
+            "A (updated)".into(),
+            "u/a".into(),
+            bag(&["rust", "rust"]),
+        );
+        other.add(site(), "C".into(), "u/c".into(), bag(&["zig"]));
+
+        index.merge(other);
+
+        assert_eq!(index.article_count(), 3);
+        let a = index.words("u/a").unwrap();
+        assert_eq!((a["rust"], a["go"]), (3, 1));
+        assert_eq!(index.search("zig")[0].0.url, "u/c");
+        assert_eq!(index.search_in_site("site.test", "rust")[0].1, 3);
+    }
+
+    /// The words `add_words` finds in `text`.
+    fn words_in(text: &str, config: &IndexConfig) -> Vec<(String, u32)> {
+        let mut words = Bag::new();
+        add_words(text, config, &mut words);
+        words.counts.into_iter().collect()
+    }
+
+    #[test]
+    fn words_are_lowercased_before_stop_words_are_dropped() {
+        let no_stop_words = IndexConfig::builder()
+            .stop_words(Vec::<String>::new())
+            .build();
+        let words = words_in("The the THE", &no_stop_words);
+        assert_eq!(words, vec![("the".to_string(), 3)]);
+        assert!(words_in("The the THE", &IndexConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn stop_words_can_be_configured() {
+        let config = IndexConfig::builder().stop_words(vec!["Rust"]).build();
+        let words = words_in("the rust book", &config);
+        let expected = vec![("book".to_string(), 1), ("the".to_string(), 1)];
+        assert_eq!(words, expected);
+    }
+
+    #[test]
+    fn only_visible_text_of_html_is_counted() {
+        let page = r#"<html><head><title>head</title><style>span { color: red }</style></head>
+            <body><div class="post"><span>visible</span> <a href="http://x.test/">link</a>
+            <script>var hidden = 1;</script></div></body></html>"#;
+        let mut words = Bag::new();
+        add_html_words(page, &IndexConfig::default(), &mut words).unwrap();
+        let words = words.counts.keys().map(String::as_str).collect::<Vec<_>>();
+        assert_eq!(words, vec!["link", "visible"]);
+    }
+
+    /// A fetcher over HTTP that fails at once instead of retrying.
+    fn no_retries(config: IndexConfigBuilder) -> HttpFetcher {
+        HttpFetcher::new(&config.max_retries(0).build()).unwrap()
+    }
+
+    #[test]
+    fn slow_server_times_out() {
+        let server = MockServer::start();
+        let slow = MockResponse::html("late").with_delay(Duration::from_secs(2));
+        server.route("/slow", slow);
+        let fetcher = no_retries(IndexConfig::builder().timeout(Duration::from_millis(200)));
+
+        let started = Instant::now();
+        let e = fetcher.fetch(&server.url("/slow")).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(RssIndexError::Timeout { .. })
+        ));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn server_errors_are_retried_until_the_article_comes_through() {
+        let server = MockServer::start();
+        let failing = MockResponse::status(503);
+        let responses = vec![failing.clone(), failing, MockResponse::html("finally")];
+        server.route_sequence("/flaky", responses);
+        let config = IndexConfig::builder()
+            .max_retries(3)
+            .base_delay(Duration::from_millis(1))
+            .build();
+        let fetcher = HttpFetcher::new(&config).unwrap();
+
+        let article = Article::new(server.url("/flaky"), "Flaky".into());
+        let words = process_article(&article, &fetcher, &config).unwrap();
+        assert_eq!(words.counts.counts["finally"], 1);
+        assert_eq!(server.hits("/flaky"), 3);
+    }
+
+    #[test]
+    fn client_errors_are_not_retried_and_the_last_error_is_kept() {
+        let server = MockServer::start();
+        server.route("/missing", MockResponse::status(404));
+        server.route("/down", MockResponse::status(503));
+        let fetcher = HttpFetcher::new(
+            &IndexConfig::builder()
+                .max_retries(2)
+                .base_delay(Duration::from_millis(1))
+                .build(),
+        )
+        .unwrap();
+
+        let e = fetcher.fetch(&server.url("/missing")).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(RssIndexError::HttpStatus { status: 404, .. })
+        ));
+        assert_eq!(server.hits("/missing"), 1);
+        let e = fetcher.fetch(&server.url("/down")).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(RssIndexError::HttpStatus { status: 503, .. })
+        ));
+        assert_eq!(server.hits("/down"), 3);
+    }
+
+    #[test]
+    fn the_last_error_keeps_its_url_and_source_once_retries_run_out() {
+        let server = MockServer::start();
+        server.route("/down", MockResponse::status(503));
+        let slow = MockResponse::html("late").with_delay(Duration::from_secs(2));
+        server.route("/slow", slow);
+        let fetcher = HttpFetcher::new(
+            &IndexConfig::builder()
+                .max_retries(1)
+                .base_delay(Duration::from_millis(1))
+                .timeout(Duration::from_millis(200))
+                .build(),
+        )
+        .unwrap();
+
+        let e = fetcher.fetch(&server.url("/down")).unwrap_err();
+        assert_eq!(server.hits("/down"), 2);
+        match e.downcast_ref() {
+            Some(RssIndexError::HttpStatus { url, status, .. }) => {
+                assert_eq!((url.as_str(), *status), (server.url("/down").as_str(), 503));
+            }
+            _ => panic!("unexpected error {}", e),
+        }
+        let source = e.source().expect("the reqwest error is kept");
+        assert!(source.downcast_ref::<reqwest::Error>().is_some());
+
+        let e = fetcher.fetch(&server.url("/slow")).unwrap_err();
+        match e.downcast_ref() {
+            Some(RssIndexError::Timeout { url, .. }) => assert_eq!(*url, server.url("/slow")),
+            _ => panic!("unexpected error {}", e),
+        }
+        let source = e.source().expect("the reqwest error is kept");
+        assert!(source
+            .downcast_ref::<reqwest::Error>()
+            .unwrap()
+            .is_timeout());
+    }
+
+    #[test]
+    fn requests_carry_the_configured_user_agent() {
+        let server = MockServer::start();
+        server.route("/page", MockResponse::html("hello"));
+        let custom = no_retries(IndexConfig::builder().user_agent("MyCrawler/2.0"));
+        custom.fetch(&server.url("/page")).unwrap();
+        no_retries(IndexConfig::builder())
+            .fetch(&server.url("/page"))
+            .unwrap();
+
+        let agents = server
+            .requests()
+            .into_iter()
+            .map(|r| r.headers["user-agent"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            agents,
+            vec!["MyCrawler/2.0".to_string(), USER_AGENT.to_string()]
+        );
+    }
+
+    /// An Atom feed of two entries; the first has its alternate link after another one.
+    const ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+        <feed xmlns="http://www.w3.org/2005/Atom">
+          <title>Atom</title><id>urn:feed</id><updated>2024-01-02T00:00:00Z</updated>
+          <entry>
+            <title>First</title><id>urn:first</id><updated>2024-01-01T00:00:00Z</updated>
+            <link rel="edit" href="http://atom.test/edit/1"/>
+            <link rel="alternate" href="http://atom.test/1"/>
+          </entry>
+          <entry>
+            <title>Second</title><id>urn:second</id><updated>2024-01-02T00:00:00Z</updated>
+            <link href="http://atom.test/2"/>
+          </entry>
+        </feed>"#;
+
+    fn links_and_titles(items: &[FeedItem]) -> Vec<(&str, &str)> {
+        items
+            .iter()
+            .map(|item| (item.link().unwrap(), item.title().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn atom_entries_are_read_with_their_alternate_links() {
+        let expected = vec![
+            ("http://atom.test/1", "First"),
+            ("http://atom.test/2", "Second"),
+        ];
+        let items = parse_feed(ATOM.as_bytes()).unwrap();
+        assert_eq!(links_and_titles(&items), expected);
+        assert_eq!(items[0].guid(), Some("urn:first"));
+        let streamed = stream_feed(ATOM.as_bytes()).collect::<RssIndexResult<Vec<_>>>();
+        assert_eq!(links_and_titles(&streamed.unwrap()), expected);
+    }
+
+    #[test]
+    fn errors_about_missing_fields_name_the_item() {
+        let untitled = FeedItem {
+            link: Some("http://feeds.test/untitled.xml".to_string()),
+            ..FeedItem::default()
+        };
+        let e = untitled.link_and_title().unwrap_err();
+        assert!(e.to_string().contains("http://feeds.test/untitled.xml"));
+
+        let unlinked = FeedItem {
+            title: Some("Nowhere Weekly".to_string()),
+            ..FeedItem::default()
+        };
+        let e = unlinked.link_and_title().unwrap_err();
+        assert!(e.to_string().contains("Nowhere Weekly"));
+
+        let e = parse_url("not a url").unwrap_err();
+        assert!(e.to_string().contains("not a url"));
+        assert!(e.source().is_some());
+    }
+
+    #[test]
+    fn concurrent_adds_to_a_sharded_index_are_all_kept() {
+        let sharded = Arc::new(ShardedArticleIndex::new());
+        let handles = (0..8)
+            .map(|t| {
+                let sharded = Arc::clone(&sharded);
+                thread::spawn(move || {
+                    for n in 0..250 {
+                        let site = format!("site{}.test", n % 5);
+                        let url = format!("http://{}/{}/{}", site, t, n);
+                        let title = format!("{} {}", t, n);
+                        sharded.add(site, title, url, bag(&["shared", "shared", "word"]));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let mut index = ArticleIndex::new();
+        sharded.drain_into(&mut index);
+        assert_eq!(index.article_count(), 2000);
+        let total = index.search("shared").iter().map(|(_, n)| n).sum::<u32>();
+        assert_eq!(total, 4000);
+        assert_eq!(index.articles_for_site("site0.test"), 400);
+    }
+
+    #[test]
+    fn top_words_rank_by_total_count_and_then_alphabetically() {
+        let mut index = rust_async_index();
+        let site = || "other.test".to_string();
+        index.add(
+            site(),
+            "Go".into(),
+            "u/go".into(),
+            bag(&["go", "go", "go", "zig"]),
+        );
+
+        let top = |n| index.top_words(n);
+        let expected = vec![("async".to_string(), 4), ("go".to_string(), 3)];
+        assert_eq!(top(2), expected);
+        // rust also has 3, and comes after go
+        assert_eq!(top(3)[2], ("rust".to_string(), 3));
+        assert_eq!(top(100).len(), 4);
+        assert!(top(0).is_empty());
+    }
+
+    #[test]
+    fn counts_follow_adds_removals_and_merges() {
+        // rust_async_index has Both, Rust and Async on site.test; populated_index adds Full and
+        // Twice on other.test
+        let mut index = populated_index();
+        assert_eq!(index.article_count(), 5);
+        assert_eq!(index.articles_for_site("site.test"), 3);
+        assert_eq!(index.articles_for_site("other.test"), 2);
+        assert_eq!(index.articles_for_site("nowhere.test"), 0);
+        assert_eq!(index.word_count(), 3);
+
+        // Twice keeps its words until its last URL is gone
+        assert!(index.remove("u/twice/1"));
+        assert_eq!(index.word_count(), 3);
+        assert!(index.remove("u/twice/2"));
+        assert_eq!(index.articles_for_site("other.test"), 1);
+        assert_eq!(index.word_count(), 2);
+
+        // adding Rust again without the word intersects it away, but Both still has it
+        let site = || "site.test".to_string();
+        index.add(site(), "Rust".into(), "u/rust/2".into(), bag(&["async"]));
+        assert_eq!(index.articles_for_site("site.test"), 3);
+        assert_eq!(index.word_count(), 2);
+        assert!(index.remove("u/both"));
+        assert!(index.remove("u/full"));
+        assert_eq!(index.word_count(), 1);
+
+        let mut other = ArticleIndex::new();
+        let site = || "new.test".to_string();
+        other.add(site(), "Zig".into(), "u/zig".into(), bag(&["zig"]));
+        index.merge(other.clone());
+        index.absorb(other);
+        assert_eq!(index.articles_for_site("new.test"), 1);
+        assert_eq!(index.word_count(), 2);
+
+        let mut json = Vec::new();
+        index.to_json_writer(&mut json).unwrap();
+        let read_back = ArticleIndex::from_json_reader(json.as_slice()).unwrap();
+        assert_eq!(read_back.articles_for_site("site.test"), 2);
+        assert_eq!(read_back.word_count(), 2);
+    }
+
+    #[test]
+    fn removing_an_article_drops_its_share_of_a_shared_word() {
+        let mut index = rust_async_index();
+        assert_eq!(
+            index.top_words(2),
+            vec![("async".to_string(), 4), ("rust".to_string(), 3)]
+        );
+
+        assert!(index.remove("u/both"));
+        assert!(!index.remove("u/both"));
+
+        assert_eq!(
+            index.search("rust"),
+            vec![(Article::new("u/rust".into(), "Rust".into()), 1)]
+        );
+        assert_eq!(
+            index.top_words(2),
+            vec![("async".to_string(), 3), ("rust".to_string(), 1)]
+        );
+        assert_eq!(index.search_in_site("site.test", "async").len(), 1);
+        assert_eq!(index.words("u/both"), None);
+    }
+
+    #[test]
+    fn a_rare_term_ranks_its_articles_above_a_common_one() {
+        let mut index = ArticleIndex::new();
+        let site = || "site.test".to_string();
+        for n in 0..9 {
+            let title = format!("Common {}", n);
+            let url = format!("u/common/{}", n);
+            index.add(site(), title, url, bag(&["common", "filler"]));
+        }
+        index.add(
+            site(),
+            "Rare".into(),
+            "u/rare".into(),
+            bag(&["common", "rare"]),
+        );
+
+        let common = index.search_ranked("common");
+        let rare = index.search_ranked("rare");
+        assert_eq!(common.len(), 10);
+        assert_eq!(titles(&rare), vec!["Rare"]);
+        // both terms make up half of their articles, so only the document frequency differs
+        assert!(common.iter().all(|(_, score)| *score < rare[0].1));
+
+        let frequencies = |term| {
+            let frequency = |_: &Article, cx: &ScoringContext| cx.document_frequency as f64;
+            index.search_by(term, frequency)[0].1
+        };
+        assert_eq!(frequencies("common"), 10.0);
+        assert_eq!(frequencies("rare"), 1.0);
+    }
+
+    #[test]
+    fn documents_are_indexed_with_the_word_settings_of_the_config() {
+        let config = IndexConfig::builder()
+            .stop_words(vec!["about"])
+            .min_word_len(3)
+            .build();
+        let mut index = ArticleIndex::new();
+        let site = || "site.test".to_string();
+        let text = "All about Rust: go and read about rust";
+        index
+            .add_document(site(), "Rust".into(), "u/rust".into(), text, &config)
+            .unwrap();
+        let text = "Gardening tips, and some Rust removal";
+        index
+            .add_document(site(), "Garden".into(), "u/garden".into(), text, &config)
+            .unwrap();
+        index.set_query_config(&config);
+
+        // two mentions in the text, plus the title
+        let rust = index.search("rust");
+        assert_eq!(titles(&rust), vec!["Rust", "Garden"]);
+        assert_eq!(rust[0].1, 2 + TITLE_WEIGHT);
+        assert_eq!(titles(&index.search("gardening")), vec!["Garden"]);
+        // a stop word and a word shorter than the minimum
+        assert!(index.search("about").is_empty());
+        assert!(index.search("go").is_empty());
+    }
+
+    #[test]
+    fn custom_scorers_decide_the_order_of_the_results() {
+        let mut index = ArticleIndex::new();
+        let site = || "site.test".to_string();
+        let mut long = vec!["rust"];
+        long.extend(vec!["filler"; 9]);
+        index.add(site(), "Alpha".into(), "u/alpha".into(), bag(&long));
+        let middle = ["rust", "filler", "filler"];
+        index.add(site(), "Mid".into(), "u/mid".into(), bag(&middle));
+        index.add(site(), "Zeta".into(), "u/zeta".into(), bag(&["rust"]));
+        index.add(site(), "Other".into(), "u/other".into(), bag(&["go"]));
+
+        // equal scores fall back to the order of the titles
+        let constant = index.search_by("rust", |_, _| 1.0);
+        assert_eq!(titles(&constant), vec!["Alpha", "Mid", "Zeta"]);
+        assert!(constant.iter().all(|(_, score)| *score == 1.0));
+
+        let per_word = |_: &Article, cx: &ScoringContext| {
+            f64::from(cx.term_frequency) / f64::from(cx.article_length)
+        };
+        let penalized = index.search_by("rust", per_word);
+        assert_eq!(titles(&penalized), vec!["Zeta", "Mid", "Alpha"]);
+        assert_eq!(penalized[0].1, 1.0);
+        assert_eq!(penalized[2].1, 0.1);
+    }
+
+    #[test]
+    fn phrase_search_needs_the_words_in_order_and_next_to_each_other() {
+        let config = IndexConfig::builder().record_positions(true).build();
+        let mut index = ArticleIndex::new();
+        let site = || "site.test".to_string();
+        let text = "Notes on machine learning and deep models";
+        index
+            .add_document(site(), "ML".into(), "u/ml".into(), text, &config)
+            .unwrap();
+        let text = "A machine for sewing, with learning curves";
+        index
+            .add_document(site(), "Sewing".into(), "u/sewing".into(), text, &config)
+            .unwrap();
+
+        assert_eq!(
+            titles_of(&index.search_phrase("Machine Learning")),
+            vec!["ML"]
+        );
+        assert!(index.search_phrase("learning machine").is_empty());
+        assert_eq!(
+            titles_of(&index.search_phrase("machine")),
+            vec!["ML", "Sewing"]
+        );
+        assert!(index.search_phrase("").is_empty());
+    }
+
+    #[test]
+    fn stemming_collapses_forms_of_a_word() {
+        let stemming = IndexConfig::builder().stem(true).build();
+        let text = "runs, and running";
+        assert_eq!(words_in(text, &stemming), vec![("run".to_string(), 2)]);
+        let plain = IndexConfig::default();
+        assert_eq!(words_in(text, &plain).len(), 2);
+
+        let mut index = ArticleIndex::new();
+        let site = "site.test".to_string();
+        let url = "u/run".to_string();
+        let stemming = IndexConfig::builder().stem(true).title_weight(0).build();
+        index
+            .add_document(site, "Run".into(), url, text, &stemming)
+            .unwrap();
+        index.set_query_config(&stemming);
+        assert_eq!(index.search("Running")[0].1, 2);
+        assert_eq!(index.top_words(10), vec![("run".to_string(), 2)]);
+    }
+
+    #[test]
+    fn csv_export_has_a_row_per_word_and_quotes_awkward_titles() {
+        let mut index = ArticleIndex::new();
+        let site = || "site.test".to_string();
+        let title = "Rust, \"fearless\" and fast".to_string();
+        index.add(
+            site(),
+            title.clone(),
+            "u/a?x=1,2".into(),
+            bag(&["rust", "rust", "fast"]),
+        );
+        index.add(site(), "Plain".into(), "u/b".into(), bag(&["go"]));
+
+        let mut csv = Vec::new();
+        index.export_csv(&mut csv).unwrap();
+
+        let mut reader = csv::Reader::from_reader(csv.as_slice());
+        let headers = reader.headers().unwrap().clone();
+        assert_eq!(headers, vec!["site", "title", "url", "word", "frequency"]);
+        let rows = reader
+            .records()
+            .map(|row| row.unwrap().iter().map(String::from).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], vec!["site.test", "Plain", "u/b", "go", "1"]);
+        assert_eq!(rows[2], vec!["site.test", &title, "u/a?x=1,2", "rust", "2"]);
+    }
+
+    #[test]
+    fn windows_1252_pages_are_decoded_before_indexing() {
+        let body = b"<html><body><p>un caf\xe9 cr\xe8me</p></body></html>".to_vec();
+        let server = MockServer::start();
+        let page = MockResponse::new(200, "text/html; charset=windows-1252", body.clone());
+        server.route("/header", page);
+        let mut tagged = b"<html><head><meta charset=\"windows-1252\"></head>".to_vec();
+        tagged.extend_from_slice(&body[6..]);
+        server.route("/meta", MockResponse::new(200, "text/html", tagged));
+
+        let config = IndexConfig::default();
+        let fetcher = no_retries(IndexConfig::builder());
+        let mut index = ArticleIndex::new();
+        let site = || "127.0.0.1".to_string();
+        for path in &["/header", "/meta"] {
+            let url = server.url(path);
+            let document = fetcher.fetch_document(&url).unwrap();
+            let words = document_words(&document, &config).unwrap();
+            index.add(site(), path.to_string(), url, words.counts);
+        }
+
+        assert_eq!(index.search("café").len(), 2);
+        assert_eq!(index.search("crème").len(), 2);
+    }
+
+    #[test]
+    fn short_words_are_dropped_unless_min_word_len_allows_them() {
+        let text = "a b c plan x";
+        assert_eq!(
+            words_in(text, &IndexConfig::default()),
+            vec![("plan".to_string(), 1)]
+        );
+
+        let single_letters = IndexConfig::builder().min_word_len(1).build();
+        let words = words_in(text, &single_letters)
+            .into_iter()
+            .map(|(word, _)| word)
+            .collect::<Vec<_>>();
+        // "a" is still a stop word
+        assert_eq!(words, vec!["b", "c", "plan", "x"]);
+    }
+
+    #[test]
+    fn redirects_are_followed_up_to_the_limit() {
+        let server = MockServer::start();
+        for n in 0..3 {
+            let next = server.url(&format!("/hop{}", n + 1));
+            let redirect = MockResponse::status(302).with_header("Location", &next);
+            server.route(&format!("/hop{}", n), redirect);
+        }
+        server.route("/hop3", MockResponse::html("arrived"));
+        let fetcher = no_retries(IndexConfig::builder().max_redirects(2));
+
+        let document = fetcher.fetch_document(&server.url("/hop1")).unwrap();
+        assert_eq!(document.url, server.url("/hop3"));
+
+        let start = server.url("/hop0");
+        let e = fetcher.fetch_document(&start).unwrap_err();
+        let e = e.downcast_ref::<RssIndexError>().unwrap();
+        assert!(matches!(e, RssIndexError::TooManyRedirects { url } if *url == start));
+
+        let looping = server.url("/loop");
+        server.route(
+            "/loop",
+            MockResponse::status(302).with_header("Location", &looping),
+        );
+        let e = no_retries(IndexConfig::builder())
+            .fetch_document(&looping)
+            .unwrap_err();
+        let e = e.downcast_ref::<RssIndexError>().unwrap();
+        assert!(matches!(e, RssIndexError::TooManyRedirects { .. }));
+        assert_eq!(server.hits("/loop"), 1);
+    }
+
+    #[test]
+    fn numbers_and_punctuation_are_only_kept_if_configured() {
+        let text = "Rust 2024 — rust2021 • 3.14";
+        let words = |config: IndexConfigBuilder| -> Vec<String> {
+            tokenize(text, &config.min_word_len(1).build()).collect()
+        };
+
+        assert_eq!(words(IndexConfig::builder()), vec!["rust", "rust2021"]);
+        assert_eq!(
+            words(IndexConfig::builder().keep_numbers(true)),
+            vec!["rust", "2024", "rust2021", "3.14"]
+        );
+        assert_eq!(
+            words(IndexConfig::builder().keep_punct(true)),
+            vec!["rust", "—", "rust2021", "•"]
+        );
+    }
+
+    #[test]
+    fn the_word_cap_keeps_the_most_frequent_distinct_words() {
+        let url = "http://site.test/long";
+        // a thousand distinct words, and one of them repeated
+        let letters = |n: usize| {
+            let letter = |i: usize| char::from(b'a' + (i % 26) as u8);
+            format!("w{}{}{}", letter(n / 676), letter(n / 26), letter(n))
+        };
+        let mut body = (0..1000).map(letters).collect::<Vec<_>>();
+        body.extend(vec!["common".to_string(); 5]);
+        let fetcher = MapFetcher::new().page(url, html(&body.join(" ")));
+        let config = IndexConfig::builder()
+            .max_words_per_article(10)
+            .record_positions(true)
+            .build();
+        let article = Article::new(url.to_string(), "Post".to_string());
+
+        let words = process_article(&article, &fetcher, &config).unwrap();
+
+        assert_eq!(words.counts.counts.len(), 10);
+        assert_eq!(words.counts.counts.get("post"), Some(&TITLE_WEIGHT));
+        assert_eq!(words.counts.counts.get("common"), Some(&5));
+        assert_eq!(words.positions.unwrap().len(), 9);
+    }
+
+    #[test]
+    fn cached_articles_are_fetched_once() {
+        let url = "http://site.test/post";
+        let map = Arc::new(MapFetcher::new().page(url, html("cached words")));
+        let config = IndexConfig::default();
+        let fetcher = CachedFetcher::new(map.clone(), 8, &config);
+        let article = Article::new(url.to_string(), "Post".to_string());
+
+        let first = process_article(&article, &fetcher, &config).unwrap();
+        let second = process_article(&article, &fetcher, &config).unwrap();
+
+        assert_eq!(first.counts.counts, second.counts.counts);
+        assert_eq!(first.counts.counts.get("cached"), Some(&1));
+        assert_eq!(map.calls(url), 1);
+    }
+
+    #[test]
+    fn title_words_outrank_body_words_unless_the_weight_is_zero() {
+        let article = Article::new("http://site.test/a".to_string(), "Zebra".to_string());
+        let words = |config: &IndexConfig| {
+            let mut words = text_words("horse horse", config).unwrap();
+            add_article_details(&article, config, &mut words);
+            words.counts.counts
+        };
+
+        let weighted = words(&IndexConfig::default());
+        assert_eq!(weighted.get("zebra"), Some(&TITLE_WEIGHT));
+        assert!(weighted["zebra"] > weighted["horse"]);
+
+        let unweighted = words(&IndexConfig::builder().title_weight(0).build());
+        assert_eq!(unweighted.get("zebra"), None);
+        assert_eq!(unweighted.get("horse"), Some(&2));
+    }
+
+    #[test]
+    fn phrases_in_titles_do_not_match() {
+        let config = IndexConfig::builder().record_positions(true).build();
+        let article = Article::new(
+            "http://site.test/a".to_string(),
+            "Zebra Crossing".to_string(),
+        );
+        let mut words = text_words("a horse", &config).unwrap();
+        add_article_details(&article, &config, &mut words);
+        let mut index = ArticleIndex::new();
+        index.add_article("site.test".to_string(), article.title, article.url, words);
+
+        assert_eq!(index.search("zebra").len(), 1);
+        assert!(index.search_phrase("zebra crossing").is_empty());
+        assert_eq!(index.search_phrase("horse").len(), 1);
+    }
+
+    /// `count` distinct URLs, scattered like real ones would be.
+    fn sample_urls(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|n| {
+                let mut hasher = DefaultHasher::new();
+                n.hash(&mut hasher);
+                format!("http://site{}.test/{:x}", n % 97, hasher.finish())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn each_url_is_new_to_exactly_one_of_many_threads() {
+        let urls = sample_urls(1_000);
+        let with_bloom = IndexConfig::builder().bloom_capacity(1_000).build();
+        for config in [IndexConfig::default(), with_bloom].iter() {
+            let seen = SeenUrls::new(HashSet::new(), config);
+            let start = std::sync::Barrier::new(16);
+            let new_counts = std::thread::scope(|scope| {
+                let threads = (0..16)
+                    .map(|t| {
+                        let (seen, start, urls) = (&seen, &start, &urls);
+                        scope.spawn(move || {
+                            start.wait();
+                            // every thread starts somewhere else, so they race on every URL
+                            let offset = t * urls.len() / 16;
+                            let mut new = vec![0; urls.len()];
+                            for i in (0..urls.len()).map(|i| (i + offset) % urls.len()) {
+                                new[i] += usize::from(seen.insert(urls[i].clone()));
+                            }
+                            new
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                threads
+                    .into_iter()
+                    .map(|thread| thread.join().unwrap())
+                    .fold(vec![0; urls.len()], |total, new| {
+                        total.iter().zip(new).map(|(a, b)| a + b).collect()
+                    })
+            });
+            assert!(new_counts.iter().all(|&count| count == 1));
+            assert!(urls.iter().all(|url| seen.contains(url)));
+        }
+    }
+
+    #[test]
+    fn a_bloom_filter_never_hides_a_seen_url() {
+        let urls = sample_urls(20_000);
+        let (seen, unseen) = urls.split_at(10_000);
+        let config = IndexConfig::builder().bloom_capacity(1_000).build();
+        let filtered = SeenUrls::new(HashSet::new(), &config);
+        let exact = SeenUrls::new(HashSet::new(), &IndexConfig::default());
+        for url in seen {
+            assert!(filtered.insert(url.clone()));
+            assert!(exact.insert(url.clone()));
+        }
+
+        for url in &urls {
+            assert_eq!(filtered.contains(url), exact.contains(url), "{}", url);
+        }
+        assert!(seen.iter().all(|url| !filtered.insert(url.clone())));
+        assert!(unseen.iter().all(|url| !filtered.contains(url)));
+    }
+
+    #[test]
+    fn new_urls_are_mostly_answered_by_the_bloom_filter() {
+        let urls = sample_urls(2_000);
+        let (seen, unseen) = urls.split_at(1_000);
+        let config = IndexConfig::builder().bloom_capacity(urls.len()).build();
+        let filtered = SeenUrls::new(seen.iter().cloned().collect(), &config);
+        let exact = SeenUrls::new(seen.iter().cloned().collect(), &IndexConfig::default());
+
+        for url in unseen {
+            assert!(!filtered.contains(url));
+            assert!(!exact.contains(url));
+        }
+        // only false positives of the filter go to the set
+        assert!(filtered.exact_lookups() < unseen.len() / 20);
+        assert_eq!(exact.exact_lookups(), unseen.len());
+
+        // a duplicate is looked up once, and a new URL not at all, unless it is a false positive
+        let before = filtered.exact_lookups();
+        assert!(!filtered.insert(seen[0].clone()));
+        assert_eq!(filtered.exact_lookups(), before + 1);
+        let added = unseen.iter().filter(|url| filtered.insert(url.to_string()));
+        assert_eq!(added.count(), unseen.len());
+        assert!(filtered.exact_lookups() < before + 1 + unseen.len() / 20);
+    }
+
+    #[test]
+    fn diff_lists_added_removed_and_modified_urls() {
+        let site = || "site.test".to_string();
+        let mut previous = ArticleIndex::new();
+        previous.add(site(), "Same".into(), "u/same".into(), bag(&["rust"]));
+        previous.add(site(), "Edited".into(), "u/edited".into(), bag(&["go"]));
+        previous.add(site(), "Gone".into(), "u/gone".into(), bag(&["zig"]));
+        let mut current = ArticleIndex::new();
+        current.add(site(), "Same".into(), "u/same".into(), bag(&["rust"]));
+        current.add(
+            site(),
+            "Edited".into(),
+            "u/edited".into(),
+            bag(&["go", "go"]),
+        );
+        current.add(site(), "New".into(), "u/new".into(), bag(&["c"]));
+
+        let expected = IndexDiff {
+            added: vec!["u/new".to_string()],
+            removed: vec!["u/gone".to_string()],
+            modified: vec!["u/edited".to_string()],
+        };
+        assert_eq!(current.diff(&previous), expected);
+        assert_eq!(current.diff(&current), IndexDiff::default());
+    }
+
+    #[test]
+    fn builder_leaves_unset_fields_at_their_defaults() {
+        let timeout = Duration::from_secs(3);
+        let config = IndexConfig::builder()
+            .timeout(timeout)
+            .max_retries(7)
+            .build();
+
+        assert_eq!(config.timeout, timeout);
+        assert_eq!(config.max_retries, 7);
+        let default = IndexConfig::default();
+        assert_ne!(config.timeout, default.timeout);
+        assert_ne!(config.max_retries, default.max_retries);
+        assert_eq!(config.stop_words, default.stop_words);
+        assert_eq!(config.max_threads_total, default.max_threads_total);
+        assert_eq!(config.min_word_len, default.min_word_len);
+        assert_eq!(config.title_weight, default.title_weight);
+        assert_eq!(config.base_delay, default.base_delay);
+        assert_eq!(config.feeds_pool_size, default.feeds_pool_size);
+        assert_eq!(config.sites_pool_size, default.sites_pool_size);
+        assert_eq!(config.max_articles_per_feed, default.max_articles_per_feed);
+        assert_eq!(config.tracking_params, default.tracking_params);
+    }
+
+    #[test]
+    fn report_lists_sites_their_articles_and_top_words() {
+        let mut index = ArticleIndex::new();
+        let rust = bag(&["rust", "rust", "rust", "borrow", "borrow", "trait"]);
+        index.add(
+            "b.test".into(),
+            "Rust".into(),
+            "http://b.test/rust".into(),
+            rust,
+        );
+        index.add(
+            "a.test".into(),
+            "Zig".into(),
+            "http://a.test/zig".into(),
+            bag(&["zig"]),
+        );
+        index.add(
+            "a.test".into(),
+            "Go".into(),
+            "http://a.test/go".into(),
+            bag(&["go"]),
+        );
+
+        let mut report = Vec::new();
+        index.print_report(&mut report, 2).unwrap();
+
+        let expected = "\
+a.test (2 articles)
+    Go
+        http://a.test/go
+        go (1)
+    Zig
+        http://a.test/zig
+        zig (1)
+b.test (1 article)
+    Rust
+        http://b.test/rust
+        rust (3), borrow (2)
+";
+        assert_eq!(String::from_utf8(report).unwrap(), expected);
+    }
+
+    #[test]
+    fn bodies_over_the_cap_are_refused() {
+        let server = MockServer::start();
+        let big = "a".repeat(100_000);
+        server.route("/declared", MockResponse::html(&big));
+        server.route("/streamed", MockResponse::html(&big).without_length());
+        server.route("/small", MockResponse::html("fits"));
+        let config = IndexConfig::builder().max_body_bytes(1_000);
+        let fetcher = no_retries(config.clone());
+        let config = config.build();
+
+        for path in &["/declared", "/streamed"] {
+            let url = server.url(path);
+            let article = Article::new(url.clone(), "Big".to_string());
+            let e = process_article(&article, &fetcher, &config).unwrap_err();
+            let e = e.downcast_ref::<RssIndexError>().unwrap();
+            assert!(
+                matches!(e, RssIndexError::BodyTooLarge { url: u, limit: 1_000 } if *u == url),
+                "{}: {}",
+                path,
+                e
+            );
+        }
+        let article = Article::new(server.url("/small"), "Small".to_string());
+        assert!(process_article(&article, &fetcher, &config).is_ok());
+    }
+
+    #[test]
+    fn search_in_site_only_finds_articles_of_that_site() {
+        let mut index = ArticleIndex::new();
+        index.add(
+            "a.test".into(),
+            "A1".into(),
+            "http://a.test/1".into(),
+            bag(&["rust"]),
+        );
+        index.add(
+            "a.test".into(),
+            "A2".into(),
+            "http://a.test/2".into(),
+            bag(&["go"]),
+        );
+        index.add(
+            "b.test".into(),
+            "B1".into(),
+            "http://b.test/1".into(),
+            bag(&["rust", "rust"]),
+        );
+
+        assert_eq!(titles(&index.search("rust")), vec!["B1", "A1"]);
+        assert_eq!(titles(&index.search_in_site("a.test", "rust")), vec!["A1"]);
+        assert_eq!(titles(&index.search_in_site("b.test", "rust")), vec!["B1"]);
+        assert!(index.search_in_site("b.test", "go").is_empty());
+        assert!(index.search_in_site("c.test", "rust").is_empty());
+    }
+
+    #[test]
+    fn subdomains_share_a_site_key_by_domain_but_not_by_host() {
+        let by_host = IndexConfig::default();
+        assert_eq!(by_host.site_key_of("a.example.com"), "a.example.com");
+        assert_eq!(by_host.site_key_of("b.example.com"), "b.example.com");
+
+        let suffixes = "// ===BEGIN ICANN DOMAINS===\ncom\nco.uk\n// ===END ICANN DOMAINS===";
+        let by_domain = IndexConfig::builder()
+            .site_key(SiteKey::Domain)
+            .public_suffixes(suffixes.parse().unwrap())
+            .build();
+        assert_eq!(by_domain.site_key_of("a.example.com"), "example.com");
+        assert_eq!(by_domain.site_key_of("b.example.com"), "example.com");
+        assert_eq!(by_domain.site_key_of("news.example.co.uk"), "example.co.uk");
+        assert_eq!(by_domain.site_key_of("co.uk"), "co.uk");
+        assert_eq!(by_domain.site_key_of("127.0.0.1"), "127.0.0.1");
+    }
+
+    #[test]
+    fn iter_walks_every_article_and_words_looks_up_its_counts() {
+        let mut index = ArticleIndex::new();
+        let a1 = "http://a.test/1".to_string();
+        index.add("a.test".into(), "A1".into(), a1.clone(), bag(&["rust"]));
+        // the same article under a second URL
+        let a1_mirror = "http://a.test/mirror/1".to_string();
+        index.add(
+            "a.test".into(),
+            "A1".into(),
+            a1_mirror.clone(),
+            bag(&["rust"]),
+        );
+        let b1 = "http://b.test/1".to_string();
+        index.add("b.test".into(), "B1".into(), b1.clone(), bag(&["go", "go"]));
+
+        let mut articles = index
+            .iter()
+            .map(|(site, article)| (site.to_string(), article.url, article.title))
+            .collect::<Vec<_>>();
+        articles.sort();
+        let expected = vec![
+            ("a.test".to_string(), a1.clone(), "A1".to_string()),
+            ("b.test".to_string(), b1.clone(), "B1".to_string()),
+        ];
+        assert_eq!(articles, expected);
+
+        assert_eq!(index.words(&b1).unwrap().get("go"), Some(&2));
+        assert_eq!(index.words(&a1_mirror), index.words(&a1));
+        assert!(index.words("http://c.test/1").is_none());
+    }
+
+    #[test]
+    fn markdown_articles_are_indexed_without_their_formatting() {
+        let server = MockServer::start();
+        let markdown = "# Heading\n\nSome *emphasis*, **bold** and [a link](http://x.test/target).";
+        server.route("/post.md", MockResponse::new(200, "text/plain", markdown));
+        // punctuation is kept, so any markers left in the text would show up as words
+        let config = IndexConfig::builder().keep_punct(true).title_weight(0);
+        let fetcher = no_retries(config.clone());
+        let article = Article::new(server.url("/post.md"), "Post".to_string());
+
+        let words = process_article(&article, &fetcher, &config.build()).unwrap();
+
+        let words = words.counts.counts.keys().cloned().collect::<Vec<_>>();
+        for word in &["heading", "some", "emphasis", "bold", "link"] {
+            assert!(words.contains(&word.to_string()), "{} missing", word);
+        }
+        for marker in &["#", "*", "**", "[", "]", "target"] {
+            assert!(!words.contains(&marker.to_string()), "{} indexed", marker);
+        }
+        assert_eq!(
+            DocumentFormat::detect("http://x.test/notes.txt", Some("text/plain")),
+            DocumentFormat::PlainText
+        );
+    }
+
+    #[test]
+    fn whitespace_keeps_punctuation_that_unicode_words_split_off() {
+        let text = "Hello, world! It's e-mail.";
+        let split = |tokenizer: Tokenizer| {
+            let config = IndexConfig::builder()
+                .tokenizer(tokenizer)
+                .min_word_len(1)
+                .stop_words(Vec::<String>::new())
+                .build();
+            tokenize(text, &config).collect::<Vec<_>>()
+        };
+
+        let whitespace = split(Tokenizer::Whitespace);
+        assert_eq!(whitespace, vec!["hello,", "world!", "it's", "e-mail."]);
+        let unicode = split(Tokenizer::UnicodeWords);
+        assert_eq!(unicode, vec!["hello", "world", "it's", "e", "mail"]);
+    }
+
+    #[test]
+    fn every_query_is_normalized_like_the_index() {
+        let config = IndexConfig::builder()
+            .stem(true)
+            .record_positions(true)
+            .title_weight(0)
+            .build();
+        let mut index = ArticleIndex::new();
+        let site = "site.test".to_string();
+        let text = "Running jumps, quickly.";
+        index
+            .add_document(site.clone(), "Run".into(), "u/run".into(), text, &config)
+            .unwrap();
+        index.set_query_config(&config);
+
+        assert_eq!(titles(&index.search("RUNNING")), vec!["Run"]);
+        assert_eq!(titles(&index.search_in_site(&site, "Runs")), vec!["Run"]);
+        assert_eq!(titles(&index.search_ranked("running")), vec!["Run"]);
+        let all = index.query(&["Runs", "Jumping"], QueryMode::All);
+        assert_eq!(titles(&all), vec!["Run"]);
+        assert_eq!(
+            titles_of(&index.search_phrase("runs, JUMPING")),
+            vec!["Run"]
+        );
+
+        // without the settings of the index, the forms of a word don't meet
+        index.set_query_config(&IndexConfig::default());
+        assert!(index.search("running").is_empty());
+        assert!(index.search_phrase("running jumps").is_empty());
+    }
 }
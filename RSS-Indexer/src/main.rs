@@ -13,6 +13,16 @@ mod pooled;
 mod single;
 mod asynchro;
 mod threadpool;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "robots")]
+mod robots;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(test)]
+mod testutil;
 
 const MAX_MATCHES: usize = 10;
 
@@ -46,26 +56,25 @@ fn build_async(filename: &str) -> RssIndexResult<RssIndex> {
         .enable_all()
         .build()?;
 
-    let mut article_index = rt.block_on(async { asynchro::process_feed_file(&filename).await })?;
+    let mut article_index = rt.block_on(async { asynchro::process_feed_file(filename).await })?;
     build_index(&mut article_index, &mut rss_index);
 
     Result::Ok(rss_index)
 }
 
 fn build_pooled(filename: &str) -> RssIndexResult<RssIndex> {
-    let article_index = Arc::new(Mutex::new(ArticleIndex::new()));
     let mut rss_index = RssIndex::new();
 
-    pooled::process_feed_file(filename, article_index.clone())?;
-
-    let mut final_index = article_index.lock().unwrap();
-
-    build_index(&mut final_index, &mut rss_index);
+    let mut article_index = pooled::index_feed_file(filename, IndexConfig::default())?;
+    build_index(&mut article_index, &mut rss_index);
 
     Result::Ok(rss_index)
 }
 
 fn main() -> RssIndexResult<()> {
+    // log progress at info level unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
     let mut args = env::args().skip(1);
 
     let rss_index = match (args.next(), args.next().as_deref()) {
@@ -101,7 +110,7 @@ fn main() -> RssIndexResult<()> {
                 let mut articles = m.iter().collect::<Vec<(&Article, &u32)>>();
                 articles.sort_by(|(art1, ct1), (art2, ct2)| {
                     // sort by decreasing hits and alphabetical title
-                    ct2.cmp(&ct1).then(art1.cmp_title(&art2))
+                    ct2.cmp(ct1).then(art1.cmp_title(art2))
                 });
                 if articles.len() > MAX_MATCHES {
                     println!("Here are the top {} of them:", MAX_MATCHES);
@@ -0,0 +1,104 @@
+use prometheus::core::Collector;
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+use std::sync::OnceLock;
+
+/// Counters and gauges of all crawls run by this process, registered with a registry of their
+/// own. They are updated at the same points as the counters behind `CrawlStats`.
+pub struct Metrics {
+    registry: Registry,
+    pub articles_indexed: IntCounter,
+    pub fetch_errors: IntCounter,
+    pub feeds_processed: IntCounter,
+    /// Feed and article jobs currently running.
+    pub active_workers: IntGauge,
+}
+
+impl Metrics {
+    /// Metrics starting from zero, with a registry of their own. Crawls update those of
+    /// `metrics()`; other instances are only useful to look at a single crawl.
+    pub fn new() -> Self {
+        let articles_indexed =
+            IntCounter::new("articles_indexed_total", "Articles fetched and indexed").unwrap();
+        let fetch_errors =
+            IntCounter::new("fetch_errors_total", "Feeds and articles that failed").unwrap();
+        let feeds_processed =
+            IntCounter::new("feeds_processed_total", "Feeds fetched and crawled").unwrap();
+        let active_workers =
+            IntGauge::new("active_workers", "Feed and article jobs currently running").unwrap();
+
+        let registry = Registry::new();
+        let collectors: Vec<Box<dyn Collector>> = vec![
+            Box::new(articles_indexed.clone()),
+            Box::new(fetch_errors.clone()),
+            Box::new(feeds_processed.clone()),
+            Box::new(active_workers.clone()),
+        ];
+        for collector in collectors {
+            // the names are all different, so registering can't fail
+            registry.register(collector).unwrap();
+        }
+        Metrics {
+            registry,
+            articles_indexed,
+            fetch_errors,
+            feeds_processed,
+            active_workers,
+        }
+    }
+
+    /// These metrics in the Prometheus text exposition format.
+    pub fn text(&self) -> String {
+        let mut text = Vec::new();
+        // encoding into a Vec can't fail
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut text)
+            .unwrap();
+        String::from_utf8(text).unwrap()
+    }
+}
+
+/// The metrics of this process.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// The metrics of this process in the Prometheus text exposition format.
+pub fn metrics_text() -> String {
+    metrics().text()
+}
+
+/// Counts a job towards `active_workers` for as long as it is alive.
+pub struct ActiveWorker(());
+
+impl ActiveWorker {
+    pub fn start() -> Self {
+        metrics().active_workers.inc();
+        ActiveWorker(())
+    }
+}
+
+impl Drop for ActiveWorker {
+    fn drop(&mut self) {
+        metrics().active_workers.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_metric_is_rendered_with_its_value() {
+        let metrics = Metrics::new();
+        metrics.articles_indexed.inc_by(3);
+        metrics.fetch_errors.inc();
+        let text = metrics.text();
+
+        assert!(text.contains("# TYPE articles_indexed_total counter"));
+        assert!(text.contains("\narticles_indexed_total 3\n"));
+        assert!(text.contains("\nfetch_errors_total 1\n"));
+        assert!(text.contains("\nfeeds_processed_total 0\n"));
+        assert!(metrics_text().contains("# TYPE active_workers gauge"));
+    }
+}
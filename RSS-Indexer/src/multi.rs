@@ -1,20 +1,14 @@
-use rss::Channel;
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::BufReader;
+use dashmap::DashSet;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::io::Read;
 use std::result::Result;
 
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use url::Url;
 
 use crate::common::*;
 
-/// Thread limits.
-const MAX_THREADS_FEEDS: u32 = 5;
-const MAX_THREADS_SITES: u32 = 10; // 10
-const MAX_THREADS_TOTAL: u32 = 18; // 18
-
 /// A lock around some T, with a condition variable for notifying/waiting.
 struct CvarLock<T> {
     mutex: Mutex<T>,
@@ -38,13 +32,47 @@ pub struct ThreadCount {
 }
 
 /// Same as for the single-threaded version, but now spawn a new thread for each call to
-/// `process_feed`. Make sure to respect the thread limits!
-pub fn process_feed_file(file_name: &str, index: Arc<Mutex<ArticleIndex>>) -> RssIndexResult<()> {
-    let file = File::open(file_name)?;
-    println!("Processing feed file: {}", file_name);
+/// `process_feed`. Make sure to respect the thread limits! Returns the feeds and articles that
+/// were skipped because of an error.
+pub fn process_feed_file(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+) -> RssIndexResult<ItemErrors> {
+    process_feed_file_with_config(file_name, index, IndexConfig::default())
+}
+
+/// Same as `process_feed_file`, but with the thread limits taken from `config`.
+pub fn process_feed_file_with_config(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+) -> RssIndexResult<ItemErrors> {
+    let file = open_feed_file(file_name)?;
+    info!("Processing feed file: {}", file_name);
 
-    let channel = Channel::read_from(BufReader::new(file))?;
-    let urls = Arc::new(Mutex::new(HashSet::new()));
+    let fetcher = Arc::new(HttpFetcher::new(&config)?);
+    process_feed_reader(file, index, config, fetcher)
+}
+
+/// Same as `process_feed_file_with_config`, but the feed of feeds is read from `reader`, and feeds
+/// and articles are fetched with `fetcher`.
+fn process_feed_reader<R: Read>(
+    reader: R,
+    index: Arc<Mutex<ArticleIndex>>,
+    mut config: IndexConfig,
+    fetcher: SharedFetcher,
+) -> RssIndexResult<ItemErrors> {
+    config.max_threads_feeds = config.max_threads_feeds.max(1);
+    config.max_threads_sites = config.max_threads_sites.max(1);
+    config.max_threads_total = config.max_threads_total.max(config.max_threads_feeds + 1);
+    let config = Arc::new(config);
+
+    let feeds = read_feed(reader)?;
+    let urls = Arc::new(DashSet::new());
+    let articles = Arc::new(ShardedArticleIndex::new());
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let sources = Arc::new(Mutex::new(Vec::new()));
+    let throttle = Arc::new(HostThrottle::new());
 
     let mut handles = Vec::new();
     let tc = Arc::new(ThreadCount {
@@ -53,41 +81,69 @@ pub fn process_feed_file(file_name: &str, index: Arc<Mutex<ArticleIndex>>) -> Rs
         total_count: CvarLock::new(0),
     });
 
-    for feed in channel.into_items() {
-        let url = feed.link().ok_or(RssIndexError::UrlError)?;
-        let title = feed.title().ok_or(RssIndexError::UrlError)?;
+    for feed in feeds {
+        let (url, title) = match feed.link_and_title() {
+            Ok(link_and_title) => link_and_title,
+            Err(e) => {
+                warn!("Skipping feed: {}", e);
+                let url = feed.link().unwrap_or_default().to_string();
+                errors.lock().unwrap().push((url, e));
+                continue;
+            }
+        };
 
-        if urls.lock().unwrap().contains(url) {
-            println!("Skipping already seen feed: {} [{}]", title, url);
+        // check and insert in one step, so that no other thread can slip in between
+        if !urls.insert(canonical_url(url, &config)) {
+            debug!("Skipping already seen feed: {} [{}]", title, url);
             continue;
         }
-        urls.lock().unwrap().insert(url.to_string());
-        println!("Processing feed: {} [{}]", title, url);
+        info!("Processing feed: {} [{}]", title, url);
 
+        // wait for a feed slot before taking a slot of the total, so that no slot of the total is
+        // held while waiting for a feed thread, whose articles may need that slot to finish
         {
-            let mut cur_tot_cnt = tc.total_count.mutex.lock().unwrap();
-            while *cur_tot_cnt > MAX_THREADS_TOTAL - 1 {
-                cur_tot_cnt = tc.total_count.condvar.wait(cur_tot_cnt).unwrap();
+            let mut cur_feeds_cnt = tc.feeds_count.mutex.lock().unwrap();
+            while *cur_feeds_cnt >= config.max_threads_feeds {
+                cur_feeds_cnt = tc.feeds_count.condvar.wait(cur_feeds_cnt).unwrap();
             }
-            *cur_tot_cnt += 1;
+            *cur_feeds_cnt += 1;
         }
 
         {
-            let mut cur_feeds_cnt = tc.feeds_count.mutex.lock().unwrap();
-            while *cur_feeds_cnt > MAX_THREADS_FEEDS - 1 {
-                cur_feeds_cnt = tc.feeds_count.condvar.wait(cur_feeds_cnt).unwrap();
+            let mut cur_tot_cnt = tc.total_count.mutex.lock().unwrap();
+            while *cur_tot_cnt >= config.max_threads_total {
+                cur_tot_cnt = tc.total_count.condvar.wait(cur_tot_cnt).unwrap();
             }
-            *cur_feeds_cnt += 1;
+            *cur_tot_cnt += 1;
         }
 
         let tc2 = Arc::clone(&tc);
         let url = url.to_string();
         let urls = Arc::clone(&urls);
-        let index = Arc::clone(&index);
+        let articles = Arc::clone(&articles);
+        let errors = Arc::clone(&errors);
+        let sources = Arc::clone(&sources);
+        let throttle = Arc::clone(&throttle);
+        let config = Arc::clone(&config);
+        let fetcher = Arc::clone(&fetcher);
 
         let handle = thread::spawn(move || {
             let tc3 = Arc::clone(&tc2);
-            process_feed(&url, index, urls, tc2).unwrap();
+            let feed_errors = Arc::clone(&errors);
+            if let Err(e) = process_feed(
+                &url,
+                articles,
+                urls,
+                feed_errors,
+                sources,
+                throttle,
+                tc2,
+                fetcher,
+                config,
+            ) {
+                warn!("Skipping feed [{}]: {}", url, e);
+                errors.lock().unwrap().push((url, e.into()));
+            }
 
             {
                 let mut cur_tot_cnt = tc3.total_count.mutex.lock().unwrap();
@@ -107,70 +163,129 @@ pub fn process_feed_file(file_name: &str, index: Arc<Mutex<ArticleIndex>>) -> Rs
     for handle in handles {
         handle.join().unwrap();
     }
-    Result::Ok(())
+    // article threads only lock their shard, so the caller's index is locked just once, here
+    let mut index = index.lock().unwrap();
+    articles.drain_into(&mut index);
+    index.add_source_feeds(std::mem::take(&mut *sources.lock().unwrap()));
+    index.set_query_config(&config);
+    drop(index);
+    let errors = std::mem::take(&mut *errors.lock().unwrap());
+    Result::Ok(errors)
 }
 
 /// Same as for the single-threaded version, but now spawn a new thread for each call to
-/// `process_article`. Make sure to respect the thread limits!
+/// `process_article`. Make sure to respect the thread limits! Articles already seen are pushed to
+/// `sources` along with the URL of this feed.
+#[allow(clippy::too_many_arguments)]
 fn process_feed(
     url: &str,
-    index: Arc<Mutex<ArticleIndex>>,
-    urls: Arc<Mutex<HashSet<String>>>,
+    articles: Arc<ShardedArticleIndex>,
+    urls: Arc<DashSet<String>>,
+    errors: Arc<Mutex<ItemErrors>>,
+    sources: Arc<Mutex<Vec<(String, String)>>>,
+    throttle: Arc<HostThrottle>,
     counters: Arc<ThreadCount>,
+    fetcher: SharedFetcher,
+    config: Arc<IndexConfig>,
 ) -> RssIndexResult<()> {
-    let contents = reqwest::blocking::get(url)?.bytes()?;
-    let channel = Channel::read_from(&contents[..])?;
-    let items = channel.into_items();
+    let contents = fetcher.fetch_feed(url)?;
+    let items = parse_feed_at(&contents, url)?;
+    let feed_url = url;
     let mut handles = Vec::new();
+    let mut count = 0;
     for item in items {
-        let (url, site, title) = match (item.link(), Url::parse(&url)?.host_str(), item.title()) {
-            (Some(u), Some(s), Some(t)) => (u, s.to_string(), t),
+        let (url, site, title) = match (item.link(), item.site(), item.title()) {
+            (Some(u), Some(s), Some(t)) => (u, s, t),
             _ => continue,
         };
 
-        if urls.lock().unwrap().contains(url) {
-            println!("Skipping already seen article: {} [{}]", title, url);
+        if is_self_link(url, feed_url, &config) {
+            debug!("Skipping link back to the feed: {} [{}]", title, url);
+            continue;
+        }
+
+        if !config.wants_item(&item) {
+            debug!("Skipping old article: {} [{}]", title, url);
+            continue;
+        }
+
+        if config.feed_is_full(count) {
+            debug!("Skipping remaining articles: per-feed limit reached");
+            break;
+        }
+
+        // check and insert in one step, so that no other thread can slip in between. A dry run
+        // only looks the article up, so that a later run still indexes it.
+        let key = dedup_key(&item, url, feed_url, &config);
+        let seen = if config.dry_run {
+            urls.contains(&key)
+        } else {
+            !urls.insert(key)
+        };
+        if seen {
+            debug!("Skipping already seen article: {} [{}]", title, url);
+            let source = (url.to_string(), feed_url.to_string());
+            sources.lock().unwrap().push(source);
+            continue;
+        }
+        count += 1;
+
+        if config.dry_run {
+            info!("Would process article: {} [{}]", title, url);
             continue;
         }
-        urls.lock().unwrap().insert(url.to_string());
-        println!("Processing article: {} [{}]", title, url);
+        info!("Processing article: {} [{}]", title, url);
 
-        let article = Article::new(url.to_string(), title.to_string());
+        let article = Article::with_published(url.to_string(), title.to_string(), item.published);
 
         {
             let mut cur_tot_cnt = counters.total_count.mutex.lock().unwrap();
-            while *cur_tot_cnt > MAX_THREADS_TOTAL - 1 {
+            while *cur_tot_cnt >= config.max_threads_total {
                 cur_tot_cnt = counters.total_count.condvar.wait(cur_tot_cnt).unwrap();
             }
             *cur_tot_cnt += 1;
         }
 
+        let site_key = config.site_key_of(&site);
         {
+            // only read the count while waiting, so that the one increment below is always paired
+            // with the one decrement in the article thread
             let mut cur_sites_map = counters.sites_count.mutex.lock().unwrap();
-            let mut cur_sites_cnt = *cur_sites_map.entry(site.to_string()).or_insert(0);
-            while cur_sites_cnt > MAX_THREADS_SITES - 1 {
+            while cur_sites_map.get(&site_key).copied().unwrap_or(0) >= config.max_threads_sites {
                 cur_sites_map = counters.sites_count.condvar.wait(cur_sites_map).unwrap();
-                cur_sites_cnt = *cur_sites_map.entry(site.to_string()).or_insert(0);
             }
-            *cur_sites_map.entry(site.to_string()).or_insert(0) += 1;
+            *cur_sites_map.entry(site_key.clone()).or_insert(0) += 1;
         }
 
-        let index = Arc::clone(&index);
+        let articles = Arc::clone(&articles);
+        let errors = Arc::clone(&errors);
+        let throttle = Arc::clone(&throttle);
         let url = url.to_string();
         let title = title.to_string();
         let site = site.to_string();
         let counters2 = Arc::clone(&counters);
-        let site2 = site.clone();
+        let config = Arc::clone(&config);
+        let fetcher = Arc::clone(&fetcher);
+        let site2 = site_key;
+        let feed_url = feed_url.to_string();
+        let summary = item.summary.clone();
 
         let handle = thread::spawn(move || {
-            {
-                let article_words = process_article(&article).unwrap();
-                index.lock().unwrap().add(
-                    site.to_string(),
-                    title.to_string(),
-                    url.to_string(),
-                    article_words,
-                );
+            let summary = summary.as_deref();
+            match article_words(&article, summary, fetcher.as_ref(), &throttle, &config) {
+                Ok(mut article_words) => {
+                    article_words.feed = Some(feed_url);
+                    articles.add_article(
+                        site.to_string(),
+                        title.to_string(),
+                        url.to_string(),
+                        article_words,
+                    )
+                }
+                Err(e) => {
+                    warn!("Skipping article: {} [{}]: {}", title, url, e);
+                    errors.lock().unwrap().push((url.to_string(), e.into()));
+                }
             }
 
             {
@@ -181,8 +296,11 @@ fn process_feed(
 
             {
                 let mut cur_sites_map = counters2.sites_count.mutex.lock().unwrap();
-                *cur_sites_map.entry(site2.to_string()).or_insert(0) -= 1;
-                counters2.sites_count.condvar.notify_one();
+                let cur_sites_cnt = cur_sites_map.entry(site2.to_string()).or_insert(0);
+                debug_assert!(*cur_sites_cnt > 0, "site counter underflow for {}", site2);
+                *cur_sites_cnt = cur_sites_cnt.saturating_sub(1);
+                // threads waiting on other sites share this condvar, so wake all of them
+                counters2.sites_count.condvar.notify_all();
             }
         });
 
@@ -193,3 +311,218 @@ fn process_feed(
     }
     Result::Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::time::Duration;
+
+    #[test]
+    fn one_thread_everywhere_still_indexes_everything() {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/a1", "apple"), ("/a2", "apricot")]);
+        let feed_b = server.feed("/b.xml", &[("/b1", "banana")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_a, feed_b]));
+        let config = IndexConfig::builder()
+            .max_threads_feeds(1)
+            .max_threads_sites(1)
+            .max_threads_total(1)
+            .build();
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feed_file_with_config(&file, Arc::clone(&index), config).unwrap();
+
+        assert!(errors.is_empty());
+        let index = index.lock().unwrap();
+        let expected = vec![server.url("/a1"), server.url("/a2"), server.url("/b1")];
+        assert_eq!(urls(&index), expected);
+        assert_eq!(index.search("banana")[0].0.url, server.url("/b1"));
+    }
+
+    #[test]
+    fn articles_are_filed_under_their_own_host_rather_than_the_feed_one() {
+        let server = MockServer::start();
+        server.route("/local", MockResponse::html("elsewhere"));
+        server.route("/same", MockResponse::html("at home"));
+        let items = vec![
+            item("Local", &server.localhost_url("/local")),
+            item("Same", &server.url("/same")),
+        ];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        process_feed_file_with_config(&file, Arc::clone(&index), IndexConfig::default()).unwrap();
+
+        let index = index.lock().unwrap();
+        let mut sites = index
+            .iter()
+            .map(|(site, article)| (site.to_string(), article.title))
+            .collect::<Vec<_>>();
+        sites.sort();
+        let expected = vec![
+            ("127.0.0.1".to_string(), "Same".to_string()),
+            ("localhost".to_string(), "Local".to_string()),
+        ];
+        assert_eq!(sites, expected);
+        assert_eq!(index.articles_for_site("localhost"), 1);
+    }
+
+    #[test]
+    fn many_articles_of_one_site_respect_the_site_limit() {
+        let server = MockServer::start();
+        let mut items = Vec::new();
+        for n in 0..40 {
+            let path = format!("/post{}", n);
+            let page = MockResponse::html("same site").with_delay(Duration::from_millis(5));
+            server.route(&path, page);
+            items.push(item(&path, &server.url(&path)));
+        }
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+        let config = IndexConfig::builder().max_threads_sites(2).build();
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feed_file_with_config(&file, Arc::clone(&index), config).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(index.lock().unwrap().article_count(), 40);
+        assert!(server.max_in_flight("127.0.0.1") <= 2);
+    }
+
+    #[test]
+    fn subdomains_share_the_site_limit_when_sites_are_domains() {
+        let feed_url = "http://feeds.test/feed.xml";
+        let mut items = Vec::new();
+        let mut fetcher = MapFetcher::with_delay(Duration::from_millis(20));
+        for n in 0..3 {
+            for host in &["a.example.com", "b.example.com"] {
+                let url = format!("http://{}/post{}", host, n);
+                items.push(item(&format!("{} {}", host, n), &url));
+                fetcher = fetcher.page(&url, html("subdomain"));
+            }
+        }
+        let fetcher = Arc::new(fetcher.page(feed_url, rss(&items)));
+        let crawl = |site_key| {
+            let config = IndexConfig::builder()
+                .max_threads_sites(1)
+                .site_key(site_key)
+                .build();
+            let index = Arc::new(Mutex::new(ArticleIndex::new()));
+            let feeds = feed_list(&[feed_url.to_string()]);
+            let shared: SharedFetcher = fetcher.clone();
+            let errors = process_feed_reader(feeds.as_bytes(), Arc::clone(&index), config, shared);
+            assert!(errors.unwrap().is_empty());
+            assert_eq!(index.lock().unwrap().article_count(), 6);
+        };
+
+        crawl(SiteKey::Domain);
+        assert_eq!(fetcher.max_in_flight(), 1);
+        crawl(SiteKey::Host);
+        assert_eq!(fetcher.max_in_flight(), 2);
+    }
+
+    #[test]
+    fn an_unreachable_article_is_reported_and_its_sibling_indexed() {
+        let server = MockServer::start();
+        server.route("/good", MockResponse::html("reachable"));
+        let bad = unreachable_url("/bad");
+        let items = vec![item("Bad", &bad), item("Good", &server.url("/good"))];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+        let config = IndexConfig::builder().max_retries(0).build();
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feed_file_with_config(&file, Arc::clone(&index), config).unwrap();
+
+        assert_eq!(urls(&index.lock().unwrap()), vec![server.url("/good")]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, bad);
+        assert!(matches!(errors[0].1, RssIndexError::RequestError { .. }));
+    }
+
+    #[test]
+    fn a_feed_without_a_link_is_reported_and_the_rest_indexed() {
+        let server = MockServer::start();
+        let feed_url = server.feed("/feed.xml", &[("/post", "still indexed")]);
+        let feeds = rss(&[
+            "<item><title>Linkless</title></item>".to_string(),
+            item("Feed", &feed_url),
+        ]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feeds);
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feed_file(&file, Arc::clone(&index)).unwrap();
+
+        assert_eq!(urls(&index.lock().unwrap()), vec![server.url("/post")]);
+        assert_eq!(errors.len(), 1);
+        let missing = &errors[0].1;
+        assert!(
+            matches!(missing, RssIndexError::MissingLink { feed_title } if feed_title == "Linkless")
+        );
+    }
+
+    #[test]
+    fn an_article_in_feeds_processed_at_once_is_indexed_once() {
+        let server = MockServer::start();
+        server.route("/shared", MockResponse::html("contested"));
+        let mut feeds = Vec::new();
+        for n in 0..4 {
+            let path = format!("/feed{}.xml", n);
+            let items = vec![item("Shared", &server.url("/shared"))];
+            // every feed answers at about the same time, so their items are handled at once
+            let response = MockResponse::rss(rss(&items)).with_delay(Duration::from_millis(50));
+            server.route(&path, response);
+            feeds.push(server.url(&path));
+        }
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&feeds));
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feed_file(&file, Arc::clone(&index)).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(server.hits("/shared"), 1);
+        assert_eq!(index.lock().unwrap().search("contested").len(), 1);
+    }
+
+    #[test]
+    fn a_dry_run_lists_articles_up_to_the_cap_without_fetching_them() {
+        capture_logs();
+        let server = MockServer::start();
+        let articles = [
+            ("/1", "one"),
+            ("/2", "two"),
+            ("/3", "three"),
+            ("/4", "four"),
+        ];
+        let feed_url = server.feed("/feed.xml", &articles);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url]));
+        let config = IndexConfig::builder()
+            .dry_run(true)
+            .max_articles_per_feed(2)
+            .build();
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feed_file_with_config(&file, Arc::clone(&index), config).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(index.lock().unwrap().article_count(), 0);
+        for (path, _) in &articles {
+            assert_eq!(server.hits(path), 0);
+        }
+        let listed = |path| {
+            let message = format!("Would process article: {} [{}]", path, server.url(path));
+            logs().iter().any(|(_, logged)| *logged == message)
+        };
+        assert!(listed("/1") && listed("/2"));
+        assert!(!listed("/3") && !listed("/4"));
+    }
+}
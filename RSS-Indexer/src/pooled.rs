@@ -1,100 +1,2147 @@
-use rss::Channel;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::BufReader;
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Write};
+#[cfg(feature = "interrupt")]
+use std::path::Path;
 use std::result::Result;
 
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use url::Url;
 
 use crate::common::*;
+#[cfg(feature = "metrics")]
+use crate::metrics::{metrics, ActiveWorker, Metrics};
 use crate::threadpool::*;
 
-/// Thread pool sizes.
-const SIZE_FEEDS_POOL: usize = 3; //3
-const SIZE_SITES_POOL: usize = 20; //20
-
 /// Same as the single/multi threaded version, but using a thread pool. Set up two thread pools:
-/// one for handling feeds, and one for handling articles. Use the sizes above. Push closures
-/// executing `process_feed` into the thread pool.
-pub fn process_feed_file(file_name: &str, index: Arc<Mutex<ArticleIndex>>) -> RssIndexResult<()> {
-    // todo!()
-    let file = File::open(file_name)?;
-    println!("Processing feed file: {}", file_name);
+/// one for handling feeds, and one for handling articles, with the default sizes. Push closures
+/// executing `process_feed` into the thread pool. Returns once every feed and article job has
+/// finished, so the index is complete, with the feeds and articles that were skipped because of an
+/// error.
+pub fn process_feed_file(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+) -> RssIndexResult<ItemErrors> {
+    process_feed_file_with_config(file_name, index, IndexConfig::default())
+}
+
+/// Same as `process_feed_file`, but with the pool sizes taken from `config`.
+pub fn process_feed_file_with_config(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+) -> RssIndexResult<ItemErrors> {
+    let file = open_feed_file(file_name)?;
+    info!("Processing feed file: {}", file_name);
+
+    process_feed_reader(file, index, config)
+}
+
+/// Crawl the feeds listed in `file_name` with `config` and the optional parts in `options`.
+fn process_feed_file_with_options(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+    options: CrawlOptions,
+) -> RssIndexResult<ItemErrors> {
+    let file = open_feed_file(file_name)?;
+    info!("Processing feed file: {}", file_name);
+
+    process_feeds(stream_feed(file), index, config, options)
+}
+
+/// Same as `process_feed_file`, but the feed of feeds is read from `reader` instead of a file.
+pub fn process_feed_reader<R: Read>(
+    reader: R,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+) -> RssIndexResult<ItemErrors> {
+    process_feeds(stream_feed(reader), index, config, CrawlOptions::default())
+}
+
+/// Same as `process_feed_file`, but the feed of feeds is given as a string of XML.
+pub fn process_feed_str(
+    xml: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+) -> RssIndexResult<ItemErrors> {
+    process_feed_reader(xml.as_bytes(), index, config)
+}
+
+/// Index the feeds listed in `file_name` with the settings taken from `config`, and return the
+/// finished index. Feeds and articles that fail are logged and left out.
+pub fn index_feed_file(file_name: &str, config: IndexConfig) -> RssIndexResult<ArticleIndex> {
+    let index = Arc::new(Mutex::new(ArticleIndex::new()));
+    process_feed_file_with_config(file_name, Arc::clone(&index), config)?;
+    // all jobs have finished, so nothing else holds on to the index any more
+    let index = std::mem::replace(&mut *index.lock().unwrap(), ArticleIndex::new());
+    Result::Ok(index)
+}
+
+/// Same as `process_feed_file_with_config`, but also returns counts of the feeds and articles that
+/// were processed, skipped, or failed.
+pub fn process_feed_file_with_stats(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+) -> RssIndexResult<(CrawlStats, ItemErrors)> {
+    let counters = Arc::new(CrawlCounters::default());
+    let options = CrawlOptions {
+        counters: Arc::clone(&counters),
+        ..CrawlOptions::default()
+    };
+    let errors = process_feed_file_with_options(file_name, index, config, options)?;
+    Result::Ok((counters.stats(), errors))
+}
+
+/// Same as `process_feed_file_with_config`, but feeds and articles are fetched with `fetcher`
+/// instead of over HTTP.
+pub fn process_feed_file_with_fetcher(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+    fetcher: SharedFetcher,
+) -> RssIndexResult<ItemErrors> {
+    let options = CrawlOptions {
+        fetcher: Some(fetcher),
+        ..CrawlOptions::default()
+    };
+    process_feed_file_with_options(file_name, index, config, options)
+}
+
+/// Same as `process_feed_file_with_config`, but the crawl stops early once `cancel` is cancelled.
+/// Articles indexed until then stay in the index.
+pub fn process_feed_file_with_cancel(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+    cancel: CancellationToken,
+) -> RssIndexResult<ItemErrors> {
+    let options = CrawlOptions {
+        cancel,
+        ..CrawlOptions::default()
+    };
+    process_feed_file_with_options(file_name, index, config, options)
+}
+
+/// Same as `index_feed_file`, but a Ctrl-C (SIGINT) stops the crawl early, as if a cancellation
+/// token passed to `process_feed_file_with_cancel` had been cancelled. Either way, once the jobs
+/// in flight have finished, the index is written to `save_path` with `ArticleIndex::save`, so it
+/// is never left half-written. The handler can only be installed once per process.
+#[cfg(feature = "interrupt")]
+pub fn run_with_ctrlc(
+    file_name: &str,
+    config: IndexConfig,
+    save_path: &Path,
+) -> RssIndexResult<ItemErrors> {
+    let cancel = CancellationToken::new();
+    let on_interrupt = cancel.clone();
+    ctrlc::set_handler(move || {
+        warn!("Interrupted, finishing the jobs in flight before saving the index");
+        on_interrupt.cancel();
+    })?;
+    index_and_save(file_name, config, save_path, cancel)
+}
+
+/// Index the feeds listed in `file_name` until `cancel` is cancelled, and save the index to
+/// `save_path`.
+#[cfg(feature = "interrupt")]
+fn index_and_save(
+    file_name: &str,
+    config: IndexConfig,
+    save_path: &Path,
+    cancel: CancellationToken,
+) -> RssIndexResult<ItemErrors> {
+    let index = Arc::new(Mutex::new(ArticleIndex::new()));
+    let errors = process_feed_file_with_cancel(file_name, Arc::clone(&index), config, cancel)?;
+    info!("Saving index to {}", save_path.display());
+    index.lock().unwrap().save(save_path)?;
+    Result::Ok(errors)
+}
+
+/// Same as `process_feed_file_with_config`, but `on_event` is called as feeds and articles are
+/// processed.
+pub fn process_feed_file_with_progress<F>(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+    on_event: F,
+) -> RssIndexResult<ItemErrors>
+where
+    F: Fn(ProgressEvent) + Send + Sync + 'static,
+{
+    let options = CrawlOptions {
+        on_event: Arc::new(on_event),
+        ..CrawlOptions::default()
+    };
+    process_feed_file_with_options(file_name, index, config, options)
+}
+
+/// Same as `process_feed_file`, but articles whose URLs are in `seen` are skipped, and every
+/// article URL processed is added to it. Passing the same set (e.g. through `load_seen_urls` and
+/// `save_seen_urls`) to later runs only indexes what is new since then.
+pub fn process_feed_file_resumable(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    seen: &mut HashSet<String>,
+) -> RssIndexResult<ItemErrors> {
+    process_feed_file_resumable_with_config(file_name, index, seen, IndexConfig::default())
+}
+
+/// Same as `process_feed_file_resumable`, but with the pool sizes taken from `config`.
+pub fn process_feed_file_resumable_with_config(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    seen: &mut HashSet<String>,
+    config: IndexConfig,
+) -> RssIndexResult<ItemErrors> {
+    let urls = Arc::new(SeenUrls::new(std::mem::take(seen), &config));
+    let options = CrawlOptions {
+        urls: Some(Arc::clone(&urls)),
+        ..CrawlOptions::default()
+    };
+    let result = process_feed_file_with_options(file_name, index, config, options);
+    // all jobs have finished, so nothing else holds on to the set any more
+    *seen = urls.take();
+    result
+}
+
+/// Same as `process_feed_file_with_config`, but feeds are fetched with conditional requests using
+/// the validators in `cache`, and feeds the server reports as unchanged are skipped. The
+/// validators of changed feeds are stored in `cache`, which can be kept across runs with
+/// `FeedCache::save` and `FeedCache::load`.
+pub fn process_feed_file_cached(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    cache: &mut FeedCache,
+    config: IndexConfig,
+) -> RssIndexResult<ItemErrors> {
+    let shared = Arc::new(Mutex::new(std::mem::take(cache)));
+    let options = CrawlOptions {
+        cache: Some(Arc::clone(&shared)),
+        ..CrawlOptions::default()
+    };
+    let result = process_feed_file_with_options(file_name, index, config, options);
+    // all jobs have finished, so nothing else holds on to the cache any more
+    *cache = std::mem::take(&mut *shared.lock().unwrap());
+    result
+}
+
+/// Same as `process_feed_file`, but for several feed files at once. All files share the same pools
+/// and the same set of seen URLs, so thread limits hold across files and an article listed in two
+/// files is only indexed once.
+pub fn process_feed_files(
+    file_names: &[&str],
+    index: Arc<Mutex<ArticleIndex>>,
+) -> RssIndexResult<ItemErrors> {
+    process_feed_files_with_config(file_names, index, IndexConfig::default())
+}
+
+/// Same as `process_feed_files`, but with the pool sizes taken from `config`.
+pub fn process_feed_files_with_config(
+    file_names: &[&str],
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+) -> RssIndexResult<ItemErrors> {
+    let mut files = Vec::new();
+    for file_name in file_names {
+        files.push(open_feed_file(file_name)?);
+        info!("Processing feed file: {}", file_name);
+    }
+    let feeds = files.into_iter().flat_map(stream_feed);
+    process_feeds(feeds, index, config, CrawlOptions::default())
+}
+
+/// Same as `process_feed_file`, but the feeds are listed in an OPML file, as exported by most
+/// feed readers.
+pub fn process_opml_file(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+) -> RssIndexResult<ItemErrors> {
+    process_opml_file_with_config(file_name, index, IndexConfig::default())
+}
+
+/// Same as `process_opml_file`, but with the pool sizes taken from `config`.
+pub fn process_opml_file_with_config(
+    file_name: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+) -> RssIndexResult<ItemErrors> {
+    let file = open_feed_file(file_name)?;
+    info!("Processing OPML file: {}", file_name);
+
+    let feeds = read_opml(file)?;
+    let feeds = feeds.into_iter().map(Result::Ok);
+    process_feeds(feeds, index, config, CrawlOptions::default())
+}
+
+/// Same as `process_feed_file`, but the list of feeds (an OPML document or an RSS/Atom feed of
+/// feeds) is downloaded from `url` with the configured client.
+pub fn process_feed_url(url: &str, index: Arc<Mutex<ArticleIndex>>) -> RssIndexResult<ItemErrors> {
+    process_feed_url_with_config(url, index, IndexConfig::default())
+}
+
+/// Same as `process_feed_url`, but with the pool sizes and HTTP settings taken from `config`.
+pub fn process_feed_url_with_config(
+    url: &str,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+) -> RssIndexResult<ItemErrors> {
+    info!("Processing feed list: {}", url);
+    // the crawl goes on with the same client, so its connections to the list's host are reused
+    let fetcher: SharedFetcher = Arc::new(HttpFetcher::new(&config)?);
+    let contents = fetcher.fetch(url)?;
+    let feeds =
+        parse_feed_list(&contents).map_err(|_| RssIndexError::FeedListError(url.to_string()))?;
+    let options = CrawlOptions {
+        fetcher: Some(fetcher),
+        ..CrawlOptions::default()
+    };
+    process_feeds(feeds.into_iter().map(Result::Ok), index, config, options)
+}
+
+/// Same as `process_feed_file_with_config`, but instead of building an index, each article is
+/// written to `writer` as soon as it has been processed, as one line of JSON:
+/// `{"url": ..., "title": ..., "site": ..., "words": {<word>: <count>, ...}}`.
+pub fn process_feed_file_streaming<W>(
+    file_name: &str,
+    writer: Arc<Mutex<W>>,
+    config: IndexConfig,
+) -> RssIndexResult<ItemErrors>
+where
+    W: Write + Send + 'static,
+{
+    let options = CrawlOptions {
+        sink: Some(writer),
+        ..CrawlOptions::default()
+    };
+    // nothing is added to the index when streaming
+    let index = Arc::new(Mutex::new(ArticleIndex::new()));
+    process_feed_file_with_options(file_name, index, config, options)
+}
+
+/// Optional parts of a crawl. By default, a crawl starts with an empty set of seen URLs, reports
+/// no progress, and adds articles to the index.
+struct CrawlOptions {
+    /// If set, articles whose URLs are in here are skipped.
+    urls: Option<Arc<SeenUrls>>,
+    on_event: ProgressFn,
+    /// If set, articles are written here as JSON lines instead of being added to the index.
+    sink: Option<Arc<Mutex<dyn Write + Send>>>,
+    /// If set, feeds are fetched with conditional requests, and unchanged feeds are skipped.
+    cache: Option<Arc<Mutex<FeedCache>>>,
+    counters: Arc<CrawlCounters>,
+    /// If set, feeds and articles are fetched with this instead of an `HttpFetcher`.
+    fetcher: Option<SharedFetcher>,
+    cancel: CancellationToken,
+}
+
+impl Default for CrawlOptions {
+    fn default() -> Self {
+        CrawlOptions {
+            urls: None,
+            on_event: Arc::new(|_| {}),
+            sink: None,
+            cache: None,
+            counters: Arc::new(CrawlCounters::default()),
+            fetcher: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+}
 
-    let mut feeds_pool = ThreadPool::new(SIZE_FEEDS_POOL);
-    let sites_pool = Arc::new(Mutex::new(ThreadPool::new(SIZE_SITES_POOL)));
+/// The counters behind `CrawlStats`, incremented from the worker threads. With the `metrics`
+/// feature, the counters of `metrics` (by default the process's, see `metrics()`) are incremented
+/// along with them.
+struct CrawlCounters {
+    feeds_processed: AtomicUsize,
+    articles_indexed: AtomicUsize,
+    duplicates_skipped: AtomicUsize,
+    errors: AtomicUsize,
+    #[cfg(feature = "metrics")]
+    metrics: &'static Metrics,
+}
+
+impl Default for CrawlCounters {
+    fn default() -> Self {
+        CrawlCounters {
+            feeds_processed: AtomicUsize::new(0),
+            articles_indexed: AtomicUsize::new(0),
+            duplicates_skipped: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            #[cfg(feature = "metrics")]
+            metrics: metrics(),
+        }
+    }
+}
+
+impl CrawlCounters {
+    /// Counters updating `metrics` instead of those of the process.
+    #[cfg(feature = "metrics")]
+    fn with_metrics(metrics: &'static Metrics) -> Self {
+        CrawlCounters {
+            metrics,
+            ..CrawlCounters::default()
+        }
+    }
+
+    /// Add one to `counter`. The counters are only read once all jobs have been joined, which
+    /// synchronizes with every increment, so no ordering is needed here.
+    fn bump(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn feed_processed(&self) {
+        CrawlCounters::bump(&self.feeds_processed);
+        #[cfg(feature = "metrics")]
+        self.metrics.feeds_processed.inc();
+    }
 
-    let channel = Channel::read_from(BufReader::new(file))?;
-    let urls = Arc::new(Mutex::new(HashSet::new()));
+    fn article_indexed(&self) {
+        CrawlCounters::bump(&self.articles_indexed);
+        #[cfg(feature = "metrics")]
+        self.metrics.articles_indexed.inc();
+    }
+
+    fn duplicate_skipped(&self) {
+        CrawlCounters::bump(&self.duplicates_skipped);
+    }
+
+    fn error(&self) {
+        CrawlCounters::bump(&self.errors);
+        #[cfg(feature = "metrics")]
+        self.metrics.fetch_errors.inc();
+    }
+
+    fn stats(&self) -> CrawlStats {
+        CrawlStats {
+            feeds_processed: self.feeds_processed.load(Ordering::Relaxed),
+            articles_indexed: self.articles_indexed.load(Ordering::Relaxed),
+            duplicates_skipped: self.duplicates_skipped.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
 
-    for feed in channel.into_items() {
-        let url = feed.link().ok_or(RssIndexError::UrlError)?;
-        let title = feed.title().ok_or(RssIndexError::UrlError)?;
+/// An article as written by `process_feed_file_streaming`.
+#[derive(Serialize)]
+struct ArticleRecord<'a> {
+    url: &'a str,
+    title: &'a str,
+    site: &'a str,
+    words: &'a BTreeMap<String, u32>,
+}
+
+/// Source of the ids that tell the buffers of different crawls apart.
+static NEXT_CRAWL_ID: AtomicUsize = AtomicUsize::new(0);
 
-        if urls.lock().unwrap().contains(url) {
-            println!("Skipping already seen feed: {} [{}]", title, url);
+thread_local! {
+    /// The articles this worker has indexed but not yet flushed, with the id of their crawl.
+    static BUFFER: RefCell<Option<(usize, Arc<Mutex<ArticleIndex>>)>> = const { RefCell::new(None) };
+}
+
+/// State shared by the feed and article jobs of one crawl.
+struct Crawl {
+    id: usize,
+    articles: Mutex<ArticleIndex>,
+    buffers: Mutex<Vec<Arc<Mutex<ArticleIndex>>>>,
+    /// Article URLs skipped as already seen, with the URL of the feed that listed them again.
+    sources: Mutex<Vec<(String, String)>>,
+    urls: Arc<SeenUrls>,
+    errors: Mutex<ItemErrors>,
+    throttle: HostThrottle,
+    fetcher: SharedFetcher,
+    config: IndexConfig,
+    on_event: ProgressFn,
+    sink: Option<Arc<Mutex<dyn Write + Send>>>,
+    cache: Option<Arc<Mutex<FeedCache>>>,
+    counters: Arc<CrawlCounters>,
+    cancel: CancellationToken,
+}
+
+impl Crawl {
+    /// Report an event to the progress callback.
+    fn report(&self, event: ProgressEvent) {
+        (self.on_event)(event);
+    }
+
+    /// The buffer of the current worker for this crawl, registered with the crawl the first time.
+    fn buffer(&self) -> Arc<Mutex<ArticleIndex>> {
+        BUFFER.with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            match &*buffer {
+                Some((id, articles)) if *id == self.id => Arc::clone(articles),
+                _ => {
+                    let articles = Arc::new(Mutex::new(ArticleIndex::new()));
+                    self.buffers.lock().unwrap().push(Arc::clone(&articles));
+                    *buffer = Some((self.id, Arc::clone(&articles)));
+                    articles
+                }
+            }
+        })
+    }
+
+    /// Add a processed article to the current worker's buffer, and move the buffer into the
+    /// crawl's index once it holds `flush_interval` articles. Only the worker itself uses its
+    /// buffer until the crawl is over, so its lock is never contended.
+    fn buffer_article(&self, site: String, title: String, url: String, words: ArticleWords) {
+        let buffer = self.buffer();
+        let mut buffer = buffer.lock().unwrap();
+        buffer.add_article(site, title, url, words);
+        if buffer.article_count() >= self.config.flush_interval {
+            let articles = std::mem::replace(&mut *buffer, ArticleIndex::new());
+            self.articles.lock().unwrap().absorb(articles);
+        }
+    }
+
+    /// Add a processed article to the index, or write it to the sink if there is one.
+    fn add_article(
+        &self,
+        site: String,
+        title: String,
+        url: String,
+        mut words: ArticleWords,
+    ) -> RssIndexResult<()> {
+        let sink = match &self.sink {
+            Some(sink) => sink,
+            None => {
+                self.buffer_article(site, title, url, words);
+                return Result::Ok(());
+            }
+        };
+        let url = words.url.take().unwrap_or(url);
+        let record = ArticleRecord {
+            url: &url,
+            title: &title,
+            site: &site,
+            words: &words.counts.counts,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        // write the whole line under one lock, so that lines of different articles don't mix
+        let mut sink = sink.lock().unwrap();
+        sink.write_all(&line)?;
+        sink.flush()?;
+        Result::Ok(())
+    }
+
+    /// Count and report a feed or article that failed, and return it as an entry of the errors.
+    fn failed(&self, url: &str, e: Box<dyn std::error::Error>) -> (String, RssIndexError) {
+        self.counters.error();
+        self.report(ProgressEvent::ItemSkipped {
+            url: url.to_string(),
+        });
+        (url.to_string(), e.into())
+    }
+
+    /// Record a feed or article that failed.
+    fn fail(&self, url: &str, e: Box<dyn std::error::Error>) {
+        let failed = self.failed(url, e);
+        self.errors.lock().unwrap().push(failed);
+    }
+}
+
+/// Push closures executing `process_feed` for each of `feeds` into a feeds pool, and wait until
+/// every feed and article job has finished. Feeds are pushed as `feeds` yields them, so with a
+/// streamed feed file, the first feeds are being crawled while the rest of it is still read. If
+/// `feeds` fails partway, the feeds before are still crawled, and then the error is returned.
+/// Articles whose URLs are in `options.urls` are skipped. Returns the feeds and articles that
+/// failed.
+fn process_feeds<I>(
+    feeds: I,
+    index: Arc<Mutex<ArticleIndex>>,
+    config: IndexConfig,
+    options: CrawlOptions,
+) -> RssIndexResult<ItemErrors>
+where
+    I: IntoIterator<Item = RssIndexResult<FeedItem>>,
+{
+    let feeds_pool = ThreadPool::named(config.feeds_pool_size.max(1), "rss-feeds-worker");
+    // the sites pool's queue is bounded, so feed jobs block in `execute` once it is full. They
+    // push into it through a shared reference, never while holding a lock, and article jobs never
+    // wait on feed jobs, so even with every feed worker blocked, the article workers still drain
+    // the queue and unblock them.
+    let sites_pool = Arc::new(ThreadPool::named_with_capacity(
+        config.sites_pool_size.max(1),
+        config.sites_queue_size,
+        "rss-sites-worker",
+    ));
+
+    let fetcher: SharedFetcher = match options.fetcher {
+        Some(fetcher) => fetcher,
+        None => Arc::new(HttpFetcher::new(&config)?),
+    };
+    let crawl = Arc::new(Crawl {
+        id: NEXT_CRAWL_ID.fetch_add(1, Ordering::Relaxed),
+        articles: Mutex::new(ArticleIndex::new()),
+        buffers: Mutex::new(Vec::new()),
+        sources: Mutex::new(Vec::new()),
+        urls: options
+            .urls
+            .unwrap_or_else(|| Arc::new(SeenUrls::new(HashSet::new(), &config))),
+        errors: Mutex::new(Vec::new()),
+        throttle: HostThrottle::new(),
+        fetcher,
+        config,
+        on_event: options.on_event,
+        sink: options.sink,
+        cache: options.cache,
+        counters: options.counters,
+        cancel: options.cancel,
+    });
+
+    // feeds are deduplicated separately, so that a persisted set of seen articles never causes a
+    // feed to be skipped on a later run
+    let mut feed_urls = HashSet::new();
+    let mut feed_results = Vec::new();
+    let mut feed_error = None;
+    for feed in feeds {
+        let feed = match feed {
+            Ok(feed) => feed,
+            Err(e) => {
+                feed_error = Some(e);
+                break;
+            }
+        };
+        let (url, title) = match feed.link_and_title() {
+            Ok(link_and_title) => link_and_title,
+            Err(e) => {
+                warn!("Skipping feed: {}", e);
+                crawl.fail(feed.link().unwrap_or_default(), Box::new(e));
+                continue;
+            }
+        };
+
+        if !feed_urls.insert(canonical_url(url, &crawl.config)) {
+            debug!("Skipping already seen feed: {} [{}]", title, url);
+            crawl.counters.duplicate_skipped();
+            crawl.report(ProgressEvent::ItemSkipped {
+                url: url.to_string(),
+            });
             continue;
         }
-        urls.lock().unwrap().insert(url.to_string());
 
-        println!("Processing feed: {} [{}]", title, url);
+        info!("Processing feed: {} [{}]", title, url);
 
-        let urls = Arc::clone(&urls);
-        let index = Arc::clone(&index);
+        let crawl = Arc::clone(&crawl);
         let sites_pool = Arc::clone(&sites_pool);
         let url = url.to_string();
-        feeds_pool.execute(move || {
-            process_feed(&url, index, urls, sites_pool).unwrap();
-        })
+        // a feed that fails comes back as the job's result, rather than through `crawl.errors`
+        feed_results.push(feeds_pool.execute_with_result(move || {
+            #[cfg(feature = "metrics")]
+            let _active = ActiveWorker::start();
+            if crawl.cancel.is_cancelled() {
+                debug!("Skipping feed [{}]: crawl cancelled", url);
+                crawl.report(ProgressEvent::ItemSkipped { url });
+                return Result::Ok(());
+            }
+            crawl.report(ProgressEvent::FeedStarted { url: url.clone() });
+            match process_feed(&url, &crawl, &sites_pool) {
+                Ok(count) => {
+                    crawl.counters.feed_processed();
+                    crawl.report(ProgressEvent::FeedFinished { url, count });
+                    Result::Ok(())
+                }
+                Err(e) => {
+                    warn!("Skipping feed [{}]: {}", url, e);
+                    Result::Err(crawl.failed(&url, e))
+                }
+            }
+        }));
+    }
+
+    // feed jobs enqueue article jobs, so wait for the feeds first
+    feeds_pool.join();
+    sites_pool.join();
+    info!(
+        "Ran {} feed jobs and {} article jobs",
+        feeds_pool.stats().completed,
+        sites_pool.stats().completed
+    );
+    let mut errors = Vec::new();
+    for result in feed_results {
+        // the sender of a job that panicked is gone without sending anything
+        if let Ok(Err(failed)) = result.recv() {
+            errors.push(failed);
+        }
+    }
+    // article jobs only lock their own buffer and, once per batch, the crawl's index, so the
+    // caller's index is locked just once, here
+    let mut index = index.lock().unwrap();
+    index.absorb(std::mem::replace(
+        &mut *crawl.articles.lock().unwrap(),
+        ArticleIndex::new(),
+    ));
+    for buffer in crawl.buffers.lock().unwrap().drain(..) {
+        index.absorb(std::mem::replace(
+            &mut *buffer.lock().unwrap(),
+            ArticleIndex::new(),
+        ));
+    }
+    index.add_source_feeds(std::mem::take(&mut *crawl.sources.lock().unwrap()));
+    index.set_query_config(&crawl.config);
+    drop(index);
+
+    if let Some(e) = feed_error {
+        return Result::Err(e);
     }
 
-    Result::Ok(())
+    errors.append(&mut crawl.errors.lock().unwrap());
+    // the pools only know the message of a job that panicked, not which feed or article it was
+    for msg in feeds_pool.panics().into_iter().chain(sites_pool.panics()) {
+        crawl.counters.error();
+        errors.push((String::new(), RssIndexError::OtherError(msg)));
+    }
+    Result::Ok(errors)
 }
 
 /// Same as the single/multi threaded version, but using a thread pool. Push closures executing
-/// `process_article` into the thread pool that is passed in.
-fn process_feed(
-    url: &str,
-    index: Arc<Mutex<ArticleIndex>>,
-    urls: Arc<Mutex<HashSet<String>>>,
-    sites_pool: Arc<Mutex<ThreadPool>>,
-) -> RssIndexResult<()> {
-    // todo!()
-    let contents = reqwest::blocking::get(url)?.bytes()?;
-    let channel = Channel::read_from(&contents[..])?;
-    let items = channel.into_items();
+/// `process_article` into the thread pool that is passed in, all at once with `execute_all` after
+/// reading the feed. Each further article of a feed gets a lower priority, so that feeds with many
+/// articles don't starve the others. Returns the number of articles queued.
+fn process_feed(url: &str, crawl: &Arc<Crawl>, sites_pool: &ThreadPool) -> RssIndexResult<usize> {
+    let config = &crawl.config;
+    let contents = match &crawl.cache {
+        Some(cache) => match crawl.fetcher.fetch_if_modified(url, cache)? {
+            Some(contents) => contents,
+            None => {
+                debug!("Skipping unchanged feed [{}]", url);
+                return Result::Ok(0);
+            }
+        },
+        None => crawl.fetcher.fetch_feed(url)?,
+    };
+    let items = parse_feed_at(&contents, url)?;
+    let feed_url = url;
+    let mut priority = DEFAULT_PRIORITY;
+    let mut count = 0;
+    let mut jobs: Vec<(u8, Box<dyn FnOnce() + Send>)> = Vec::new();
     for item in items {
-        let (url, site, title) = match (item.link(), Url::parse(&url)?.host_str(), item.title()) {
-            (Some(u), Some(s), Some(t)) => (u, s.to_string(), t),
+        if crawl.cancel.is_cancelled() {
+            debug!("Skipping remaining articles: crawl cancelled");
+            break;
+        }
+        let (url, site, title) = match (item.link(), item.site(), item.title()) {
+            (Some(u), Some(s), Some(t)) => (u, s, t),
             _ => continue,
         };
+        let skipped = ProgressEvent::ItemSkipped {
+            url: url.to_string(),
+        };
 
-        if urls.lock().unwrap().contains(url) {
-            println!("Skipping already seen article: {} [{}]", title, url);
+        if is_self_link(url, feed_url, config) {
+            debug!("Skipping link back to the feed: {} [{}]", title, url);
+            crawl.report(skipped);
             continue;
         }
-        urls.lock().unwrap().insert(url.to_string());
 
-        println!("Processing article: {} [{}]", title, url);
+        if !config.wants_item(&item) {
+            debug!("Skipping old article: {} [{}]", title, url);
+            crawl.report(skipped);
+            continue;
+        }
 
-        let article = Article::new(url.to_string(), title.to_string());
+        if config.feed_is_full(count) {
+            debug!("Skipping remaining articles: per-feed limit reached");
+            crawl.report(skipped);
+            break;
+        }
 
-        let sites_pool = Arc::clone(&sites_pool);
-        let mut sites_pool = sites_pool.lock().unwrap();
-        let index = Arc::clone(&index);
+        // check and insert under one lock, so that no other thread can slip in between. A dry run
+        // only looks the article up, so that a resumed run still indexes it.
+        let key = dedup_key(&item, url, feed_url, config);
+        let seen = if config.dry_run {
+            crawl.urls.contains(&key)
+        } else {
+            !crawl.urls.insert(key.clone())
+        };
+        if seen {
+            debug!("Skipping already seen article: {} [{}]", title, url);
+            let source = (url.to_string(), feed_url.to_string());
+            crawl.sources.lock().unwrap().push(source);
+            crawl.counters.duplicate_skipped();
+            crawl.report(skipped);
+            continue;
+        }
+        count += 1;
+
+        if config.dry_run {
+            info!("Would process article: {} [{}]", title, url);
+            continue;
+        }
+
+        info!("Processing article: {} [{}]", title, url);
+
+        let article = Article::with_published(url.to_string(), title.to_string(), item.published);
+
+        let crawl = Arc::clone(crawl);
 
         let url = url.to_string();
         let title = title.to_string();
-        sites_pool.execute(move || {
-            let article_words = process_article(&article);
-            index.lock().unwrap().add(
-                site.to_string(),
-                title.to_string(),
-                url.to_string(),
-                article_words.unwrap(),
+        let feed_url = feed_url.to_string();
+        let summary = item.summary.clone();
+        let job = move || {
+            #[cfg(feature = "metrics")]
+            let _active = ActiveWorker::start();
+            if crawl.cancel.is_cancelled() {
+                debug!("Skipping article: {} [{}]: crawl cancelled", title, url);
+                // it was never indexed, so a resumed crawl must not skip it
+                crawl.urls.remove(&key);
+                crawl.report(ProgressEvent::ItemSkipped { url });
+                return;
+            }
+            let words = article_words(
+                &article,
+                summary.as_deref(),
+                crawl.fetcher.as_ref(),
+                &crawl.throttle,
+                &crawl.config,
             );
+            let result = words.and_then(|mut article_words| {
+                article_words.feed = Some(feed_url);
+                crawl.add_article(
+                    site.to_string(),
+                    title.to_string(),
+                    url.to_string(),
+                    article_words,
+                )
+            });
+            match result {
+                Ok(()) => {
+                    crawl.counters.article_indexed();
+                    crawl.report(ProgressEvent::ArticleIndexed { url })
+                }
+                Err(e) => {
+                    warn!("Skipping article: {} [{}]: {}", title, url, e);
+                    crawl.fail(&url, e);
+                }
+            }
+        };
+        jobs.push((priority, Box::new(job)));
+        priority = priority.saturating_sub(1);
+    }
+    sites_pool.execute_all_with_priorities(jobs);
+    Result::Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use chrono::{DateTime, Utc};
+    use reqwest::header::{self, HeaderValue};
+    use std::sync::Condvar;
+    use std::time::Duration;
+
+    #[test]
+    fn failed_feeds_are_collected_from_the_feed_jobs() {
+        let good = "http://feeds.test/good.xml";
+        let fetcher = MapFetcher::new()
+            .page(good, rss(&[item("Post", "http://site.test/post")]))
+            .page("http://site.test/post", html("words of the post"));
+        let feeds = vec![
+            Result::Ok(feed(good, "Good")),
+            Result::Ok(feed("http://feeds.test/missing.xml", "Missing")),
+        ];
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let options = CrawlOptions {
+            fetcher: Some(Arc::new(fetcher)),
+            ..CrawlOptions::default()
+        };
+        let errors = process_feeds(feeds, Arc::clone(&index), IndexConfig::default(), options);
+
+        let errors = errors.unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "http://feeds.test/missing.xml");
+        assert!(matches!(errors[0].1, RssIndexError::OtherError(_)));
+        assert_eq!(urls(&index.lock().unwrap()), vec!["http://site.test/post"]);
+    }
+
+    struct PanickingFetcher;
+
+    impl Fetcher for PanickingFetcher {
+        fn fetch(&self, _url: &str) -> RssIndexResult<Vec<u8>> {
+            panic!("fetcher broke")
+        }
+    }
+
+    #[test]
+    fn panicking_jobs_are_reported_as_errors() {
+        let feeds = vec![Result::Ok(feed("http://feeds.test/a.xml", "A"))];
+        let counters = Arc::new(CrawlCounters::default());
+        let options = CrawlOptions {
+            fetcher: Some(Arc::new(PanickingFetcher)),
+            counters: Arc::clone(&counters),
+            ..CrawlOptions::default()
+        };
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feeds(feeds, index, IndexConfig::default(), options).unwrap();
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&errors[0].1, RssIndexError::OtherError(msg) if msg == "fetcher broke"));
+        assert_eq!(counters.stats().errors, 1);
+    }
+
+    #[test]
+    fn failures_are_collected_and_the_workers_keep_going() {
+        let server = MockServer::start();
+        server.route("/missing", MockResponse::status(404));
+        server.route("/good", MockResponse::html("survivor"));
+        // the failing article is queued first, so the only worker runs it before the good one
+        let items = vec![
+            item("Missing", &server.url("/missing")),
+            item("Good", &server.url("/good")),
+        ];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        server.route("/broken.xml", MockResponse::status(500));
+        let dir = TempDir::new();
+        let feeds = [server.url("/broken.xml"), server.url("/feed.xml")];
+        let file = dir.file("feeds.xml", feed_list(&feeds));
+        let config = IndexConfig::builder()
+            .feeds_pool_size(1)
+            .sites_pool_size(1)
+            .max_retries(0)
+            .build();
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feed_file_with_config(&file, Arc::clone(&index), config).unwrap();
+
+        let mut failed = errors
+            .iter()
+            .map(|(url, _)| url.clone())
+            .collect::<Vec<_>>();
+        failed.sort();
+        assert_eq!(
+            failed,
+            vec![server.url("/broken.xml"), server.url("/missing")]
+        );
+        let index = index.lock().unwrap();
+        assert_eq!(index.search("survivor")[0].0.url, server.url("/good"));
+    }
+
+    #[test]
+    fn articles_are_filed_under_their_own_host_rather_than_the_feed_one() {
+        let server = MockServer::start();
+        server.route("/local", MockResponse::html("elsewhere"));
+        server.route("/same", MockResponse::html("at home"));
+        let items = vec![
+            item("Local", &server.localhost_url("/local")),
+            item("Same", &server.url("/same")),
+        ];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        process_feed_file_with_config(&file, Arc::clone(&index), IndexConfig::default()).unwrap();
+
+        let index = index.lock().unwrap();
+        let mut sites = index
+            .iter()
+            .map(|(site, article)| (site.to_string(), article.title))
+            .collect::<Vec<_>>();
+        sites.sort();
+        let expected = vec![
+            ("127.0.0.1".to_string(), "Same".to_string()),
+            ("localhost".to_string(), "Local".to_string()),
+        ];
+        assert_eq!(sites, expected);
+        assert_eq!(index.articles_for_site("localhost"), 1);
+    }
+
+    #[test]
+    fn one_worker_per_pool_still_indexes_everything() {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/a1", "apple"), ("/a2", "apricot")]);
+        let feed_b = server.feed("/b.xml", &[("/b1", "banana")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_a, feed_b]));
+        let config = IndexConfig::builder()
+            .feeds_pool_size(1)
+            .sites_pool_size(1)
+            .build();
+
+        let index = index_feed_file(&file, config).unwrap();
+
+        let expected = vec![server.url("/a1"), server.url("/a2"), server.url("/b1")];
+        assert_eq!(urls(&index), expected);
+    }
+
+    #[test]
+    fn error_pages_are_skipped_and_their_siblings_indexed() {
+        let server = MockServer::start();
+        let feed_url = server.feed("/feed.xml", &[("/ok", "fine")]);
+        server.route("/missing", MockResponse::status(404));
+        server.route("/broken", MockResponse::status(500));
+        let items = vec![
+            item("Missing", &server.url("/missing")),
+            item("Ok", &server.url("/ok")),
+            item("Broken", &server.url("/broken")),
+        ];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url]));
+        let config = IndexConfig::builder().max_retries(0).build();
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feed_file_with_config(&file, Arc::clone(&index), config).unwrap();
+
+        assert_eq!(urls(&index.lock().unwrap()), vec![server.url("/ok")]);
+        let mut statuses = errors
+            .iter()
+            .map(|(url, e)| match e {
+                RssIndexError::HttpStatus { status, .. } => (url.clone(), *status),
+                e => panic!("unexpected error {}", e),
+            })
+            .collect::<Vec<_>>();
+        statuses.sort();
+        let expected = vec![(server.url("/broken"), 500), (server.url("/missing"), 404)];
+        assert_eq!(statuses, expected);
+    }
+
+    #[test]
+    fn atom_feeds_are_indexed() {
+        let feed_url = "http://atom.test/feed";
+        let atom = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+            <title>Atom</title><id>urn:feed</id><updated>2024-01-01T00:00:00Z</updated>
+            <entry><title>Entry</title><id>urn:1</id><updated>2024-01-01T00:00:00Z</updated>
+            <link href="http://atom.test/1"/></entry></feed>"#;
+        let fetcher = MapFetcher::new()
+            .page(feed_url, atom)
+            .page("http://atom.test/1", html("atomic"));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url.to_string()]));
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let config = IndexConfig::default();
+        let fetcher = Arc::new(fetcher);
+        process_feed_file_with_fetcher(&file, Arc::clone(&index), config, fetcher).unwrap();
+
+        let index = index.lock().unwrap();
+        assert_eq!(index.search("atomic")[0].0.url, "http://atom.test/1");
+    }
+
+    #[test]
+    fn feeds_in_opml_folders_are_visited() {
+        let server = MockServer::start();
+        let top = server.feed("/top.xml", &[("/top", "surface")]);
+        let nested = server.feed("/nested.xml", &[("/nested", "deep")]);
+        let opml = format!(
+            r#"<?xml version="1.0"?><opml version="2.0"><head><title>Subs</title></head><body>
+            <outline text="Top" type="rss" xmlUrl="{}"/>
+            <outline text="Folder"><outline text="Nested" type="rss" xmlUrl="{}"/></outline>
+            </body></opml>"#,
+            top, nested
+        );
+        let dir = TempDir::new();
+        let file = dir.file("subs.opml", opml);
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_opml_file(&file, Arc::clone(&index)).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(server.hits("/top.xml"), 1);
+        assert_eq!(server.hits("/nested.xml"), 1);
+        let expected = vec![server.url("/nested"), server.url("/top")];
+        assert_eq!(urls(&index.lock().unwrap()), expected);
+    }
+
+    #[test]
+    fn an_article_in_two_feed_files_is_indexed_once() {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/shared", "common ground"), ("/a", "only a")]);
+        let feed_b = server.feed("/b.xml", &[("/shared", "common ground"), ("/b", "only b")]);
+        let dir = TempDir::new();
+        let file_a = dir.file("a.xml", feed_list(&[feed_a]));
+        let file_b = dir.file("b.xml", feed_list(&[feed_b]));
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feed_files(&[&file_a, &file_b], Arc::clone(&index)).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(server.hits("/shared"), 1);
+        let index = index.lock().unwrap();
+        let expected = vec![server.url("/a"), server.url("/b"), server.url("/shared")];
+        assert_eq!(urls(&index), expected);
+        assert_eq!(index.search("common").len(), 1);
+    }
+
+    #[test]
+    fn a_feed_list_is_fetched_from_a_url() {
+        let server = MockServer::start();
+        let feed_url = server.feed("/feed.xml", &[("/post", "remote subscriptions")]);
+        server.route("/list.xml", MockResponse::rss(feed_list(&[feed_url])));
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feed_url(&server.url("/list.xml"), Arc::clone(&index)).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(urls(&index.lock().unwrap()), vec![server.url("/post")]);
+    }
+
+    #[test]
+    fn a_feed_list_that_is_not_xml_is_an_error() {
+        let server = MockServer::start();
+        server.route(
+            "/list.txt",
+            MockResponse::new(200, "text/plain", "no feeds here"),
+        );
+        let url = server.url("/list.txt");
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let e = process_feed_url(&url, index).unwrap_err();
+
+        let e = e.downcast_ref::<RssIndexError>().unwrap();
+        assert!(matches!(e, RssIndexError::FeedListError(u) if *u == url));
+    }
+
+    #[test]
+    fn an_unreachable_feed_list_is_an_error() {
+        let url = unreachable_url("/list.xml");
+        let config = IndexConfig::builder().max_retries(0).build();
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let e = process_feed_url_with_config(&url, index, config).unwrap_err();
+
+        let e = e.downcast_ref::<RssIndexError>().unwrap();
+        assert!(matches!(e, RssIndexError::RequestError { url: u, .. } if *u == url));
+    }
+
+    #[test]
+    fn a_resumed_run_indexes_nothing_seen_before() {
+        let server = MockServer::start();
+        let feed_url = server.feed("/feed.xml", &[("/one", "first"), ("/two", "second")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url]));
+        let seen_file = dir.path("seen.json");
+
+        let mut seen = load_seen_urls(&seen_file).unwrap();
+        let first = Arc::new(Mutex::new(ArticleIndex::new()));
+        process_feed_file_resumable(&file, Arc::clone(&first), &mut seen).unwrap();
+        save_seen_urls(&seen, &seen_file).unwrap();
+
+        let mut seen = load_seen_urls(&seen_file).unwrap();
+        let second = Arc::new(Mutex::new(ArticleIndex::new()));
+        process_feed_file_resumable(&file, Arc::clone(&second), &mut seen).unwrap();
+
+        let expected = vec![server.url("/one"), server.url("/two")];
+        assert_eq!(urls(&first.lock().unwrap()), expected);
+        assert!(urls(&second.lock().unwrap()).is_empty());
+        assert_eq!(server.hits("/one"), 1);
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn a_dry_run_leaves_its_articles_to_a_resumed_run() {
+        let server = MockServer::start();
+        let feed_url = server.feed("/feed.xml", &[("/one", "first"), ("/two", "second")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url]));
+
+        let mut seen = HashSet::new();
+        let dry = Arc::new(Mutex::new(ArticleIndex::new()));
+        let config = IndexConfig::builder().dry_run(true).build();
+        process_feed_file_resumable_with_config(&file, Arc::clone(&dry), &mut seen, config)
+            .unwrap();
+        assert_eq!(dry.lock().unwrap().article_count(), 0);
+        assert_eq!(server.hits("/one"), 0);
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        process_feed_file_resumable(&file, Arc::clone(&index), &mut seen).unwrap();
+
+        let expected = vec![server.url("/one"), server.url("/two")];
+        assert_eq!(urls(&index.lock().unwrap()), expected);
+        assert_eq!(seen.len(), 2);
+    }
+
+    /// The start of `year`.
+    fn new_year(year: i32) -> DateTime<Utc> {
+        let date = format!("{}-01-01T00:00:00Z", year);
+        DateTime::parse_from_rfc3339(&date)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    /// A crawl with `config` of a feed with an item from 2020, one from 2024 and an undated one.
+    fn crawl_dated_feed(config: IndexConfig) -> ArticleIndex {
+        let feed_url = "http://dated.test/feed.xml";
+        let feed = rss(&[
+            "<item><title>Old</title><link>http://dated.test/old</link>\
+             <pubDate>Wed, 01 Jan 2020 00:00:00 +0000</pubDate></item>"
+                .to_string(),
+            "<item><title>New</title><link>http://dated.test/new</link>\
+             <pubDate>Mon, 01 Jan 2024 00:00:00 +0000</pubDate></item>"
+                .to_string(),
+            item("Undated", "http://dated.test/undated"),
+        ]);
+        let fetcher = MapFetcher::new()
+            .page(feed_url, feed)
+            .page("http://dated.test/old", html("old"))
+            .page("http://dated.test/new", html("new"))
+            .page("http://dated.test/undated", html("undated"));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url.to_string()]));
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let fetcher = Arc::new(fetcher);
+        process_feed_file_with_fetcher(&file, Arc::clone(&index), config, fetcher).unwrap();
+        let index = std::mem::replace(&mut *index.lock().unwrap(), ArticleIndex::new());
+        index
+    }
+
+    #[test]
+    fn items_older_than_since_are_skipped() {
+        let since = |include_undated| {
+            let config = IndexConfig::builder()
+                .since(new_year(2023))
+                .include_undated(include_undated)
+                .build();
+            urls(&crawl_dated_feed(config))
+        };
+        assert_eq!(since(false), vec!["http://dated.test/new"]);
+        let expected = vec!["http://dated.test/new", "http://dated.test/undated"];
+        assert_eq!(since(true), expected);
+    }
+
+    #[test]
+    fn articles_since_filters_by_the_dates_of_the_feed() {
+        let index = crawl_dated_feed(IndexConfig::default());
+        assert_eq!(index.article_count(), 3);
+
+        let since = |year| {
+            let articles = index.articles_since(new_year(year));
+            articles.into_iter().map(|a| a.url).collect::<Vec<_>>()
+        };
+        assert_eq!(since(2023), vec!["http://dated.test/new"]);
+        // newest first, and never the undated article
+        assert_eq!(
+            since(2019),
+            vec!["http://dated.test/new", "http://dated.test/old"]
+        );
+        assert!(since(2025).is_empty());
+        let new = index.articles_since(new_year(2023)).remove(0);
+        assert_eq!(new.published, Some(new_year(2024)));
+    }
+
+    #[test]
+    fn articles_past_the_cap_are_not_fetched() {
+        let server = MockServer::start();
+        let articles = [
+            ("/1", "one"),
+            ("/2", "two"),
+            ("/3", "three"),
+            ("/4", "four"),
+            ("/5", "five"),
+        ];
+        let feed_url = server.feed("/feed.xml", &articles);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url]));
+        let config = IndexConfig::builder().max_articles_per_feed(2).build();
+        // an article seen before doesn't count against the cap
+        let mut seen = vec![server.url("/1")].into_iter().collect();
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        process_feed_file_resumable_with_config(&file, Arc::clone(&index), &mut seen, config)
+            .unwrap();
+
+        assert_eq!(
+            urls(&index.lock().unwrap()),
+            vec![server.url("/2"), server.url("/3")]
+        );
+        for path in &["/1", "/4", "/5"] {
+            assert_eq!(server.hits(path), 0);
+        }
+    }
+
+    #[test]
+    fn an_article_indexed_event_is_reported_for_each_article() {
+        let server = MockServer::start();
+        let feed_url = server.feed("/feed.xml", &[("/one", "first"), ("/two", "second")]);
+        let items = vec![
+            item("One", &server.url("/one")),
+            item("Two", &server.url("/two")),
+            item("Again", &server.url("/one")),
+        ];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(std::slice::from_ref(&feed_url)));
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        let on_event = move |event| sink.lock().unwrap().push(event);
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let config = IndexConfig::default();
+        process_feed_file_with_progress(&file, Arc::clone(&index), config, on_event).unwrap();
+
+        let events = events.lock().unwrap();
+        let indexed = events
+            .iter()
+            .filter(|e| matches!(e, ProgressEvent::ArticleIndexed { .. }))
+            .count();
+        assert_eq!(indexed, index.lock().unwrap().article_count());
+        assert_eq!(indexed, 2);
+        let skipped = ProgressEvent::ItemSkipped {
+            url: server.url("/one"),
+        };
+        assert!(events.contains(&skipped));
+        assert_eq!(events[0], ProgressEvent::FeedStarted { url: feed_url });
+    }
+
+    /// A `MapFetcher` whose article fetches each wait (for up to five seconds) until `parties`
+    /// article fetches have started, and which counts the fetches that gave up waiting.
+    struct RendezvousFetcher {
+        pages: MapFetcher,
+        parties: usize,
+        started: Mutex<usize>,
+        all_started: Condvar,
+        gave_up: AtomicUsize,
+    }
+
+    impl Fetcher for RendezvousFetcher {
+        fn fetch(&self, url: &str) -> RssIndexResult<Vec<u8>> {
+            if url.ends_with("/article") {
+                let mut started = self.started.lock().unwrap();
+                *started += 1;
+                self.all_started.notify_all();
+                let wait = Duration::from_secs(5);
+                let (started, timeout) = self
+                    .all_started
+                    .wait_timeout_while(started, wait, |started| *started < self.parties)
+                    .unwrap();
+                drop(started);
+                if timeout.timed_out() {
+                    self.gave_up.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            self.pages.fetch(url)
+        }
+    }
+
+    #[test]
+    fn feed_workers_enqueue_article_jobs_at_the_same_time() {
+        let pages = MapFetcher::new()
+            .page(
+                "http://a.test/feed",
+                rss(&[item("A", "http://a.test/article")]),
+            )
+            .page(
+                "http://b.test/feed",
+                rss(&[item("B", "http://b.test/article")]),
+            )
+            .page("http://a.test/article", html("from a"))
+            .page("http://b.test/article", html("from b"));
+        let fetcher = Arc::new(RendezvousFetcher {
+            pages,
+            parties: 2,
+            started: Mutex::new(0),
+            all_started: Condvar::new(),
+            gave_up: AtomicUsize::new(0),
+        });
+        let feeds = [
+            "http://a.test/feed".to_string(),
+            "http://b.test/feed".to_string(),
+        ];
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&feeds));
+        let config = IndexConfig::builder()
+            .feeds_pool_size(2)
+            .sites_pool_size(2)
+            .build();
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let shared = Arc::clone(&fetcher);
+        process_feed_file_with_fetcher(&file, Arc::clone(&index), config, shared).unwrap();
+
+        // the article of each feed was being fetched while the other feed's article was
+        assert_eq!(fetcher.gave_up.load(Ordering::SeqCst), 0);
+        assert_eq!(index.lock().unwrap().article_count(), 2);
+    }
+
+    #[test]
+    fn streamed_articles_are_one_json_object_per_line() {
+        let server = MockServer::start();
+        let feed_url = server.feed("/feed.xml", &[("/one", "first words"), ("/two", "second")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url]));
+
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let config = IndexConfig::default();
+        let errors = process_feed_file_streaming(&file, Arc::clone(&out), config).unwrap();
+
+        assert!(errors.is_empty());
+        let out = String::from_utf8(out.lock().unwrap().clone()).unwrap();
+        let mut lines = out
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        lines.sort_by_key(|line| line["url"].as_str().unwrap().to_string());
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["url"], server.url("/one"));
+        assert_eq!(lines[0]["title"], "/one");
+        assert_eq!(lines[0]["site"], "127.0.0.1");
+        assert_eq!(lines[0]["words"]["words"], 1);
+    }
+
+    #[test]
+    fn a_streamed_crawl_follows_its_config() {
+        let server = MockServer::start();
+        let feed_url = server.feed("/feed.xml", &[("/one", "first"), ("/two", "second")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url]));
+
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let config = IndexConfig::builder().max_articles_per_feed(1).build();
+        process_feed_file_streaming(&file, Arc::clone(&out), config).unwrap();
+
+        let out = String::from_utf8(out.lock().unwrap().clone()).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert_eq!(server.hits("/one") + server.hits("/two"), 1);
+    }
+
+    #[test]
+    fn an_unchanged_feed_is_not_processed_again() {
+        let server = MockServer::start();
+        server.route("/post", MockResponse::html("cached"));
+        let feed = rss(&[item("Post", &server.url("/post"))]);
+        server.route_with("/feed.xml", move |request| {
+            if request.headers.get("if-none-match").map(String::as_str) == Some("\"v1\"") {
+                MockResponse::status(304)
+            } else {
+                MockResponse::rss(feed.clone()).with_header("ETag", "\"v1\"")
+            }
+        });
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+        let cache_file = dir.path("cache.json");
+
+        let mut cache = FeedCache::load(&cache_file).unwrap();
+        let first = Arc::new(Mutex::new(ArticleIndex::new()));
+        let config = IndexConfig::default();
+        process_feed_file_cached(&file, Arc::clone(&first), &mut cache, config).unwrap();
+        cache.save(&cache_file).unwrap();
+
+        let mut cache = FeedCache::load(&cache_file).unwrap();
+        let second = Arc::new(Mutex::new(ArticleIndex::new()));
+        let config = IndexConfig::default();
+        let errors =
+            process_feed_file_cached(&file, Arc::clone(&second), &mut cache, config).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(urls(&first.lock().unwrap()), vec![server.url("/post")]);
+        assert!(urls(&second.lock().unwrap()).is_empty());
+        assert_eq!(server.hits("/feed.xml"), 2);
+        assert_eq!(server.hits("/post"), 1);
+    }
+
+    #[test]
+    fn gzipped_feeds_and_articles_are_decoded() {
+        let server = MockServer::start();
+        let page = MockResponse::new(200, "text/html", gzip(html("squeezed").as_bytes()));
+        server.route("/post", page.with_header("Content-Encoding", "gzip"));
+        let feed = gzip(rss(&[item("Post", &server.url("/post"))]).as_bytes());
+        let feed = MockResponse::rss(feed).with_header("Content-Encoding", "gzip");
+        server.route("/feed.xml", feed);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let errors = process_feed_file(&file, Arc::clone(&index)).unwrap();
+
+        assert!(errors.is_empty());
+        let index = index.lock().unwrap();
+        assert_eq!(index.search("squeezed")[0].0.url, server.url("/post"));
+        let accepted = &server.requests()[0].headers["accept-encoding"];
+        assert!(accepted.contains("gzip"));
+    }
+
+    /// Crawl two feeds listing the same article with `config`, and return the index along with
+    /// how often the shared article was fetched, after checking that both feeds are recorded as
+    /// its sources.
+    fn crawl_shared_article(config: IndexConfig) -> (ArticleIndex, usize) {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/shared", "syndicated"), ("/a1", "apple")]);
+        let feed_b = server.feed("/b.xml", &[("/shared", "syndicated")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_a.clone(), feed_b.clone()]));
+
+        let index = index_feed_file(&file, config).unwrap();
+
+        for feed_url in [feed_a, feed_b].iter() {
+            let articles = index.articles_from_feed(feed_url);
+            assert!(articles.iter().any(|a| a.url == server.url("/shared")));
+        }
+        (index, server.hits("/shared"))
+    }
+
+    #[test]
+    fn a_shared_article_is_fetched_once_with_the_global_scope() {
+        let (index, fetches) = crawl_shared_article(IndexConfig::default());
+        assert_eq!(fetches, 1);
+        assert_eq!(index.article_count(), 2);
+    }
+
+    #[test]
+    fn a_shared_article_is_fetched_for_each_feed_with_the_per_feed_scope() {
+        let config = IndexConfig::builder()
+            .dedup_scope(DedupScope::PerFeed)
+            .build();
+        let (index, fetches) = crawl_shared_article(config);
+        assert_eq!(fetches, 2);
+        assert_eq!(index.article_count(), 2);
+    }
+
+    #[test]
+    fn summaries_are_indexed_without_fetching_the_articles() {
+        let server = MockServer::start();
+        server.route("/post", MockResponse::html("full body"));
+        let summarized = format!(
+            "<item><title>Post</title><link>{}</link>\
+             <description>&lt;p&gt;summarized words&lt;/p&gt;</description></item>",
+            server.url("/post")
+        );
+        server.route("/feed.xml", MockResponse::rss(rss(&[summarized])));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+        let config = IndexConfig::builder()
+            .index_source(IndexSource::FeedSummary)
+            .build();
+
+        let index = index_feed_file(&file, config).unwrap();
+
+        assert_eq!(index.search("summarized")[0].0.url, server.url("/post"));
+        assert!(index.search("body").is_empty());
+        assert_eq!(server.hits("/feed.xml"), 1);
+        assert_eq!(server.hits("/post"), 0);
+    }
+
+    #[test]
+    fn gzipped_feed_files_are_indexed_like_plain_ones() {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/a1", "apple"), ("/a2", "apricot")]);
+        let feed_b = server.feed("/b.xml", &[("/b1", "banana")]);
+        let list = feed_list(&[feed_a, feed_b]);
+        let dir = TempDir::new();
+        let plain = dir.file("feeds.xml", &list);
+        let gzipped = dir.file("feeds.xml.gz", gzip(list.as_bytes()));
+        // no extension to go by, only the magic bytes
+        let unnamed = dir.file("feeds", gzip(list.as_bytes()));
+
+        let expected = index_feed_file(&plain, IndexConfig::default()).unwrap();
+        assert_eq!(expected.article_count(), 3);
+        for file in [gzipped, unnamed].iter() {
+            let index = index_feed_file(file, IndexConfig::default()).unwrap();
+            assert!(index == expected, "{} was indexed differently", file);
+        }
+    }
+
+    #[test]
+    fn a_full_sites_queue_does_not_deadlock_the_feed_workers() {
+        let mut fetcher = MapFetcher::with_delay(Duration::from_millis(1));
+        let mut feeds = Vec::new();
+        for f in 0..4 {
+            let feed_url = format!("http://feeds.test/{}.xml", f);
+            let mut items = Vec::new();
+            for a in 0..10 {
+                let url = format!("http://site{}.test/{}", f, a);
+                items.push(item(&format!("{} {}", f, a), &url));
+                fetcher = fetcher.page(&url, html("queued"));
+            }
+            fetcher = fetcher.page(&feed_url, rss(&items));
+            feeds.push(feed_url);
+        }
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&feeds));
+        let config = IndexConfig::builder()
+            .feeds_pool_size(4)
+            .sites_pool_size(1)
+            .sites_queue_size(1)
+            .build();
+
+        let (done, finished) = std::sync::mpsc::channel();
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let crawl_index = Arc::clone(&index);
+        std::thread::spawn(move || {
+            let fetcher = Arc::new(fetcher);
+            let result = process_feed_file_with_fetcher(&file, crawl_index, config, fetcher);
+            done.send(result.map(|errors| errors.len()).ok()).unwrap();
+        });
+
+        let errors = finished
+            .recv_timeout(Duration::from_secs(30))
+            .expect("crawl deadlocked");
+        assert_eq!(errors, Some(0));
+        assert_eq!(index.lock().unwrap().article_count(), 40);
+    }
+
+    #[test]
+    fn index_feed_file_returns_once_every_article_is_in() {
+        let server = MockServer::start();
+        let slow = |text| MockResponse::html(text).with_delay(Duration::from_millis(100));
+        server.route("/slow1", slow("tortoise"));
+        server.route("/slow2", slow("snail"));
+        let items = vec![
+            item("1", &server.url("/slow1")),
+            item("2", &server.url("/slow2")),
+        ];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+
+        let index = index_feed_file(&file, IndexConfig::default()).unwrap();
+
+        assert_eq!(index.article_count(), 2);
+        assert_eq!(index.search("tortoise").len(), 1);
+        assert_eq!(index.search("snail").len(), 1);
+    }
+
+    #[test]
+    fn snapshots_do_not_depend_on_the_pool_sizes() {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/a1", "one two two"), ("/a2", "three three")]);
+        let feed_b = server.feed("/b.xml", &[("/b1", "two four"), ("/a1", "one two two")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_a, feed_b]));
+        let snapshot = |feeds, sites| {
+            let config = IndexConfig::builder()
+                .feeds_pool_size(feeds)
+                .sites_pool_size(sites)
+                .build();
+            index_feed_file(&file, config).unwrap().snapshot()
+        };
+
+        let serial = snapshot(1, 1);
+        assert_eq!(serial, snapshot(4, 8));
+        let urls = serial.iter().map(|a| a.urls[0].clone()).collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            vec![server.url("/a1"), server.url("/a2"), server.url("/b1")]
+        );
+        // the title counts three times
+        let expected = vec![
+            ("a1".to_string(), 3),
+            ("two".to_string(), 2),
+            ("one".to_string(), 1),
+        ];
+        assert_eq!(serial[0].words, expected);
+    }
+
+    #[test]
+    fn variants_of_an_article_url_are_indexed_once() {
+        let server = MockServer::start();
+        server.route("/a", MockResponse::html("canonical"));
+        let url = server.localhost_url("/a");
+        let items = vec![
+            item("Plain", &url),
+            item(
+                "Slash",
+                &format!("{}/", url.replace("localhost", "LocalHost")),
+            ),
+            item(
+                "Tracked",
+                &format!("{}?utm_source=feed&amp;utm_medium=rss", url),
+            ),
+            item("Clicked", &format!("{}?fbclid=abc", url)),
+        ];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+
+        let index = index_feed_file(&file, IndexConfig::default()).unwrap();
+
+        assert_eq!(server.hits("/a"), 1);
+        assert_eq!(server.hits("/a/"), 0);
+        assert_eq!(urls(&index), vec![url]);
+    }
+
+    #[test]
+    fn requests_to_one_host_are_spaced_by_the_per_host_delay() {
+        let server = MockServer::start();
+        let times = Arc::new(Mutex::new(Vec::new()));
+        for path in &["/1", "/2"] {
+            let times = Arc::clone(&times);
+            server.route_with(path, move |_| {
+                times.lock().unwrap().push(std::time::Instant::now());
+                MockResponse::html("polite")
+            });
+        }
+        let items = vec![
+            item("1", &server.localhost_url("/1")),
+            item("2", &server.localhost_url("/2")),
+        ];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+        let delay = Duration::from_millis(300);
+        let config = IndexConfig::builder().per_host_delay(delay).build();
+
+        let index = index_feed_file(&file, config).unwrap();
+
+        assert_eq!(index.article_count(), 2);
+        let mut times = times.lock().unwrap().clone();
+        times.sort();
+        // the delay is measured from when the first request is sent, not when it arrives
+        assert!(times[1] - times[0] >= delay - Duration::from_millis(50));
+    }
+
+    #[test]
+    fn crawl_stats_count_every_feed_and_article() {
+        let server = MockServer::start();
+        server.route("/gone", MockResponse::status(404));
+        let feed_a = server.feed("/a.xml", &[("/a1", "apple"), ("/a2", "apricot")]);
+        let feed_b = server.feed("/b.xml", &[("/a1", "apple"), ("/b1", "banana")]);
+        server.route(
+            "/c.xml",
+            MockResponse::rss(rss(&[item("/gone", &server.url("/gone"))])),
+        );
+        let feeds = vec![feed_a.clone(), feed_b, server.url("/c.xml"), feed_a];
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&feeds));
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let config = IndexConfig::builder().sites_pool_size(4).build();
+
+        let (stats, errors) = process_feed_file_with_stats(&file, index, config).unwrap();
+
+        let expected = CrawlStats {
+            feeds_processed: 3,
+            articles_indexed: 3,
+            // the second listing of /a1, and the second listing of a.xml
+            duplicates_skipped: 2,
+            errors: 1,
+        };
+        assert_eq!(stats, expected);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, server.url("/gone"));
+    }
+
+    #[test]
+    fn feeds_listed_in_a_string_are_indexed() {
+        let server = MockServer::start();
+        let feed_url = server.feed("/feed.xml", &[("/one", "first"), ("/two", "second")]);
+        let xml = feed_list(&[feed_url]);
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+
+        let errors = process_feed_str(&xml, Arc::clone(&index), IndexConfig::default()).unwrap();
+
+        assert!(errors.is_empty());
+        let expected = vec![server.url("/one"), server.url("/two")];
+        assert_eq!(urls(&index.lock().unwrap()), expected);
+    }
+
+    #[test]
+    fn feeds_and_articles_come_from_the_given_fetcher() {
+        let feed_url = "http://feeds.test/feed.xml";
+        let post = "http://site.test/post";
+        let fetcher = Arc::new(
+            MapFetcher::new()
+                .page(feed_url, rss(&[item("Post", post)]))
+                .page(post, html("canned zebra")),
+        );
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url.to_string()]));
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let shared: SharedFetcher = fetcher.clone();
+
+        let config = IndexConfig::default();
+        let errors = process_feed_file_with_fetcher(&file, Arc::clone(&index), config, shared);
+
+        assert!(errors.unwrap().is_empty());
+        let hits = index.lock().unwrap().search("zebra");
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].0.url, post);
+        assert_eq!(fetcher.calls(feed_url), 1);
+        assert_eq!(fetcher.calls(post), 1);
+        assert_eq!(fetcher.total_calls(), 2);
+    }
+
+    #[test]
+    fn feeds_after_a_cancel_are_not_processed() {
+        let first = "http://feeds.test/first.xml";
+        let second = "http://feeds.test/second.xml";
+        let fetcher = Arc::new(
+            MapFetcher::new()
+                .page(first, rss(&[item("One", "http://site.test/one")]))
+                .page(second, rss(&[item("Two", "http://site.test/two")]))
+                .page("http://site.test/one", html("kept"))
+                .page("http://site.test/two", html("never")),
+        );
+        let cancel = CancellationToken::new();
+        let on_cancel = cancel.clone();
+        let (indexed, wait_indexed) = std::sync::mpsc::channel();
+        let (indexed, wait_indexed) = (Mutex::new(indexed), Mutex::new(wait_indexed));
+        // the feed worker runs the callback, so with one feed worker the second feed only starts
+        // once the article of the first is in and the crawl is cancelled
+        let on_event: ProgressFn = Arc::new(move |event| match event {
+            ProgressEvent::ArticleIndexed { .. } => indexed.lock().unwrap().send(()).unwrap(),
+            ProgressEvent::FeedFinished { .. } => {
+                wait_indexed.lock().unwrap().recv().unwrap();
+                on_cancel.cancel();
+            }
+            _ => {}
+        });
+        let shared: SharedFetcher = fetcher.clone();
+        let options = CrawlOptions {
+            fetcher: Some(shared),
+            on_event,
+            cancel,
+            ..CrawlOptions::default()
+        };
+        let feeds = vec![
+            Result::Ok(feed(first, "First")),
+            Result::Ok(feed(second, "Second")),
+        ];
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let config = IndexConfig::builder().feeds_pool_size(1).build();
+
+        let errors = process_feeds(feeds, Arc::clone(&index), config, options).unwrap();
+
+        assert!(errors.is_empty());
+        assert_eq!(urls(&index.lock().unwrap()), vec!["http://site.test/one"]);
+        assert_eq!(fetcher.calls(second), 0);
+    }
+
+    #[test]
+    fn feed_headers_are_sent_to_the_feed_and_its_host_only() {
+        let server = MockServer::start();
+        let items = vec![
+            item("Same", &server.url("/same")),
+            item("Other", &server.localhost_url("/other")),
+        ];
+        let feed_xml = rss(&items);
+        server.route_with("/feed.xml", move |request| {
+            match request.headers.get("authorization").map(String::as_str) {
+                Some("Bearer secret") => MockResponse::rss(feed_xml.clone()),
+                _ => MockResponse::status(401),
+            }
         });
+        server.route("/same", MockResponse::html("same host"));
+        server.route("/other", MockResponse::html("other host"));
+        let feed_url = server.url("/feed.xml");
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(std::slice::from_ref(&feed_url)));
+
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let config = IndexConfig::default();
+        let errors = process_feed_file_with_config(&file, Arc::clone(&index), config).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0].1,
+            RssIndexError::HttpStatus { status: 401, .. }
+        ));
+        assert_eq!(index.lock().unwrap().article_count(), 0);
+
+        let mut config = IndexConfig::builder().build();
+        let auth = HeaderValue::from_static("Bearer secret");
+        config
+            .feed_headers
+            .insert(feed_url, vec![(header::AUTHORIZATION, auth)]);
+        config.share_feed_headers = true;
+        let index = index_feed_file(&file, config).unwrap();
+
+        assert_eq!(index.article_count(), 2);
+        let auth_of = |path: &str| {
+            let requests = server.requests();
+            let request = requests.iter().find(|r| r.path == path).unwrap();
+            request.headers.get("authorization").cloned()
+        };
+        assert_eq!(auth_of("/same"), Some("Bearer secret".to_string()));
+        assert_eq!(auth_of("/other"), None);
+    }
+
+    #[test]
+    fn one_client_serves_the_feed_list_the_feeds_and_the_articles() {
+        let server = MockServer::start_keep_alive();
+        let articles = [("/1", "one"), ("/2", "two"), ("/3", "three")];
+        let feed_url = server.feed("/feed.xml", &articles);
+        server.route("/feeds.xml", MockResponse::rss(feed_list(&[feed_url])));
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let config = IndexConfig::builder()
+            .feeds_pool_size(1)
+            .sites_pool_size(1)
+            .build();
+
+        let list_url = server.url("/feeds.xml");
+        process_feed_url_with_config(&list_url, Arc::clone(&index), config).unwrap();
+
+        assert_eq!(index.lock().unwrap().article_count(), 3);
+        assert_eq!(server.requests().len(), 5);
+        // one request at a time, so a shared client sends them all over its first connection
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[test]
+    fn relative_article_links_are_resolved_against_the_feed_url() {
+        let server = MockServer::start();
+        for path in &["/posts/1", "/blog/2", "/abs"] {
+            server.route(path, MockResponse::html("linked"));
+        }
+        let items = vec![
+            item("Rooted", "/posts/1"),
+            item("Relative", "2"),
+            item("Absolute", &server.localhost_url("/abs")),
+        ];
+        server.route("/blog/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/blog/feed.xml")]));
+
+        let index = index_feed_file(&file, IndexConfig::default()).unwrap();
+
+        let mut expected = vec![
+            server.url("/posts/1"),
+            server.url("/blog/2"),
+            server.localhost_url("/abs"),
+        ];
+        expected.sort();
+        assert_eq!(urls(&index), expected);
+    }
+
+    #[test]
+    fn a_feed_without_a_title_is_skipped_and_the_rest_crawled() {
+        let good = "http://feeds.test/good.xml";
+        let untitled = "http://feeds.test/untitled.xml";
+        let fetcher = MapFetcher::new()
+            .page(good, rss(&[item("Post", "http://site.test/post")]))
+            .page("http://site.test/post", html("still crawled"));
+        let feeds = vec![
+            Result::Ok(FeedItem {
+                link: Some(untitled.to_string()),
+                ..FeedItem::default()
+            }),
+            Result::Ok(feed(good, "Good")),
+        ];
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        let counters = Arc::new(CrawlCounters::default());
+        let options = CrawlOptions {
+            fetcher: Some(Arc::new(fetcher)),
+            counters: Arc::clone(&counters),
+            ..CrawlOptions::default()
+        };
+
+        let errors = process_feeds(feeds, Arc::clone(&index), IndexConfig::default(), options);
+
+        let errors = errors.unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, untitled);
+        assert!(matches!(&errors[0].1, RssIndexError::MissingTitle { url } if url == untitled));
+        assert_eq!(counters.stats().errors, 1);
+        assert_eq!(urls(&index.lock().unwrap()), vec!["http://site.test/post"]);
+    }
+
+    #[test]
+    fn buffered_articles_add_up_to_the_index_of_a_single_thread() {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/a1", "apple pie"), ("/a2", "apple tart")]);
+        let feed_b = server.feed("/b.xml", &[("/b1", "banana"), ("/b2", "banana split")]);
+        let feed_c = server.feed("/c.xml", &[("/c1", "cherry"), ("/a1", "apple pie")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_a, feed_b, feed_c]));
+        let mut expected = ArticleIndex::new();
+        crate::single::process_feed_file(&file, &mut expected).unwrap();
+
+        for flush_interval in &[1, 2, 1000] {
+            let config = IndexConfig::builder()
+                .sites_pool_size(3)
+                .flush_interval(*flush_interval)
+                .build();
+            let index = index_feed_file(&file, config).unwrap();
+            assert_eq!(index, expected, "flush interval {}", flush_interval);
+        }
+    }
+
+    #[test]
+    fn a_feed_served_as_html_is_reported_as_not_a_feed() {
+        let server = MockServer::start();
+        server.route("/portal.xml", MockResponse::html("please log in"));
+        let good = server.feed("/good.xml", &[("/post", "crawled anyway")]);
+        let portal = server.url("/portal.xml");
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[portal.clone(), good]));
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+
+        let errors = process_feed_file(&file, Arc::clone(&index)).unwrap();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, portal);
+        match &errors[0].1 {
+            RssIndexError::NotAFeed { url, content_type } => {
+                assert_eq!(url, &portal);
+                assert_eq!(content_type, "text/html; charset=utf-8");
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        let message = errors[0].1.to_string();
+        assert!(message.contains("is text/html; charset=utf-8, not a feed"));
+        assert_eq!(urls(&index.lock().unwrap()), vec![server.url("/post")]);
+    }
+
+    /// Reads `head`, then waits up to five seconds for `gate` before reading `tail`, and records
+    /// whether the gate was opened in time.
+    struct GatedReader {
+        head: std::io::Cursor<Vec<u8>>,
+        tail: std::io::Cursor<Vec<u8>>,
+        gate: std::sync::mpsc::Receiver<()>,
+        opened: Arc<Mutex<Option<bool>>>,
+    }
+
+    impl Read for GatedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.head.read(buf)?;
+            if n > 0 {
+                return Result::Ok(n);
+            }
+            let mut opened = self.opened.lock().unwrap();
+            if opened.is_none() {
+                let in_time = self.gate.recv_timeout(Duration::from_secs(5)).is_ok();
+                *opened = Some(in_time);
+            }
+            self.tail.read(buf)
+        }
+    }
+
+    #[test]
+    fn feeds_of_a_large_file_are_started_before_it_is_read_to_the_end() {
+        let feed_urls = (0..2000)
+            .map(|n| format!("http://feeds.test/{}.xml", n))
+            .collect::<Vec<_>>();
+        let mut xml = feed_list(&feed_urls).into_bytes();
+        let tail = xml.split_off(xml.len() / 2);
+        let (started, gate) = std::sync::mpsc::channel();
+        let started = Mutex::new(started);
+        let opened = Arc::new(Mutex::new(None));
+        let reader = GatedReader {
+            head: std::io::Cursor::new(xml),
+            tail: std::io::Cursor::new(tail),
+            gate,
+            opened: Arc::clone(&opened),
+        };
+        let started_count = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&started_count);
+        let options = CrawlOptions {
+            fetcher: Some(Arc::new(MapFetcher::new())),
+            on_event: Arc::new(move |event| {
+                if let ProgressEvent::FeedStarted { .. } = event {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    // the reader is gone once the whole file was read
+                    let _ = started.lock().unwrap().send(());
+                }
+            }),
+            ..CrawlOptions::default()
+        };
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+
+        let errors = process_feeds(stream_feed(reader), index, IndexConfig::default(), options);
+
+        // every feed is missing from the fetcher, so each one fails
+        assert_eq!(errors.unwrap().len(), feed_urls.len());
+        assert_eq!(*opened.lock().unwrap(), Some(true));
+        assert_eq!(started_count.load(Ordering::SeqCst), feed_urls.len());
+    }
+
+    #[cfg(feature = "interrupt")]
+    #[test]
+    fn an_interrupted_crawl_still_saves_a_valid_index() {
+        let server = MockServer::start();
+        let cancel = CancellationToken::new();
+        let on_fetch = cancel.clone();
+        // the first article cancels the crawl as it is fetched, well before the second feed is in
+        server.route_with("/a", move |_| {
+            on_fetch.cancel();
+            MockResponse::html("saved")
+        });
+        server.route("/b", MockResponse::html("never"));
+        let delay = Duration::from_millis(300);
+        let feed_a = server.url("/a.xml");
+        server.route(
+            "/a.xml",
+            MockResponse::rss(rss(&[item("A", &server.url("/a"))])),
+        );
+        let feed_b = server.url("/b.xml");
+        let feed_b_xml = rss(&[item("B", &server.url("/b"))]);
+        server.route("/b.xml", MockResponse::rss(feed_b_xml).with_delay(delay));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_a, feed_b]));
+        let save_path = dir.path("index.json");
+
+        let config = IndexConfig::default();
+        let errors = index_and_save(&file, config.clone(), &save_path, cancel).unwrap();
+
+        assert!(errors.is_empty());
+        let saved = ArticleIndex::load(&save_path).unwrap();
+        assert_eq!(urls(&saved), vec![server.url("/a")]);
+        assert_eq!(server.hits("/b"), 0);
+
+        // as if Ctrl-C was pressed before any feed was started; the saved index is replaced
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        index_and_save(&file, config, &save_path, cancel).unwrap();
+        let saved = ArticleIndex::load(&save_path).unwrap();
+        assert_eq!(saved.article_count(), 0);
+        assert_eq!(server.hits("/a.xml"), 1);
+    }
+
+    #[test]
+    fn an_article_in_two_feeds_lists_both_as_its_sources() {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/shared", "both"), ("/only-a", "one")]);
+        let feed_b = server.feed("/b.xml", &[("/shared", "both")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_a.clone(), feed_b.clone()]));
+
+        let pooled = index_feed_file(&file, IndexConfig::default()).unwrap();
+        let mut single = ArticleIndex::new();
+        crate::single::process_feed_file(&file, &mut single).unwrap();
+        let multi = Arc::new(Mutex::new(ArticleIndex::new()));
+        crate::multi::process_feed_file(&file, Arc::clone(&multi)).unwrap();
+        let multi = multi.lock().unwrap();
+
+        for index in &[&pooled, &single, &*multi] {
+            let from_a = index.articles_from_feed(&feed_a);
+            let from_b = index.articles_from_feed(&feed_b);
+            assert_eq!(titles_of(&from_a), vec!["/only-a", "/shared"]);
+            assert_eq!(titles_of(&from_b), vec!["/shared"]);
+            assert_eq!(from_b[0].url, server.url("/shared"));
+            assert_eq!(index.article_count(), 2);
+        }
+    }
+
+    #[test]
+    fn a_crawled_index_normalizes_queries_with_the_crawl_settings() {
+        let server = MockServer::start();
+        let feed_url = server.feed("/feed.xml", &[("/post", "running")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url]));
+        let config = IndexConfig::builder().stem(true).build();
+
+        let index = index_feed_file(&file, config).unwrap();
+
+        assert_eq!(index.search("runs")[0].0.url, server.url("/post"));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn rendered_metrics_count_the_articles_of_the_index() {
+        let server = MockServer::start();
+        let feed_a = server.feed("/a.xml", &[("/a1", "apple"), ("/a2", "apricot")]);
+        let feed_b = server.feed("/b.xml", &[("/b1", "banana")]);
+        let feeds = vec![
+            Result::Ok(feed(&feed_a, "A")),
+            Result::Ok(feed(&feed_b, "B")),
+        ];
+        // metrics of this crawl alone, as other tests crawl at the same time
+        let metrics: &'static Metrics = Box::leak(Box::new(Metrics::new()));
+        let options = CrawlOptions {
+            counters: Arc::new(CrawlCounters::with_metrics(metrics)),
+            ..CrawlOptions::default()
+        };
+        let index = Arc::new(Mutex::new(ArticleIndex::new()));
+        process_feeds(feeds, Arc::clone(&index), IndexConfig::default(), options).unwrap();
+
+        let count = index.lock().unwrap().article_count();
+        assert_eq!(count, 3);
+        let text = metrics.text();
+        assert!(text.contains(&format!("\narticles_indexed_total {}\n", count)));
+        assert!(text.contains("\nfeeds_processed_total 2\n"));
+        assert!(text.contains("\nfetch_errors_total 0\n"));
     }
-    Result::Ok(())
 }
@@ -0,0 +1,115 @@
+use log::debug;
+use reqwest::blocking::Client;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use texting_robots::{get_robots_url, Robot};
+
+use crate::common::*;
+
+/// The parsed robots.txt of one host, set once it has been fetched.
+type RobotCell = Arc<OnceLock<Option<Arc<Robot>>>>;
+
+/// The robots.txt rules of the hosts visited by a crawl. Each host's robots.txt is fetched and
+/// parsed once, the first time one of its URLs is checked. A host whose robots.txt can't be
+/// fetched or parsed allows everything.
+#[derive(Default)]
+pub struct RobotsRules {
+    robots: Mutex<HashMap<String, RobotCell>>,
+}
+
+impl RobotsRules {
+    pub fn new() -> Self {
+        RobotsRules::default()
+    }
+
+    /// Fail with `RssIndexError::RobotsDisallowed` if the robots.txt of the host of `url`
+    /// disallows fetching it for `config.user_agent`.
+    pub fn check(&self, client: &Client, url: &str, config: &IndexConfig) -> RssIndexResult<()> {
+        let robots_url = match get_robots_url(url) {
+            Ok(robots_url) => robots_url,
+            Err(_) => return Result::Ok(()),
+        };
+        match self.robot(client, &robots_url, config) {
+            Some(robot) if !robot.allowed(url) => {
+                Result::Err(Box::new(RssIndexError::RobotsDisallowed {
+                    url: url.to_string(),
+                }))
+            }
+            _ => Result::Ok(()),
+        }
+    }
+
+    /// The parsed robots.txt at `robots_url`, fetched unless it already has been.
+    fn robot(&self, client: &Client, robots_url: &str, config: &IndexConfig) -> Option<Arc<Robot>> {
+        let cell = Arc::clone(
+            self.robots
+                .lock()
+                .unwrap()
+                .entry(robots_url.to_string())
+                .or_default(),
+        );
+        // only the map is locked, and only briefly, so that checks of other hosts go on while this
+        // one is fetched; other checks of this host wait for it instead of fetching it again
+        cell.get_or_init(|| match fetch_bytes(client, robots_url, config) {
+            Ok(txt) => Robot::new(&config.user_agent, &txt).ok().map(Arc::new),
+            Err(e) => {
+                debug!("No robots.txt [{}]: {}", robots_url, e);
+                None
+            }
+        })
+        .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pooled::index_feed_file;
+    use crate::testutil::*;
+
+    #[test]
+    fn articles_disallowed_by_robots_txt_are_skipped() {
+        capture_logs();
+        let server = MockServer::start();
+        let robots_txt = "User-agent: *\nDisallow: /private\n";
+        server.route(
+            "/robots.txt",
+            MockResponse::new(200, "text/plain", robots_txt),
+        );
+        let feed_url = server.feed(
+            "/feed.xml",
+            &[
+                ("/public", "open"),
+                ("/private/a", "closed"),
+                ("/private/b", "closed"),
+            ],
+        );
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url]));
+        let config = IndexConfig::builder().respect_robots(true).build();
+
+        let index = index_feed_file(&file, config).unwrap();
+
+        assert_eq!(urls(&index), vec![server.url("/public")]);
+        assert_eq!(server.hits("/private/a") + server.hits("/private/b"), 0);
+        // the rules of the host are fetched once, and then cached
+        assert_eq!(server.hits("/robots.txt"), 1);
+        let disallowed = format!("robots.txt disallows {}", server.url("/private/a"));
+        let warned = logs()
+            .into_iter()
+            .any(|(level, msg)| level == log::Level::Warn && msg.contains(&disallowed));
+        assert!(warned);
+    }
+
+    #[test]
+    fn a_host_without_robots_txt_allows_everything() {
+        let server = MockServer::start();
+        let client = Client::new();
+        let rules = RobotsRules::new();
+        let config = IndexConfig::default();
+
+        assert!(rules.check(&client, &server.url("/any"), &config).is_ok());
+        assert!(rules.check(&client, &server.url("/other"), &config).is_ok());
+        assert_eq!(server.hits("/robots.txt"), 1);
+    }
+}
@@ -0,0 +1,112 @@
+use log::{info, warn};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tiny_http::{Header, Method, Request, Response, Server};
+use url::Url;
+
+use crate::common::*;
+
+/// One search result, as returned by `GET /search`.
+#[derive(Serialize)]
+struct SearchHit {
+    title: String,
+    url: String,
+    count: u32,
+}
+
+/// Serve the index over HTTP at `addr`, one request at a time. `GET /search?q=<word>` returns the
+/// results of `ArticleIndex::search` as a JSON array of `{"title", "url", "count"}` objects, or
+/// 400 if `q` is missing. With the `metrics` feature, `GET /metrics` returns the process's
+/// metrics in the Prometheus text format (see `metrics_text`). Any other request gets a 404. Only
+/// returns if the server can't be started.
+pub fn serve(index: Arc<Mutex<ArticleIndex>>, addr: SocketAddr) -> RssIndexResult<()> {
+    let server = Server::http(addr).map_err(|e| RssIndexError::OtherError(e.to_string()))?;
+    info!("Serving search on http://{}", server.server_addr());
+    serve_requests(&server, &index);
+    Result::Ok(())
+}
+
+/// Answer the requests `server` receives, for as long as it runs.
+fn serve_requests(server: &Server, index: &Mutex<ArticleIndex>) {
+    for request in server.incoming_requests() {
+        let response = handle(index, &request);
+        if let Err(e) = request.respond(response) {
+            warn!("Failed to send response: {}", e);
+        }
+    }
+}
+
+/// Answer one request.
+fn handle(index: &Mutex<ArticleIndex>, request: &Request) -> Response<std::io::Cursor<Vec<u8>>> {
+    // the request only carries the path and query, so give it some base to parse against
+    let url = match Url::parse("http://localhost").and_then(|base| base.join(request.url())) {
+        Ok(url) => url,
+        Err(_) => return Response::from_string("Bad request").with_status_code(400),
+    };
+    #[cfg(feature = "metrics")]
+    if *request.method() == Method::Get && url.path() == "/metrics" {
+        let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+        return Response::from_string(crate::metrics::metrics_text()).with_header(content_type);
+    }
+    if *request.method() != Method::Get || url.path() != "/search" {
+        return Response::from_string("Not found").with_status_code(404);
+    }
+    let word = match url.query_pairs().find(|(key, _)| key == "q") {
+        Some((_, word)) => word.into_owned(),
+        None => return Response::from_string("Missing query parameter q").with_status_code(400),
+    };
+
+    let hits = index
+        .lock()
+        .unwrap()
+        .search(&word)
+        .into_iter()
+        .map(|(article, count)| SearchHit {
+            title: article.title,
+            url: article.url,
+            count,
+        })
+        .collect::<Vec<_>>();
+    match serde_json::to_vec(&hits) {
+        Ok(body) => {
+            let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+            Response::from_data(body).with_header(content_type)
+        }
+        Err(_) => Response::from_string("Internal server error").with_status_code(500),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn search_results_are_served_as_json() {
+        let mut index = ArticleIndex::new();
+        let mut words = Bag::new();
+        words.add("served".to_string());
+        let (site, url) = ("site.test".to_string(), "http://site.test/a".to_string());
+        index.add(site, "Served".to_string(), url, words);
+        let index = Arc::new(Mutex::new(index));
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", server.server_addr());
+        thread::spawn(move || serve_requests(&server, &index));
+
+        let response = reqwest::blocking::get(&format!("{}/search?q=served", base)).unwrap();
+        assert_eq!(response.status(), 200);
+        let hits: serde_json::Value = serde_json::from_str(&response.text().unwrap()).unwrap();
+        let expected = serde_json::json!([
+            {"title": "Served", "url": "http://site.test/a", "count": 1}
+        ]);
+        assert_eq!(hits, expected);
+
+        let status = |path: &str| {
+            let response = reqwest::blocking::get(&format!("{}{}", base, path)).unwrap();
+            response.status().as_u16()
+        };
+        assert_eq!(status("/search"), 400);
+        assert_eq!(status("/elsewhere?q=served"), 404);
+    }
+}
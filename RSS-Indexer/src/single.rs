@@ -1,9 +1,6 @@
-use rss::Channel;
+use log::{debug, info, warn};
 use std::collections::HashSet;
-use std::fs::File;
-use std::io::BufReader;
 use std::result::Result;
-use url::Url;
 
 use crate::common::*;
 
@@ -11,26 +8,49 @@ use crate::common::*;
 /// For each feed in the list, get the URL and call `process_feed`. Take a look at the examples in
 /// the rss crate.
 pub fn process_feed_file(file_name: &str, index: &mut ArticleIndex) -> RssIndexResult<()> {
-    let file = File::open(file_name)?;
-    println!("Processing feed file: {}", file_name);
+    process_feed_file_with_config(file_name, index, IndexConfig::default())
+}
+
+/// Same as `process_feed_file`, but with the settings taken from `config`.
+pub fn process_feed_file_with_config(
+    file_name: &str,
+    index: &mut ArticleIndex,
+    config: IndexConfig,
+) -> RssIndexResult<()> {
+    let file = open_feed_file(file_name)?;
+    info!("Processing feed file: {}", file_name);
 
-    let channel = Channel::read_from(BufReader::new(file))?;
+    let feeds = read_feed(file)?;
+    let fetcher = HttpFetcher::new(&config)?;
 
     let mut urls = HashSet::new();
+    let mut sources = Vec::new();
+    let throttle = HostThrottle::new();
 
-    for feed in channel.into_items() {
-        let url = feed.link().ok_or(RssIndexError::UrlError)?;
-        let title = feed.title().ok_or(RssIndexError::UrlError)?;
+    for feed in feeds {
+        let (url, title) = feed.link_and_title()?;
 
-        if urls.contains(url) {
-            println!("Skipping already seen feed: {} [{}]", title, url);
+        if !urls.insert(canonical_url(url, &config)) {
+            debug!("Skipping already seen feed: {} [{}]", title, url);
             continue;
         }
-        urls.insert(url.to_string());
 
-        println!("Processing feed: {} [{}]", title, url);
-        process_feed(url, index, &mut urls)?;
+        info!("Processing feed: {} [{}]", title, url);
+        let result = process_feed(
+            url,
+            index,
+            &mut urls,
+            &mut sources,
+            &throttle,
+            &fetcher,
+            &config,
+        );
+        if let Err(e) = result {
+            warn!("Skipping feed: {} [{}]: {}", title, url, e);
+        }
     }
+    index.add_source_feeds(sources);
+    index.set_query_config(&config);
     Result::Ok(())
 }
 
@@ -38,37 +58,194 @@ pub fn process_feed_file(file_name: &str, index: &mut ArticleIndex) -> RssIndexR
 /// (articles). Pull out three pieces of information: the URL, the hostname, and the title (see
 /// Item::link, Url::parse, and Url::host_str here). Process each url/title with `process_article`,
 /// and then add it to the input ArticleIndex along with the hostname. If an Item is missing a
-/// url/hostname/title, skip it (do not panic).
+/// url/hostname/title, or it can't be fetched, skip it (do not panic). Articles already seen are
+/// pushed to `sources` along with the URL of this feed.
 fn process_feed(
     url: &str,
     index: &mut ArticleIndex,
     urls: &mut HashSet<String>,
+    sources: &mut Vec<(String, String)>,
+    throttle: &HostThrottle,
+    fetcher: &dyn Fetcher,
+    config: &IndexConfig,
 ) -> RssIndexResult<()> {
-    let contents = reqwest::blocking::get(url)?.bytes()?;
-    let channel = Channel::read_from(&contents[..])?;
-    let items = channel.into_items();
+    let contents = fetcher.fetch_feed(url)?;
+    let items = parse_feed_at(&contents, url)?;
+    let feed_url = url;
+    let mut count = 0;
     for item in items {
-        let (url, site, title) = match (item.link(), Url::parse(&url)?.host_str(), item.title()) {
-            (Some(u), Some(s), Some(t)) => (u, s.to_string(), t),
+        let (url, site, title) = match (item.link(), item.site(), item.title()) {
+            (Some(u), Some(s), Some(t)) => (u, s, t),
             _ => continue,
         };
 
-        if urls.contains(url) {
-            println!("Skipping already seen article: {} [{}]", title, url);
+        if is_self_link(url, feed_url, config) {
+            debug!("Skipping link back to the feed: {} [{}]", title, url);
             continue;
         }
-        urls.insert(url.to_string());
 
-        println!("Processing article: {} [{}]", title, url);
+        if !config.wants_item(&item) {
+            debug!("Skipping old article: {} [{}]", title, url);
+            continue;
+        }
 
-        let article = Article::new(url.to_string(), title.to_string());
-        let article_words = process_article(&article)?;
-        index.add(
-            site.to_string(),
-            title.to_string(),
-            url.to_string(),
-            article_words,
-        );
+        if config.feed_is_full(count) {
+            debug!("Skipping remaining articles: per-feed limit reached");
+            break;
+        }
+
+        if !urls.insert(dedup_key(&item, url, feed_url, config)) {
+            debug!("Skipping already seen article: {} [{}]", title, url);
+            sources.push((url.to_string(), feed_url.to_string()));
+            continue;
+        }
+        count += 1;
+
+        if config.dry_run {
+            info!("Would process article: {} [{}]", title, url);
+            continue;
+        }
+
+        info!("Processing article: {} [{}]", title, url);
+
+        let article = Article::with_published(url.to_string(), title.to_string(), item.published);
+        match article_words(&article, item.summary.as_deref(), fetcher, throttle, config) {
+            Ok(mut article_words) => {
+                article_words.feed = Some(feed_url.to_string());
+                index.add_article(
+                    site.to_string(),
+                    title.to_string(),
+                    url.to_string(),
+                    article_words,
+                )
+            }
+            Err(e) => warn!("Skipping article: {} [{}]: {}", title, url, e),
+        }
     }
     Result::Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn processing_a_feed_is_logged_at_info_level() {
+        capture_logs();
+        let server = MockServer::start();
+        let feed_url = server.feed("/feed.xml", &[("/post", "logged")]);
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(std::slice::from_ref(&feed_url)));
+
+        let mut index = ArticleIndex::new();
+        process_feed_file(&file, &mut index).unwrap();
+
+        let expected = (
+            log::Level::Info,
+            format!("Processing feed: Feed 0 [{}]", feed_url),
+        );
+        assert!(logs().contains(&expected));
+    }
+
+    #[test]
+    fn an_item_linking_back_to_its_feed_is_skipped() {
+        capture_logs();
+        let server = MockServer::start();
+        server.route("/post", MockResponse::html("real article"));
+        let feed_url = server.url("/feed.xml");
+        let self_link = server.url("/feed.xml/?utm_source=rss");
+        let items = vec![
+            item("Itself", &self_link),
+            item("Post", &server.url("/post")),
+        ];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[feed_url]));
+
+        let mut index = ArticleIndex::new();
+        process_feed_file(&file, &mut index).unwrap();
+
+        assert_eq!(urls(&index), vec![server.url("/post")]);
+        let expected = (
+            log::Level::Debug,
+            format!("Skipping link back to the feed: Itself [{}]", self_link),
+        );
+        assert!(logs().contains(&expected));
+    }
+
+    #[test]
+    fn an_item_linking_to_another_feed_is_not_indexed() {
+        let server = MockServer::start();
+        let other = server.feed("/other.xml", &[("/elsewhere", "elsewhere")]);
+        server.route("/post", MockResponse::html("real article"));
+        let items = vec![item("Other", &other), item("Post", &server.url("/post"))];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+
+        let mut index = ArticleIndex::new();
+        process_feed_file(&file, &mut index).unwrap();
+
+        assert_eq!(urls(&index), vec![server.url("/post")]);
+        assert!(index.search("elsewhere").is_empty());
+    }
+
+    #[test]
+    fn items_sharing_a_guid_are_indexed_once_unless_told_apart_by_link() {
+        let server = MockServer::start();
+        server.route("/post", MockResponse::html("same story"));
+        let with_guid = |title: &str, link: &str| {
+            let guid = "<guid isPermaLink=\"false\">urn:story</guid>";
+            format!(
+                "<item><title>{}</title><link>{}</link>{}</item>",
+                title, link, guid
+            )
+        };
+        let items = vec![
+            with_guid("From home", &server.url("/post?from=home")),
+            with_guid("From archive", &server.url("/post?from=archive")),
+        ];
+        server.route("/feed.xml", MockResponse::rss(rss(&items)));
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&[server.url("/feed.xml")]));
+
+        let mut index = ArticleIndex::new();
+        process_feed_file(&file, &mut index).unwrap();
+        assert_eq!(urls(&index), vec![server.url("/post?from=home")]);
+
+        let mut index = ArticleIndex::new();
+        let config = IndexConfig::builder().dedup_by_guid(false).build();
+        process_feed_file_with_config(&file, &mut index, config).unwrap();
+        let expected = vec![
+            server.url("/post?from=archive"),
+            server.url("/post?from=home"),
+        ];
+        assert_eq!(urls(&index), expected);
+    }
+
+    #[test]
+    fn items_of_two_feeds_sharing_a_plain_guid_are_both_indexed() {
+        let server = MockServer::start();
+        server.route("/a/1", MockResponse::html("first feed"));
+        server.route("/b/1", MockResponse::html("second feed"));
+        let with_guid = |title: &str, link: &str| {
+            format!(
+                "<item><title>{}</title><link>{}</link><guid>1</guid></item>",
+                title, link
+            )
+        };
+        let a = vec![with_guid("A one", &server.url("/a/1"))];
+        let b = vec![with_guid("B one", &server.url("/b/1"))];
+        server.route("/a.xml", MockResponse::rss(rss(&a)));
+        server.route("/b.xml", MockResponse::rss(rss(&b)));
+        let feeds = [server.url("/a.xml"), server.url("/b.xml")];
+        let dir = TempDir::new();
+        let file = dir.file("feeds.xml", feed_list(&feeds));
+
+        let mut index = ArticleIndex::new();
+        process_feed_file(&file, &mut index).unwrap();
+
+        assert_eq!(urls(&index), vec![server.url("/a/1"), server.url("/b/1")]);
+    }
+}
@@ -0,0 +1,96 @@
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+use crate::common::*;
+
+impl ArticleIndex {
+    /// Write the index to the SQLite database at `path`, creating it if needed, in one
+    /// transaction. Articles go into `articles(url, site, title)` under their
+    /// alphabetically-earliest URL, and their words into `words(article_url, word, freq)`.
+    /// Articles already in the database are replaced, so writing the same index twice doesn't
+    /// duplicate anything.
+    pub fn to_sqlite(&self, path: &Path) -> RssIndexResult<()> {
+        let mut conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS articles (
+                 url   TEXT PRIMARY KEY,
+                 site  TEXT NOT NULL,
+                 title TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS words (
+                 article_url TEXT NOT NULL REFERENCES articles(url),
+                 word        TEXT NOT NULL,
+                 freq        INTEGER NOT NULL,
+                 PRIMARY KEY (article_url, word)
+             );",
+        )?;
+
+        let tx = conn.transaction()?;
+        {
+            let mut insert_article = tx.prepare(
+                "INSERT INTO articles (url, site, title) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(url) DO UPDATE SET site = excluded.site, title = excluded.title",
+            )?;
+            let mut delete_words = tx.prepare("DELETE FROM words WHERE article_url = ?1")?;
+            let mut insert_word =
+                tx.prepare("INSERT INTO words (article_url, word, freq) VALUES (?1, ?2, ?3)")?;
+
+            for (key, (urls, words)) in &self.index {
+                let url = match urls.iter().min() {
+                    Some(url) => url,
+                    None => continue,
+                };
+                insert_article.execute(params![url, key.site, key.title])?;
+                // drop the words of an earlier crawl, which may have had words this one doesn't
+                delete_words.execute(params![url])?;
+                for (word, &count) in words.counts.iter().filter(|(_, &count)| count > 0) {
+                    insert_word.execute(params![url, word, count])?;
+                }
+            }
+        }
+        tx.commit()?;
+        Result::Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn count(conn: &Connection, table: &str) -> i64 {
+        let sql = format!("SELECT COUNT(*) FROM {}", table);
+        conn.query_row(&sql, params![], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn rows_are_written_once_even_when_written_twice() {
+        let mut index = ArticleIndex::new();
+        let mut words = Bag::new();
+        for word in &["rust", "rust", "sql"] {
+            words.add(word.to_string());
+        }
+        let site = || "site.test".to_string();
+        index.add(site(), "A".into(), "http://site.test/a".into(), words);
+        let mut words = Bag::new();
+        words.add("go".to_string());
+        index.add(site(), "B".into(), "http://site.test/b".into(), words);
+        let dir = TempDir::new();
+        let path = dir.path("index.db");
+
+        index.to_sqlite(&path).unwrap();
+        index.to_sqlite(&path).unwrap();
+
+        let conn = Connection::open(&path).unwrap();
+        assert_eq!(count(&conn, "articles"), 2);
+        assert_eq!(count(&conn, "words"), 3);
+        let freq: i64 = conn
+            .query_row(
+                "SELECT freq FROM words WHERE article_url = ?1 AND word = 'rust'",
+                params!["http://site.test/a"],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(freq, 2);
+    }
+}
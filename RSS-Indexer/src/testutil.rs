@@ -0,0 +1,488 @@
+//! Helpers shared by the tests of all modules: a small HTTP server with canned responses, builders
+//! for feed documents, temporary files, and a `Fetcher` that serves pages from memory.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+use crate::common::*;
+
+/// A canned response of a `MockServer`.
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// How long to wait before answering.
+    pub delay: Duration,
+    /// Whether to leave out the Content-Length header, so that the body ends when the connection
+    /// is closed.
+    pub omit_length: bool,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        MockResponse {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: body.into(),
+            delay: Duration::from_secs(0),
+            omit_length: false,
+        }
+    }
+
+    /// A 200 response with an HTML page whose body is `text`.
+    pub fn html(text: &str) -> Self {
+        MockResponse::new(200, "text/html; charset=utf-8", html(text))
+    }
+
+    /// A 200 response with a feed.
+    pub fn rss(xml: impl Into<Vec<u8>>) -> Self {
+        MockResponse::new(200, "application/rss+xml", xml)
+    }
+
+    /// An empty response with the given status.
+    pub fn status(status: u16) -> Self {
+        MockResponse::new(status, "text/plain", Vec::new())
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn without_length(mut self) -> Self {
+        self.omit_length = true;
+        self
+    }
+}
+
+/// A request received by a `MockServer`. Header names are lowercased.
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub path: String,
+    pub host: String,
+    pub headers: HashMap<String, String>,
+}
+
+type Handler = Arc<dyn Fn(&MockRequest) -> MockResponse + Send + Sync>;
+
+#[derive(Default)]
+struct MockState {
+    routes: Mutex<HashMap<String, Handler>>,
+    requests: Mutex<Vec<MockRequest>>,
+    /// Requests being answered, and the most ever answered at once, by host.
+    in_flight: Mutex<HashMap<String, (usize, usize)>>,
+    /// Connections accepted so far.
+    connections: AtomicUsize,
+    /// Whether connections are kept open for further requests.
+    keep_alive: bool,
+}
+
+/// An HTTP server on an ephemeral port of 127.0.0.1, answering each connection on a thread of its
+/// own with the responses routed to the paths of its requests (without the query), or 404. Unless
+/// started with `start_keep_alive`, every connection is closed after one response. The server runs
+/// until the test process ends.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+}
+
+impl MockServer {
+    pub fn start() -> Self {
+        MockServer::start_with(MockState::default())
+    }
+
+    /// Same as `start`, but connections are kept open, so that a client can send more requests
+    /// over them. Responses without a length still close the connection.
+    pub fn start_keep_alive() -> Self {
+        MockServer::start_with(MockState {
+            keep_alive: true,
+            ..MockState::default()
+        })
+    }
+
+    fn start_with(state: MockState) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(state);
+        let server_state = Arc::clone(&state);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                server_state.connections.fetch_add(1, Ordering::SeqCst);
+                let state = Arc::clone(&server_state);
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream);
+                    while answer(&mut reader, &state) {}
+                });
+            }
+        });
+        MockServer { addr, state }
+    }
+
+    /// The URL of `path` on this server, through 127.0.0.1.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// The URL of `path` on this server, through `localhost`, which is another host as far as
+    /// the crawl can tell.
+    pub fn localhost_url(&self, path: &str) -> String {
+        format!("http://localhost:{}{}", self.addr.port(), path)
+    }
+
+    /// Answer requests for `path` with `response`.
+    pub fn route(&self, path: &str, response: MockResponse) {
+        self.route_with(path, move |_| response.clone());
+    }
+
+    /// Answer requests for `path` with what `handler` returns for them.
+    pub fn route_with<F>(&self, path: &str, handler: F)
+    where
+        F: Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        let mut routes = self.state.routes.lock().unwrap();
+        routes.insert(path.to_string(), Arc::new(handler));
+    }
+
+    /// Answer the requests for `path` with `responses` in turn, and then keep answering with the
+    /// last one.
+    pub fn route_sequence(&self, path: &str, responses: Vec<MockResponse>) {
+        let count = AtomicUsize::new(0);
+        self.route_with(path, move |_| {
+            let n = count.fetch_add(1, Ordering::SeqCst);
+            responses[n.min(responses.len() - 1)].clone()
+        });
+    }
+
+    /// Serve a feed at `feed_path` listing an article at each of `articles`' paths, whose page says
+    /// the given text and whose title is its path. Returns the URL of the feed.
+    pub fn feed(&self, feed_path: &str, articles: &[(&str, &str)]) -> String {
+        let mut items = Vec::new();
+        for (path, text) in articles {
+            self.route(path, MockResponse::html(text));
+            items.push(item(path, &self.url(path)));
+        }
+        self.route(feed_path, MockResponse::rss(rss(&items)));
+        self.url(feed_path)
+    }
+
+    /// All requests received so far, in the order they arrived.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// How many requests for `path` were received.
+    pub fn hits(&self, path: &str) -> usize {
+        self.requests().iter().filter(|r| r.path == path).count()
+    }
+
+    /// How many connections were accepted.
+    pub fn connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+
+    /// The most requests to `host` that were ever being answered at once.
+    pub fn max_in_flight(&self, host: &str) -> usize {
+        let in_flight = self.state.in_flight.lock().unwrap();
+        in_flight.get(host).map_or(0, |&(_, max)| max)
+    }
+}
+
+/// Answer the next request on a connection, and return whether the connection stays open.
+fn answer(reader: &mut BufReader<TcpStream>, state: &MockState) -> bool {
+    let mut line = String::new();
+    if !matches!(reader.read_line(&mut line), Ok(n) if n > 0) {
+        return false;
+    }
+    let target = line.split_whitespace().nth(1).unwrap_or("/").to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).is_err() || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let path = target.split('?').next().unwrap_or("/").to_string();
+    let host = headers.get("host").cloned().unwrap_or_default();
+    let host = host
+        .rsplit_once(':')
+        .map_or(host.clone(), |(h, _)| h.to_string());
+    let request = MockRequest {
+        path,
+        host: host.clone(),
+        headers,
+    };
+    state.requests.lock().unwrap().push(request.clone());
+
+    let handler = state.routes.lock().unwrap().get(&request.path).cloned();
+    let response = match handler {
+        Some(handler) => handler(&request),
+        None => MockResponse::status(404),
+    };
+
+    {
+        let mut in_flight = state.in_flight.lock().unwrap();
+        let (current, max) = in_flight.entry(host.clone()).or_insert((0, 0));
+        *current += 1;
+        *max = (*max).max(*current);
+    }
+    thread::sleep(response.delay);
+    state.in_flight.lock().unwrap().get_mut(&host).unwrap().0 -= 1;
+
+    let keep_alive = state.keep_alive && !response.omit_length;
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    let stream = reader.get_mut();
+    let mut head = format!(
+        "HTTP/1.1 {} Mock\r\nConnection: {}\r\n",
+        response.status, connection
+    );
+    for (name, value) in &response.headers {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    if !response.omit_length {
+        head += &format!("Content-Length: {}\r\n", response.body.len());
+    }
+    head += "\r\n";
+    // the client may have given up already
+    let sent =
+        stream.write_all(head.as_bytes()).is_ok() && stream.write_all(&response.body).is_ok();
+    sent && keep_alive
+}
+
+/// The URL of `path` on a port of localhost nothing listens on (one that was just given up), so
+/// that connecting to it fails.
+pub fn unreachable_url(path: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!(
+        "http://localhost:{}{}",
+        listener.local_addr().unwrap().port(),
+        path
+    )
+}
+
+/// An HTML page whose body is `text`.
+pub fn html(text: &str) -> String {
+    format!("<html><body><p>{}</p></body></html>", text)
+}
+
+/// An RSS `<item>` with a title and a link.
+pub fn item(title: &str, link: &str) -> String {
+    format!("<item><title>{}</title><link>{}</link></item>", title, link)
+}
+
+/// An RSS 2.0 feed of the given `<item>`s.
+pub fn rss(items: &[String]) -> String {
+    format!(
+        "<?xml version=\"1.0\"?><rss version=\"2.0\"><channel><title>Feed</title>\
+         <link>http://example.com/</link><description>Test feed</description>{}</channel></rss>",
+        items.concat()
+    )
+}
+
+/// An RSS feed listing the feeds at `feed_urls`, as read from a feed file.
+pub fn feed_list(feed_urls: &[String]) -> String {
+    let items = feed_urls
+        .iter()
+        .enumerate()
+        .map(|(n, url)| item(&format!("Feed {}", n), url))
+        .collect::<Vec<_>>();
+    rss(&items)
+}
+
+/// `data` compressed with gzip.
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// A feed listed in a feed file.
+pub fn feed(url: &str, title: &str) -> FeedItem {
+    FeedItem {
+        link: Some(url.to_string()),
+        title: Some(title.to_string()),
+        ..FeedItem::default()
+    }
+}
+
+/// Source of the numbers that tell temporary directories apart.
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A directory for the files of one test, removed when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        let n = NEXT_DIR.fetch_add(1, Ordering::SeqCst);
+        let name = format!("rss-indexer-test-{}-{}", std::process::id(), n);
+        let path = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    /// The path of `name` in this directory.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// Write `contents` to the file `name` in this directory, and return its path as a string.
+    pub fn file(&self, name: &str, contents: impl AsRef<[u8]>) -> String {
+        let path = self.path(name);
+        std::fs::write(&path, contents).unwrap();
+        path_str(&path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+pub fn path_str(path: &Path) -> String {
+    path.to_str().unwrap().to_string()
+}
+
+/// A `Fetcher` serving pages from memory, which counts how often each URL is fetched and how many
+/// fetches overlap. Unknown URLs fail with `RssIndexError::OtherError`.
+#[derive(Default)]
+pub struct MapFetcher {
+    pages: Mutex<HashMap<String, Vec<u8>>>,
+    calls: Mutex<HashMap<String, usize>>,
+    /// How many fetches are running, and the most that ever were at once.
+    in_flight: Mutex<(usize, usize)>,
+    /// How long each fetch takes.
+    pub delay: Duration,
+}
+
+impl MapFetcher {
+    pub fn new() -> Self {
+        MapFetcher::default()
+    }
+
+    pub fn with_delay(delay: Duration) -> Self {
+        MapFetcher {
+            delay,
+            ..MapFetcher::default()
+        }
+    }
+
+    pub fn page(self, url: &str, contents: impl Into<Vec<u8>>) -> Self {
+        self.pages
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), contents.into());
+        self
+    }
+
+    /// How often `url` was fetched.
+    pub fn calls(&self, url: &str) -> usize {
+        self.calls.lock().unwrap().get(url).copied().unwrap_or(0)
+    }
+
+    /// How many fetches there were in all.
+    pub fn total_calls(&self) -> usize {
+        self.calls.lock().unwrap().values().sum()
+    }
+
+    /// The most fetches that were ever running at once.
+    pub fn max_in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().1
+    }
+}
+
+impl Fetcher for MapFetcher {
+    fn fetch(&self, url: &str) -> RssIndexResult<Vec<u8>> {
+        *self
+            .calls
+            .lock()
+            .unwrap()
+            .entry(url.to_string())
+            .or_insert(0) += 1;
+        {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            in_flight.0 += 1;
+            in_flight.1 = in_flight.1.max(in_flight.0);
+        }
+        thread::sleep(self.delay);
+        self.in_flight.lock().unwrap().0 -= 1;
+        match self.pages.lock().unwrap().get(url) {
+            Some(contents) => Result::Ok(contents.clone()),
+            None => Result::Err(Box::new(RssIndexError::OtherError(format!(
+                "no page at {}",
+                url
+            )))),
+        }
+    }
+}
+
+/// The URLs of the articles in `index`, sorted.
+pub fn urls(index: &ArticleIndex) -> Vec<String> {
+    let mut urls = index
+        .iter()
+        .map(|(_, article)| article.url)
+        .collect::<Vec<_>>();
+    urls.sort();
+    urls
+}
+
+/// The titles of `articles`, in order.
+pub fn titles_of(articles: &[Article]) -> Vec<&str> {
+    articles
+        .iter()
+        .map(|article| article.title.as_str())
+        .collect()
+}
+
+/// A logger keeping every record logged, from all tests.
+struct CapturingLogger {
+    records: Mutex<Vec<(log::Level, String)>>,
+}
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let message = record.args().to_string();
+        self.records.lock().unwrap().push((record.level(), message));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger {
+    records: Mutex::new(Vec::new()),
+};
+
+static INSTALL_LOGGER: Once = Once::new();
+
+/// Start capturing log records, if that hasn't started yet.
+pub fn capture_logs() {
+    INSTALL_LOGGER.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+/// The records logged since `capture_logs` was first called, by any test.
+pub fn logs() -> Vec<(log::Level, String)> {
+    LOGGER.records.lock().unwrap().clone()
+}
@@ -1,71 +1,837 @@
-use std::sync::{mpsc, Arc, Mutex};
+use std::any::Any;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, PoisonError};
 use std::thread;
+use std::time::{Duration, Instant};
 
-/// Message type to communicate with workers. A JobMsg is either a FnOnce closure or None, which
-/// signals the worker to shut down.
-// type JobMsg = Option<Box<dyn FnOnce() + Send + 'static>>;
-type JobMsg = Option<Box<dyn FnBox + Send + 'static>>;
+use log::error;
 
-trait FnBox {
-    fn call_box(self: Box<Self>);
+/// A job is a FnOnce closure run by one of the workers.
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A job run within a `Scope`, which may borrow data living at least as long as the scope.
+type ScopedJob<'env> = Box<dyn FnOnce() + Send + 'env>;
+
+/// Priority of jobs pushed with `execute`. Jobs with a higher priority run first.
+pub const DEFAULT_PRIORITY: u8 = 128;
+
+/// A ThreadPool should have a job queue shared with the workers and a vector of `JoinHandle`s
+/// for the worker threads. Workers report their thread id on the `exited` channel when they shut
+/// down, so that exactly those threads can be joined. Jobs can be pushed, and workers added or
+/// removed, through a shared reference, so a pool can be shared between threads in an `Arc`.
+pub struct ThreadPool {
+    workers: Mutex<Vec<thread::JoinHandle<()>>>,
+    shared: Arc<Shared>,
+    exit_sender: mpsc::Sender<thread::ThreadId>,
+    exited: Mutex<mpsc::Receiver<thread::ThreadId>>,
+    name: String,
+    next_worker: AtomicUsize,
 }
 
-impl<F: FnOnce()> FnBox for F {
-    fn call_box(self: Box<F>) {
-        (*self)()
+/// Name prefix for worker threads of pools that are not explicitly named.
+const DEFAULT_NAME: &str = "rss-pool-worker";
+
+/// How long dropping a pool waits for its workers before detaching them.
+const DROP_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// A job waiting in the queue. Jobs are ordered by priority, and jobs of equal priority in the
+/// order they were pushed.
+struct QueuedJob {
+    priority: u8,
+    seq: u64,
+    job: Job,
+}
+
+impl PartialEq for QueuedJob {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
     }
 }
 
-/// A ThreadPool should have a sending-end of a mpsc channel (`mpsc::Sender`) and a vector of
-/// `JoinHandle`s for the worker threads.
-pub struct ThreadPool {
-    sender: mpsc::Sender<JobMsg>,
-    pub workers: Vec<thread::JoinHandle<()>>,
+impl Eq for QueuedJob {}
+
+impl PartialOrd for QueuedJob {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedJob {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+/// Jobs waiting to be picked up, and the number of kill messages for workers. Workers only pick
+/// up a kill message once there are no jobs left.
+#[derive(Default)]
+struct Queue {
+    jobs: BinaryHeap<QueuedJob>,
+    kills: usize,
+    next_seq: u64,
+}
+
+impl Queue {
+    /// Queue `job` behind the jobs already queued with the same `priority`.
+    fn push(&mut self, priority: u8, job: Job) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.jobs.push(QueuedJob { priority, seq, job });
+    }
+}
+
+/// State shared between the pool and its workers: the job queue with condition variables for
+/// "job available" and "space available", the optional queue capacity, messages of jobs that
+/// panicked, and job counters with a condition variable signalled once no job is queued or
+/// running.
+struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+    space: Condvar,
+    capacity: Option<usize>,
+    panics: Mutex<Vec<String>>,
+    stats: (Mutex<PoolStats>, Condvar),
+}
+
+/// A snapshot of the pool's job counters. The counters are updated under one lock, so
+/// `queued + active + completed` always equals the number of jobs submitted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Jobs sent but not yet picked up by a worker.
+    pub queued: usize,
+    /// Jobs currently executing.
+    pub active: usize,
+    /// Jobs finished, including those that panicked.
+    pub completed: usize,
+}
+
+/// Jobs pushed into a `Scope`, and whether the scope is closed. Once it is, the scope's workers
+/// exit as soon as the queue is empty.
+struct ScopeQueue<'env> {
+    jobs: VecDeque<ScopedJob<'env>>,
+    closed: bool,
+}
+
+/// A scope for jobs borrowing data from the stack, see `ThreadPool::scoped`.
+pub struct Scope<'env> {
+    queue: Mutex<ScopeQueue<'env>>,
+    available: Condvar,
+}
+
+impl<'env> Scope<'env> {
+    /// Push a new job into the scope. It may borrow anything that outlives the scope.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'env,
+    {
+        self.queue.lock().unwrap().jobs.push_back(Box::new(job));
+        self.available.notify_one();
+    }
+
+    /// Wait for the next job, or None once the scope is closed and no job is left.
+    fn next_job(&self) -> Option<ScopedJob<'env>> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some(job) = queue.jobs.pop_front() {
+                return Some(job);
+            }
+            if queue.closed {
+                return None;
+            }
+            queue = self.available.wait(queue).unwrap();
+        }
+    }
+}
+
+/// Closes a scope when dropped, so that its workers exit even if the scope's closure panics.
+struct CloseOnDrop<'a, 'env>(&'a Scope<'env>);
+
+impl Drop for CloseOnDrop<'_, '_> {
+    fn drop(&mut self) {
+        self.0.queue.lock().unwrap().closed = true;
+        self.0.available.notify_all();
+    }
+}
+
+/// Turn a panic payload into a readable message.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+/// Wait for the highest-priority job in the queue, or None if the worker should shut down.
+fn next_job(shared: &Shared) -> Option<Job> {
+    let mut queue = shared.queue.lock().unwrap();
+    loop {
+        if let Some(queued) = queue.jobs.pop() {
+            shared.space.notify_one();
+            return Some(queued.job);
+        }
+        if queue.kills > 0 {
+            queue.kills -= 1;
+            return None;
+        }
+        queue = shared.available.wait(queue).unwrap();
+    }
+}
+
+/// Reports a worker's thread id on the `exited` channel when the worker ends, however it ends, so
+/// that the pool never waits for a worker that is already gone.
+struct ExitGuard(mpsc::Sender<thread::ThreadId>);
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        // the pool may already be gone if it is being dropped; nobody is waiting on us then
+        let _ = self.0.send(thread::current().id());
+    }
+}
+
+/// Worker loop: (1) wait for new jobs in the queue, (2) execute received jobs, and (3) quit the
+/// loop if it receives a kill message. A panicking job does not take its worker down: the panic
+/// is caught, logged, and recorded.
+fn run_worker(shared: Arc<Shared>, exit_sender: mpsc::Sender<thread::ThreadId>) {
+    let _exit = ExitGuard(exit_sender);
+    while let Some(job) = next_job(&shared) {
+        {
+            let mut stats = shared.stats.0.lock().unwrap();
+            stats.queued -= 1;
+            stats.active += 1;
+        }
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+            let msg = panic_message(payload);
+            error!("Thread pool job panicked: {}", msg);
+            shared.panics.lock().unwrap().push(msg);
+        }
+        let (stats, cvar) = &shared.stats;
+        let mut stats = stats.lock().unwrap();
+        stats.active -= 1;
+        stats.completed += 1;
+        if stats.queued == 0 && stats.active == 0 {
+            cvar.notify_all();
+        }
+    }
+}
+
+/// Join a worker that has exited. A worker only panics outside of a job if the pool's state is
+/// broken; that is logged rather than passed on, since this runs while the pool is dropped.
+fn join_worker(worker: thread::JoinHandle<()>) {
+    if worker.join().is_err() {
+        error!("Thread pool worker panicked");
+    }
 }
 
 impl ThreadPool {
-    /// Spin up a thread pool with `num_workers` threads. Workers should all share the same
-    /// receiving end of an mpsc channel (`mpsc::Receiver`) with appropriate synchronization. Each
-    /// thread should loop and (1) listen for new jobs on the channel, (2) execute received jobs,
-    /// and (3) quit the loop if it receives None.
+    /// Spin up a thread pool with `num_workers` threads. Workers all share the same job queue,
+    /// which is unbounded.
     pub fn new(num_workers: usize) -> Self {
-        let (sender, receiver): (mpsc::Sender<JobMsg>, mpsc::Receiver<JobMsg>) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(num_workers);
-        for _ in 0..num_workers {
-            let receiver = Arc::clone(&receiver);
-            let thread = thread::spawn(move || loop {
-                let message = receiver.lock().unwrap().recv().unwrap();
-                match message {
-                    Some(job) => job.call_box(),
-                    None => break,
-                }
-            });
-            workers.push(thread);
+        ThreadPool::with_queue(num_workers, None, DEFAULT_NAME)
+    }
+
+    /// Same as `new`, but worker threads are named `<prefix>-<n>` rather than
+    /// `rss-pool-worker-<n>`, so that several pools can be told apart in panic messages and
+    /// debuggers.
+    pub fn named(num_workers: usize, prefix: &str) -> Self {
+        ThreadPool::with_queue(num_workers, None, prefix)
+    }
+
+    /// Spin up a thread pool with `num_workers` threads whose job queue holds at most `max_queued`
+    /// jobs (but at least one). Once the queue is full, `execute` blocks the caller until a worker
+    /// picks up a job.
+    pub fn with_capacity(num_workers: usize, max_queued: usize) -> Self {
+        ThreadPool::named_with_capacity(num_workers, max_queued, DEFAULT_NAME)
+    }
+
+    /// Same as `with_capacity`, but with worker threads named as by `named`.
+    pub fn named_with_capacity(num_workers: usize, max_queued: usize, prefix: &str) -> Self {
+        ThreadPool::with_queue(num_workers, Some(max_queued.max(1)), prefix)
+    }
+
+    fn with_queue(num_workers: usize, capacity: Option<usize>, name: &str) -> Self {
+        let (exit_sender, exited) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            available: Condvar::new(),
+            space: Condvar::new(),
+            capacity,
+            panics: Mutex::new(Vec::new()),
+            stats: (Mutex::new(PoolStats::default()), Condvar::new()),
+        });
+        let pool = ThreadPool {
+            workers: Mutex::new(Vec::with_capacity(num_workers)),
+            shared,
+            exit_sender,
+            exited: Mutex::new(exited),
+            name: name.to_string(),
+            next_worker: AtomicUsize::new(0),
+        };
+        pool.add_workers(num_workers);
+        pool
+    }
+
+    /// Ask `n` workers to shut down once the queue is empty. Also works if a panic poisoned the
+    /// queue's lock, so that the pool can always be dropped.
+    fn send_kills(&self, n: usize) {
+        let mut queue = self
+            .shared
+            .queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        queue.kills += n;
+        drop(queue);
+        self.shared.available.notify_all();
+    }
+
+    /// Spawn `n` more workers sharing the existing job queue.
+    pub fn add_workers(&self, n: usize) {
+        for _ in 0..n {
+            let shared = Arc::clone(&self.shared);
+            let exit_sender = self.exit_sender.clone();
+            let number = self.next_worker.fetch_add(1, AtomicOrdering::Relaxed);
+            let thread = thread::Builder::new()
+                .name(format!("{}-{}", self.name, number))
+                .spawn(move || run_worker(shared, exit_sender))
+                .unwrap();
+            self.workers.lock().unwrap().push(thread);
         }
-        ThreadPool { workers, sender }
     }
 
-    /// Push a new job into the thread pool.
-    pub fn execute<F>(&mut self, job: F)
+    /// Shut down `n` workers (or all of them, if there are fewer). Jobs queued before still run
+    /// first. Only the workers that actually picked up a kill message are joined, so this blocks
+    /// until that many workers have finished their current jobs; it must not be called from a job
+    /// of this pool.
+    pub fn remove_workers(&self, n: usize) {
+        // held throughout, so that concurrent calls each join the workers their kills retired
+        let exited = self.exited.lock().unwrap();
+        let n = n.min(self.num_workers());
+        self.send_kills(n);
+        for _ in 0..n {
+            // the pool holds a sender itself, so the channel can't be closed
+            let id = exited.recv().unwrap();
+            let mut workers = self.workers.lock().unwrap();
+            if let Some(pos) = workers.iter().position(|w| w.thread().id() == id) {
+                let worker = workers.swap_remove(pos);
+                drop(workers);
+                join_worker(worker);
+            }
+        }
+    }
+
+    /// Number of live workers.
+    pub fn num_workers(&self) -> usize {
+        self.workers.lock().unwrap().len()
+    }
+
+    /// Messages of all jobs that have panicked so far.
+    pub fn panics(&self) -> Vec<String> {
+        self.shared.panics.lock().unwrap().clone()
+    }
+
+    /// Current job counters.
+    pub fn stats(&self) -> PoolStats {
+        *self.shared.stats.0.lock().unwrap()
+    }
+
+    /// Push a new job into the thread pool with `DEFAULT_PRIORITY`.
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with_priority(DEFAULT_PRIORITY, job);
+    }
+
+    /// Push a new job into the thread pool. Queued jobs with a higher `priority` are picked up
+    /// first; jobs of equal priority run in the order they were pushed.
+    pub fn execute_with_priority<F>(&self, priority: u8, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(job);
-        self.sender.send(Some(job)).unwrap();
+        self.shared.stats.0.lock().unwrap().queued += 1;
+        let mut queue = self.shared.queue.lock().unwrap();
+        if let Some(capacity) = self.shared.capacity {
+            while queue.jobs.len() >= capacity {
+                queue = self.shared.space.wait(queue).unwrap();
+            }
+        }
+        queue.push(priority, job);
+        drop(queue);
+        self.shared.available.notify_one();
+    }
+
+    /// Push a batch of jobs into the thread pool with `DEFAULT_PRIORITY`, in order. Unlike calling
+    /// `execute` for each of them, the queue is locked once for the whole batch (unless it fills
+    /// up) and the workers are woken once.
+    pub fn execute_all<I>(&self, jobs: I)
+    where
+        I: IntoIterator<Item = Box<dyn FnOnce() + Send>>,
+    {
+        let jobs = jobs.into_iter().map(|job| (DEFAULT_PRIORITY, job));
+        self.execute_all_with_priorities(jobs);
+    }
+
+    /// Same as `execute_all`, but each job is pushed with the priority paired with it (see
+    /// `execute_with_priority`).
+    pub fn execute_all_with_priorities<I>(&self, jobs: I)
+    where
+        I: IntoIterator<Item = (u8, Box<dyn FnOnce() + Send>)>,
+    {
+        let jobs = jobs.into_iter().collect::<Vec<_>>();
+        if jobs.is_empty() {
+            return;
+        }
+        self.shared.stats.0.lock().unwrap().queued += jobs.len();
+        let mut queue = self.shared.queue.lock().unwrap();
+        for (priority, job) in jobs {
+            if let Some(capacity) = self.shared.capacity {
+                while queue.jobs.len() >= capacity {
+                    // the workers can only make space once they know about the jobs pushed so far
+                    self.shared.available.notify_all();
+                    queue = self.shared.space.wait(queue).unwrap();
+                }
+            }
+            queue.push(priority, job);
+        }
+        drop(queue);
+        self.shared.available.notify_all();
+    }
+
+    /// Block until every job pushed so far has finished running. Unlike dropping the pool, the
+    /// workers stay alive and the pool can be reused afterwards.
+    pub fn join(&self) {
+        let (stats, cvar) = &self.shared.stats;
+        let mut stats = stats.lock().unwrap();
+        while stats.queued > 0 || stats.active > 0 {
+            stats = cvar.wait(stats).unwrap();
+        }
+    }
+
+    /// Push a new job into the thread pool and return the receiving end of a oneshot channel that
+    /// will carry the job's return value once a worker has run it.
+    pub fn execute_with_result<F, T>(&self, job: F) -> mpsc::Receiver<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_sender, result_receiver) = mpsc::channel();
+        self.execute(move || {
+            // the caller may have dropped the receiver; the result is simply discarded then
+            let _ = result_sender.send(job());
+        });
+        result_receiver
+    }
+
+    /// Run `f` with a `Scope` whose jobs may borrow data from the caller's stack, and return once
+    /// every job pushed into the scope has finished, even if `f` panics. The jobs run on as many
+    /// threads as the pool has workers, which are spawned for the scope and joined at its end.
+    /// Panicking jobs are caught and recorded like those of the pool.
+    pub fn scoped<'env, F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&Scope<'env>) -> R,
+    {
+        let scope = Scope {
+            queue: Mutex::new(ScopeQueue {
+                jobs: VecDeque::new(),
+                closed: false,
+            }),
+            available: Condvar::new(),
+        };
+        thread::scope(|s| {
+            for n in 0..self.num_workers().max(1) {
+                let scope = &scope;
+                let shared = &self.shared;
+                thread::Builder::new()
+                    .name(format!("{}-scoped-{}", self.name, n))
+                    .spawn_scoped(s, move || {
+                        while let Some(job) = scope.next_job() {
+                            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                                let msg = panic_message(payload);
+                                error!("Thread pool job panicked: {}", msg);
+                                shared.panics.lock().unwrap().push(msg);
+                            }
+                        }
+                    })
+                    .unwrap();
+            }
+            let _close = CloseOnDrop(&scope);
+            f(&scope)
+        })
+    }
+
+    /// Shut the pool down, giving the workers at most `dur` to finish their current jobs. Returns
+    /// the handles of workers that did not finish in time; they are left running detached.
+    pub fn shutdown_timeout(mut self, dur: Duration) -> Result<(), Vec<thread::JoinHandle<()>>> {
+        self.shutdown_workers(dur)
+    }
+
+    /// Send a kill message to each worker, and join each worker that exits before `dur` has
+    /// passed. Leaves `self.workers` empty. If this runs on one of the pool's own workers (because
+    /// a job dropped the last `Arc` of its pool), that worker is left to exit by itself once its
+    /// job returns, as it can't wait for itself.
+    fn shutdown_workers(&mut self, dur: Duration) -> Result<(), Vec<thread::JoinHandle<()>>> {
+        let deadline = Instant::now() + dur;
+        let workers = self
+            .workers
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut workers = std::mem::take(workers);
+        self.send_kills(workers.len());
+        let current = thread::current().id();
+        workers.retain(|w| w.thread().id() != current);
+        let exited = self
+            .exited
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner);
+        while !workers.is_empty() {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let id = match exited.recv_timeout(timeout) {
+                Ok(id) => id,
+                Err(_) => return Err(workers),
+            };
+            if let Some(pos) = workers.iter().position(|w| w.thread().id() == id) {
+                join_worker(workers.swap_remove(pos));
+            }
+        }
+        Ok(())
     }
 }
 
 impl Drop for ThreadPool {
-    /// Clean up the thread pool. Send a kill message (None) to each worker, and join each worker.
-    /// This function should only return when all workers have finished.
+    /// Clean up the thread pool. Send a kill message to each worker, and join each worker.
+    /// Workers still busy after `DROP_TIMEOUT` are detached so that one stuck job cannot hang the
+    /// program forever.
     fn drop(&mut self) {
-        for _ in &mut self.workers {
-            self.sender.send(None).unwrap();
+        if let Err(stuck) = self.shutdown_workers(DROP_TIMEOUT) {
+            error!(
+                "{} thread pool workers did not shut down in time",
+                stuck.len()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn execute_with_result_sends_the_value_back() {
+        let pool = ThreadPool::new(2);
+        let main = thread::current().id();
+        let result = pool.execute_with_result(move || (thread::current().id() != main, 6 * 7));
+        assert_eq!(result.recv().unwrap(), (true, 42));
+    }
+
+    #[test]
+    fn execute_with_result_runs_alongside_plain_jobs() {
+        let pool = ThreadPool::new(1);
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send("plain").unwrap());
+        let result = pool.execute_with_result(|| "with result");
+        assert_eq!(receiver.recv().unwrap(), "plain");
+        assert_eq!(result.recv().unwrap(), "with result");
+    }
+
+    #[test]
+    fn panicking_job_does_not_kill_its_worker() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("job failed"));
+        let result = pool.execute_with_result(|| "still running");
+        assert_eq!(result.recv().unwrap(), "still running");
+        assert_eq!(pool.panics(), vec!["job failed".to_string()]);
+    }
+
+    #[test]
+    fn join_waits_for_all_jobs_and_keeps_the_pool_usable() {
+        let pool = ThreadPool::new(3);
+        let done = Arc::new(Mutex::new(0));
+        for round in 1..=2 {
+            for _ in 0..20 {
+                let done = Arc::clone(&done);
+                pool.execute(move || {
+                    thread::sleep(Duration::from_millis(1));
+                    *done.lock().unwrap() += 1;
+                });
+            }
+            pool.join();
+            assert_eq!(*done.lock().unwrap(), 20 * round);
         }
-        for _ in 0..self.workers.len() {
-            let worker = self.workers.pop().unwrap();
+        assert_eq!(pool.num_workers(), 3);
+    }
+
+    /// Whether `pool` runs `n` jobs at the same time: each of them waits for all the others.
+    fn runs_at_once(pool: &ThreadPool, n: usize) -> bool {
+        let barrier = Arc::new(std::sync::Barrier::new(n));
+        let results = (0..n)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                pool.execute_with_result(move || barrier.wait())
+            })
+            .collect::<Vec<_>>();
+        results
+            .iter()
+            .all(|result| result.recv_timeout(Duration::from_secs(5)).is_ok())
+    }
+
+    #[test]
+    fn a_pool_grows_and_shrinks_at_runtime() {
+        let pool = ThreadPool::new(2);
+        pool.add_workers(3);
+        assert_eq!(pool.num_workers(), 5);
+        assert!(runs_at_once(&pool, 5));
+
+        pool.remove_workers(3);
+        assert_eq!(pool.num_workers(), 2);
+        // the workers left are the ones that didn't pick up a kill message
+        assert!(pool
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|w| !w.is_finished()));
+        assert!(runs_at_once(&pool, 2));
+        assert_eq!(pool.execute_with_result(|| 42).recv().unwrap(), 42);
+
+        // asking for more than there are removes them all
+        pool.remove_workers(10);
+        assert_eq!(pool.num_workers(), 0);
+        pool.add_workers(1);
+        assert_eq!(pool.execute_with_result(|| 7).recv().unwrap(), 7);
+    }
+
+    #[test]
+    fn jobs_can_consume_what_they_capture() {
+        let pool = ThreadPool::new(1);
+        let words = vec!["a".to_string(), "b".to_string()];
+        // `into_iter` consumes `words`, so the job can only be called once
+        let result = pool.execute_with_result(move || words.into_iter().collect::<String>());
+        assert_eq!(result.recv().unwrap(), "ab");
+    }
+
+    #[test]
+    fn stats_add_up_and_active_jobs_never_exceed_the_workers() {
+        let pool = ThreadPool::new(4);
+        for _ in 0..100 {
+            pool.execute(|| thread::sleep(Duration::from_millis(1)));
+        }
+        loop {
+            let stats = pool.stats();
+            assert_eq!(stats.queued + stats.active + stats.completed, 100);
+            assert!(stats.active <= 4);
+            if stats.completed == 100 {
+                break;
+            }
+        }
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                queued: 0,
+                active: 0,
+                completed: 100
+            }
+        );
+    }
+
+    #[test]
+    fn full_queue_blocks_the_producer_until_a_job_is_picked_up() {
+        let pool = Arc::new(ThreadPool::with_capacity(1, 1));
+        let (release, gate) = mpsc::channel::<()>();
+        pool.execute(move || gate.recv().unwrap());
+        // the worker is busy, so this one fills the queue
+        pool.execute(|| {});
+
+        let (pushed, was_pushed) = mpsc::channel();
+        let producer_pool = Arc::clone(&pool);
+        let producer = thread::spawn(move || {
+            producer_pool.execute(|| {});
+            pushed.send(()).unwrap();
+        });
+        assert!(was_pushed.recv_timeout(Duration::from_millis(100)).is_err());
+
+        release.send(()).unwrap();
+        was_pushed.recv_timeout(Duration::from_secs(5)).unwrap();
+        producer.join().unwrap();
+        pool.join();
+        assert_eq!(pool.stats().completed, 3);
+    }
+
+    #[test]
+    fn workers_are_named_after_their_pool() {
+        let name = || thread::current().name().map(String::from);
+        let pool = ThreadPool::named(1, "feeds");
+        let feeds_name = pool.execute_with_result(name).recv().unwrap().unwrap();
+        assert_eq!(feeds_name, "feeds-0");
+        let pool = ThreadPool::new(1);
+        let default_name = pool.execute_with_result(name).recv().unwrap().unwrap();
+        assert_eq!(default_name, "rss-pool-worker-0");
+    }
+
+    #[test]
+    fn shutdown_gives_up_on_stuck_workers_after_the_timeout() {
+        let pool = ThreadPool::new(2);
+        let (release, gate) = mpsc::channel::<()>();
+        pool.execute(move || gate.recv().unwrap());
+        pool.execute(|| {});
+        while pool.stats().completed < 1 || pool.stats().active < 1 {
+            thread::yield_now();
+        }
+
+        let started = Instant::now();
+        let stuck = pool
+            .shutdown_timeout(Duration::from_millis(100))
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(stuck.len(), 1);
+        assert!(!stuck[0].is_finished());
+
+        release.send(()).unwrap();
+        for worker in stuck {
             worker.join().unwrap();
         }
     }
+
+    #[test]
+    fn shutdown_joins_idle_workers() {
+        let pool = ThreadPool::new(3);
+        assert!(pool.shutdown_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn higher_priorities_run_first_and_equal_ones_in_order() {
+        let pool = ThreadPool::new(1);
+        let (release, gate) = mpsc::channel::<()>();
+        pool.execute(move || gate.recv().unwrap());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for (priority, name) in [
+            (1, "low"),
+            (200, "high"),
+            (DEFAULT_PRIORITY, "a"),
+            (200, "higher"),
+        ] {
+            let order = Arc::clone(&order);
+            pool.execute_with_priority(priority, move || order.lock().unwrap().push(name));
+        }
+        let order_b = Arc::clone(&order);
+        pool.execute(move || order_b.lock().unwrap().push("b"));
+
+        release.send(()).unwrap();
+        pool.join();
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec!["high", "higher", "a", "b", "low"]
+        );
+    }
+
+    #[test]
+    fn dropping_clones_of_a_shared_pool_never_panics() {
+        let pool = Arc::new(ThreadPool::new(2));
+        let extra = Arc::clone(&pool);
+        drop(pool);
+        // the extra clone still has a working pool
+        assert_eq!(extra.execute_with_result(|| 1).recv().unwrap(), 1);
+
+        // the last clone is dropped by a job of the pool itself, so its worker can't be joined
+        let (go, wait_for_go) = mpsc::channel();
+        let (sender, receiver) = mpsc::channel();
+        let last = Arc::clone(&extra);
+        extra.execute(move || {
+            wait_for_go.recv().unwrap();
+            let dropped = panic::catch_unwind(AssertUnwindSafe(|| drop(last)));
+            sender.send(dropped.is_ok()).unwrap();
+        });
+        drop(extra);
+        go.send(()).unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(true));
+    }
+
+    #[test]
+    fn a_batch_of_jobs_all_run() {
+        let pool = ThreadPool::new(4);
+        let ran = Arc::new(Mutex::new(0));
+        let jobs = (0..1000).map(|_| {
+            let ran = Arc::clone(&ran);
+            Box::new(move || *ran.lock().unwrap() += 1) as Box<dyn FnOnce() + Send>
+        });
+        pool.execute_all(jobs);
+        pool.join();
+        assert_eq!(*ran.lock().unwrap(), 1000);
+        assert_eq!(pool.stats().completed, 1000);
+    }
+
+    #[test]
+    fn a_batch_larger_than_the_queue_waits_for_space() {
+        let pool = ThreadPool::with_capacity(2, 3);
+        let ran = Arc::new(Mutex::new(0));
+        let jobs = (0..50).map(|_| {
+            let ran = Arc::clone(&ran);
+            Box::new(move || *ran.lock().unwrap() += 1) as Box<dyn FnOnce() + Send>
+        });
+        pool.execute_all(jobs);
+        pool.join();
+        assert_eq!(*ran.lock().unwrap(), 50);
+    }
+
+    #[test]
+    fn a_batch_keeps_the_priorities_of_its_jobs() {
+        let pool = ThreadPool::new(1);
+        let (release, gate) = mpsc::channel::<()>();
+        pool.execute(move || gate.recv().unwrap());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let jobs = vec![(1, "low"), (200, "high"), (DEFAULT_PRIORITY, "a")]
+            .into_iter()
+            .map(|(priority, name)| {
+                let order = Arc::clone(&order);
+                let job = move || order.lock().unwrap().push(name);
+                (priority, Box::new(job) as Box<dyn FnOnce() + Send>)
+            });
+        pool.execute_all_with_priorities(jobs);
+
+        release.send(()).unwrap();
+        pool.join();
+        assert_eq!(*order.lock().unwrap(), vec!["high", "a", "low"]);
+    }
+
+    #[test]
+    fn scoped_jobs_can_mutate_a_borrowed_buffer() {
+        let pool = ThreadPool::new(3);
+        let mut buffer = vec![0; 16];
+        let offset = 100;
+        pool.scoped(|scope| {
+            for (i, slot) in buffer.iter_mut().enumerate() {
+                scope.execute(move || *slot = offset + i * i);
+            }
+        });
+        let expected = (0..16).map(|i| offset + i * i).collect::<Vec<_>>();
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn a_scope_waits_for_all_its_jobs_even_when_something_panics() {
+        let pool = ThreadPool::new(2);
+        let done = Mutex::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scoped(|scope| {
+                scope.execute(|| panic!("scoped job failed"));
+                for _ in 0..10 {
+                    scope.execute(|| {
+                        thread::sleep(Duration::from_millis(1));
+                        *done.lock().unwrap() += 1;
+                    });
+                }
+                panic!("scope closure failed");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(*done.lock().unwrap(), 10);
+        assert_eq!(pool.panics(), vec!["scoped job failed".to_string()]);
+    }
 }